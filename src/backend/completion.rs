@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tower_lsp::jsonrpc::Result;
+#[allow(clippy::wildcard_imports)]
+use tower_lsp::lsp_types::*;
+
+use super::Backend;
+use crate::{
+    error::map_err_to_internal_error,
+    nu::{
+        catalog::{Catalog, CatalogCommand},
+        IdeCommand, IdeComplete,
+    },
+    offsets::LineIndex,
+    signature::{ParameterKind, Signature},
+    syntax::{self, Call},
+};
+
+/// What `completionItem/resolve` needs to find a command's documentation again.
+#[derive(Deserialize, Serialize)]
+struct ResolveData {
+    command: String,
+    /// the file declaring the command, or `None` if it's in the catalog
+    uri: Option<Url>,
+}
+
+impl Backend {
+    /// Answers `textDocument/completion` with what `nu --ide-complete` suggests,
+    /// described with the catalog and the workspace's own declarations.
+    pub(super) async fn completion(
+        &self,
+        uri: &Url,
+        position: Position,
+    ) -> Result<Option<CompletionResponse>> {
+        let text = self.for_document(uri, &|doc| String::from(doc.get_content(None)))?;
+        let line_index = LineIndex::new(&text, self.encoding());
+        let offset = line_index.offset(position);
+
+        let ide_settings = self.get_document_settings(uri).await?;
+        let output = self
+            .run_compiler(&text, IdeCommand::Complete(offset), ide_settings, uri)
+            .await?;
+        let complete = IdeComplete::try_from(output)?;
+
+        // for a nu that doesn't say what each suggestion replaces (e.g. 0.85),
+        // work out what it would have from the text
+        let range = |start: usize| Range {
+            start: line_index.position(u32::try_from(start).unwrap_or(offset)),
+            end: position,
+        };
+        let call = syntax::call_at(&text, offset as usize);
+        let word_start = syntax::word_start(&text, offset as usize);
+        let typed = &text[word_start..offset as usize];
+        let completions = Completions {
+            replacing_call: call.as_ref().map(|c| range(c.start)),
+            // within a cell path, e.g. `$env.PA`, only the last member is completed
+            replacing_member: typed
+                .starts_with(['$', '.'])
+                .then(|| typed.rfind('.'))
+                .flatten()
+                .map(|dot| range(word_start + dot + 1)),
+            replacing_word: range(word_start),
+            snippets: *self.can_insert_snippets.get().unwrap_or(&false),
+            call,
+            catalog: self.catalog().await,
+            declared: syntax::signatures(&text),
+            directory: uri
+                .to_file_path()
+                .ok()
+                .and_then(|p| p.parent().map(Path::to_path_buf)),
+            workspace: self.workspace_commands()?,
+        };
+        Ok(Some(CompletionResponse::Array(
+            complete
+                .completions
+                .into_iter()
+                .map(|suggestion| {
+                    let (label, span) = suggestion.into_parts();
+                    let replacing = span.map(|span| Range {
+                        start: line_index.position(span.start),
+                        end: line_index.position(span.end),
+                    });
+                    completions.item(label, replacing)
+                })
+                .collect(),
+        )))
+    }
+
+    /// Answers `completionItem/resolve` with a command's full documentation, including any examples.
+    pub(super) async fn completion_resolve(
+        &self,
+        mut item: CompletionItem,
+    ) -> Result<CompletionItem> {
+        let Some(data) = item
+            .data
+            .clone()
+            .and_then(|d| serde_json::from_value::<ResolveData>(d).ok())
+        else {
+            return Ok(item);
+        };
+
+        let documentation = match &data.uri {
+            Some(uri) => self.read_document(uri).await.ok().and_then(|(text, _)| {
+                syntax::signatures(&text)
+                    .into_iter()
+                    .find(|s| s.name == data.command)
+                    .map(|s| s.description)
+            }),
+            None => self
+                .catalog()
+                .await
+                .get(&data.command)
+                .map(CatalogCommand::to_markdown),
+        };
+        item.documentation = documentation.filter(|d| !d.is_empty()).map(|value| {
+            Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            })
+        });
+        Ok(item)
+    }
+
+    /// The commands and modules declared in the workspace (as indexed so far), and where.
+    fn workspace_commands(&self) -> Result<HashMap<String, (SymbolKind, Url)>> {
+        let symbol_index = self.symbol_index.read().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot read symbol index: {e:?}"))
+        })?;
+        Ok(symbol_index
+            .iter()
+            .flat_map(|(uri, symbols)| symbols.iter().map(move |s| (uri, s)))
+            .filter(|(_, s)| {
+                matches!(
+                    s.kind,
+                    SymbolKind::FUNCTION | SymbolKind::INTERFACE | SymbolKind::MODULE
+                )
+            })
+            .map(|(uri, s)| (s.name.clone(), (s.kind, uri.clone())))
+            .collect())
+    }
+}
+
+/// What's known about the text around a completion, to describe each suggestion.
+struct Completions<'a> {
+    /// the call being completed, if any
+    call: Option<Call>,
+    catalog: &'a Catalog,
+    /// commands declared in the document itself
+    declared: Vec<Signature>,
+    /// where `nu` resolves relative paths, i.e. the document's directory
+    directory: Option<PathBuf>,
+    /// from the start of the call to the cursor, for a command name of several words
+    replacing_call: Option<Range>,
+    /// from the start of the last member of a cell path to the cursor
+    replacing_member: Option<Range>,
+    /// from the start of the word to the cursor
+    replacing_word: Range,
+    /// whether the client can fill in placeholders
+    snippets: bool,
+    workspace: HashMap<String, (SymbolKind, Url)>,
+}
+
+impl Completions<'_> {
+    /// Describes `label`, replacing the text nu said it does, or else what it seems to complete.
+    fn item(&self, label: String, replacing: Option<Range>) -> CompletionItem {
+        let mut item = CompletionItem {
+            label,
+            ..Default::default()
+        };
+        let label = item.label.as_str();
+        // e.g. `str join` for `str jo`
+        let command_position = self.call.as_ref().is_none_or(|c| {
+            c.cursor == 0 || label.starts_with(&format!("{} ", c.words[..c.cursor].join(" ")))
+        });
+        let mut signature = None;
+
+        if label.starts_with('$') {
+            item.kind = Some(CompletionItemKind::VARIABLE);
+        } else if label.starts_with('-') && !command_position {
+            item.kind = Some(CompletionItemKind::PROPERTY);
+            let flag = self
+                .signature()
+                .and_then(|s| s.parameters.into_iter().find(|p| p.is_named_by(label)));
+            if let Some(flag) = flag {
+                item.detail = Some(flag.label());
+                item.documentation = (!flag.description.is_empty())
+                    .then_some(Documentation::String(flag.description));
+            }
+        } else if let Some(kind) = self.path_kind(label).filter(|_| !command_position) {
+            item.kind = Some(kind);
+        } else if let Some((kind, uri)) = self.workspace.get(label) {
+            item.kind = Some(if *kind == SymbolKind::MODULE {
+                CompletionItemKind::MODULE
+            } else {
+                CompletionItemKind::FUNCTION
+            });
+            signature = self.declared.iter().find(|s| s.name == label).cloned();
+            item.detail = signature
+                .as_ref()
+                .map(|s| s.to_signature_information(None).label);
+            item.data = resolve_data(label, Some(uri));
+        } else if let Some(command) = self.catalog.get(label) {
+            item.kind = Some(match command.kind() {
+                "keyword" => CompletionItemKind::KEYWORD,
+                "external" => CompletionItemKind::INTERFACE,
+                _ => CompletionItemKind::FUNCTION,
+            });
+            let command = command.to_signature();
+            item.detail = Some(command.to_signature_information(None).label);
+            item.data = resolve_data(label, None);
+            signature = Some(command);
+        } else if command_position {
+            // unknown to `nu` itself, so presumably a program on the PATH
+            item.kind = Some(CompletionItemKind::INTERFACE);
+            item.detail = Some(String::from("external"));
+        } else if self
+            .call
+            .as_ref()
+            .is_some_and(|c| matches!(c.words[0].as_str(), "use" | "export" | "hide" | "overlay"))
+        {
+            item.kind = Some(CompletionItemKind::MODULE);
+        } else {
+            item.kind = Some(CompletionItemKind::VALUE);
+        }
+
+        let range = match (replacing, &self.replacing_call, &self.replacing_member) {
+            (Some(replacing), _, _) => replacing,
+            (None, Some(call), _) if command_position => *call,
+            (None, _, Some(member)) if !label.starts_with(['$', '.']) => *member,
+            _ => self.replacing_word,
+        };
+        // only when typing a new call, rather than renaming one that already has arguments
+        let snippet = signature
+            .filter(|_| {
+                self.snippets
+                    && command_position
+                    && self
+                        .call
+                        .as_ref()
+                        .is_none_or(|c| c.words.len() <= c.cursor + 1)
+            })
+            .and_then(|s| snippet(label, &s));
+        if snippet.is_some() {
+            item.insert_text_format = Some(InsertTextFormat::SNIPPET);
+        }
+        item.text_edit = Some(CompletionTextEdit::Edit(TextEdit {
+            range,
+            new_text: snippet.unwrap_or_else(|| item.label.clone()),
+        }));
+        item
+    }
+
+    /// The signature of the command being called, to describe its flags.
+    fn signature(&self) -> Option<Signature> {
+        let call = self.call.as_ref()?;
+        let names: Vec<String> = (1..=call.cursor.min(call.words.len()))
+            .rev()
+            .map(|n| call.words[..n].join(" "))
+            .collect();
+        names
+            .iter()
+            .find_map(|name| self.declared.iter().find(|s| &s.name == name).cloned())
+            .or_else(|| {
+                self.catalog
+                    .find(&call.words, call.cursor)
+                    .map(CatalogCommand::to_signature)
+            })
+    }
+
+    /// Whether `label` is a directory or file, relative to the document.
+    fn path_kind(&self, label: &str) -> Option<CompletionItemKind> {
+        let unquoted = label.trim_matches(['"', '\'', '`']);
+        let path = match unquoted.strip_prefix("~/") {
+            Some(rest) => PathBuf::from(std::env::var_os("HOME")?).join(rest),
+            None => self.directory.as_ref()?.join(unquoted),
+        };
+        if unquoted.ends_with(['/', '\\']) || path.is_dir() {
+            Some(CompletionItemKind::FOLDER)
+        } else if path.exists() {
+            Some(CompletionItemKind::FILE)
+        } else {
+            None
+        }
+    }
+}
+
+/// e.g. `cp ${1:source} ${2:target}`, so the required arguments can be filled in one by one.
+fn snippet(label: &str, signature: &Signature) -> Option<String> {
+    let escape = |s: &str| {
+        s.replace('\\', "\\\\")
+            .replace('$', "\\$")
+            .replace('}', "\\}")
+    };
+    let placeholders: Vec<String> = signature
+        .parameters
+        .iter()
+        .filter(|p| p.kind == ParameterKind::Required)
+        .enumerate()
+        .map(|(i, p)| format!("${{{}:{}}}", i + 1, escape(&p.name)))
+        .collect();
+    if placeholders.is_empty() {
+        return None;
+    }
+    Some(format!("{} {}", escape(label), placeholders.join(" ")))
+}
+
+fn resolve_data(command: &str, uri: Option<&Url>) -> Option<serde_json::Value> {
+    serde_json::to_value(ResolveData {
+        command: String::from(command),
+        uri: uri.cloned(),
+    })
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn item_kinds_follow_what_is_being_completed() {
+        let catalog = Catalog::from_json(
+            &serde_json::json!([
+                { "name": "if", "command_type": "keyword" },
+                {
+                    "name": "ls",
+                    "usage": "List the filenames, sizes, and modification times of items in a directory.",
+                    "is_builtin": true,
+                    "signatures": { "nothing": [
+                        { "parameter_name": "all", "parameter_type": "switch", "short_flag": "a", "description": "Show hidden files" },
+                    ] },
+                },
+            ])
+            .to_string(),
+        )
+        .expect("should parse");
+        let directory = mktemp::Temp::new_dir().expect("should create temporary directory");
+        std::fs::create_dir(directory.join("scripts")).expect("should create directory");
+        std::fs::write(directory.join("main.nu"), "").expect("should write file");
+        let uri = Url::parse("file:///project/lib.nu").expect("should parse URL");
+        let text = "# Greets someone\ndef greet [name: string] {}\nls ";
+        let completions = |offset| Completions {
+            call: syntax::call_at(text, offset),
+            catalog: &catalog,
+            declared: syntax::signatures(text),
+            directory: Some(directory.to_path_buf()),
+            replacing_call: None,
+            replacing_member: None,
+            replacing_word: Range::default(),
+            snippets: true,
+            workspace: HashMap::from([(
+                String::from("greet"),
+                (SymbolKind::FUNCTION, uri.clone()),
+            )]),
+        };
+        let kind = |offset, label: &str| completions(offset).item(String::from(label), None).kind;
+
+        let ls = completions(45).item(String::from("ls"), None);
+        assert_eq!(ls.kind, Some(CompletionItemKind::FUNCTION));
+        assert_eq!(ls.detail.as_deref(), Some("ls --all(-a)"));
+        assert_eq!(
+            ls.data,
+            Some(serde_json::json!({ "command": "ls", "uri": null }))
+        );
+        let greet = completions(45).item(String::from("greet"), None);
+        assert_eq!(greet.detail.as_deref(), Some("greet <name: string>"));
+        assert_eq!(
+            greet.data,
+            Some(serde_json::json!({ "command": "greet", "uri": uri }))
+        );
+        assert_eq!(greet.insert_text_format, Some(InsertTextFormat::SNIPPET));
+        assert_eq!(
+            greet.text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit {
+                range: Range::default(),
+                new_text: String::from("greet ${1:name}"),
+            }))
+        );
+        assert_eq!(ls.insert_text_format, None);
+        assert_eq!(kind(45, "if"), Some(CompletionItemKind::KEYWORD));
+        assert_eq!(kind(45, "rg"), Some(CompletionItemKind::INTERFACE));
+
+        let all = completions(48).item(String::from("--all"), None);
+        assert_eq!(all.kind, Some(CompletionItemKind::PROPERTY));
+        assert_eq!(all.detail.as_deref(), Some("--all(-a)"));
+        assert_eq!(
+            all.documentation,
+            Some(Documentation::String(String::from("Show hidden files")))
+        );
+        assert_eq!(kind(48, "$env"), Some(CompletionItemKind::VARIABLE));
+        assert_eq!(kind(48, "scripts/"), Some(CompletionItemKind::FOLDER));
+        assert_eq!(kind(48, "`main.nu`"), Some(CompletionItemKind::FILE));
+        assert_eq!(kind(48, "true"), Some(CompletionItemKind::VALUE));
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use futures::{stream, StreamExt};
+use tower_lsp::jsonrpc::Result;
+#[allow(clippy::wildcard_imports)]
+use tower_lsp::lsp_types::*;
+
+use super::Backend;
+use crate::{error::map_err_to_internal_error, nu::IdeSettings, syntax};
+
+// a widely imported module shouldn't take every place under the process limit when it changes
+const MAX_CONCURRENT_DEPENDENT_CHECKS: usize = 2;
+
+impl Backend {
+    /// Remembers which files `text` imports, so that it can be revalidated when they change.
+    pub(super) fn record_dependencies(
+        &self,
+        uri: &Url,
+        text: &str,
+        settings: &IdeSettings,
+    ) -> Result<()> {
+        let imported = imported_files(uri, text, settings)?;
+        let mut dependencies = self.dependencies.write().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot write dependency graph: {e:?}"))
+        })?;
+        dependencies.insert(uri.clone(), imported);
+        Ok(())
+    }
+
+    pub(super) fn forget_dependencies(&self, uri: &Url) -> Result<()> {
+        let mut dependencies = self.dependencies.write().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot write dependency graph: {e:?}"))
+        })?;
+        dependencies.remove(uri);
+        Ok(())
+    }
+
+    /// Checks everything that (directly or indirectly) imports `uri` again, a few at a time.
+    ///
+    /// Their cached diagnostics are dropped, so pulling clients get fresh results too.
+    pub(super) async fn revalidate_dependents(&self, uri: &Url) -> Result<()> {
+        let dependents = {
+            let dependencies = self.dependencies.read().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot read dependency graph: {e:?}"))
+            })?;
+            dependents(&dependencies, uri)
+        };
+        if dependents.is_empty() {
+            return Ok(());
+        }
+
+        {
+            let mut document_diagnostics = self.document_diagnostics.write().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot write diagnostics cache: {e:?}"))
+            })?;
+            for dependent in &dependents {
+                document_diagnostics.remove(dependent);
+            }
+        }
+        let mut checks = stream::iter(dependents)
+            .map(|dependent| async move {
+                let result = self.validate_document(&dependent).await;
+                (dependent, result)
+            })
+            .buffer_unordered(MAX_CONCURRENT_DEPENDENT_CHECKS);
+        while let Some((dependent, result)) = checks.next().await {
+            if let Err(e) = result {
+                self.client
+                    .log_message(MessageType::ERROR, format!("{dependent}: {e:?}"))
+                    .await;
+            }
+        }
+        if *self.can_pull_diagnostics.get().unwrap_or(&false)
+            && *self.can_refresh_diagnostics.get().unwrap_or(&false)
+        {
+            self.client.workspace_diagnostic_refresh().await?;
+        }
+        Ok(())
+    }
+
+    /// Updates what is known about files changed outside the editor,
+    /// then revalidates whatever imports them without holding up the notification.
+    pub(super) async fn try_did_change_watched_files(
+        &self,
+        params: DidChangeWatchedFilesParams,
+    ) -> Result<()> {
+        let mut changed = Vec::with_capacity(params.changes.len());
+        for change in params.changes {
+            if change.typ == FileChangeType::DELETED && !self.is_open(&change.uri)? {
+                self.forget_dependencies(&change.uri)?;
+            }
+            self.refresh_symbols(&change.uri).await?;
+            changed.push(change.uri);
+        }
+
+        let backend = self.clone();
+        tokio::spawn(async move {
+            for uri in changed {
+                if let Err(e) = backend.revalidate_dependents(&uri).await {
+                    backend
+                        .client
+                        .log_message(MessageType::ERROR, format!("{e:?}"))
+                        .await;
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// The files `text` imports, including those that don't exist (yet).
+pub(super) fn imported_files(
+    uri: &Url,
+    text: &str,
+    settings: &IdeSettings,
+) -> Result<HashSet<Url>> {
+    let include_paths = crate::nu::include_paths(settings, uri)?;
+    Ok(syntax::imports(text)
+        .iter()
+        .flat_map(|path| candidates(path.unquoted(), &include_paths))
+        .filter_map(|path| Url::from_file_path(path).ok())
+        .collect())
+}
+
+/// Every file nu might load for an import of `path`, in the order it would look for them.
+///
+/// An import that doesn't resolve yet still depends on the files that would satisfy it,
+/// so that creating one of them counts as a change.
+fn candidates(path: &str, include_paths: &[PathBuf]) -> Vec<PathBuf> {
+    // computed at runtime, so there's nothing to follow
+    if path.is_empty() || path.starts_with(['$', '(']) {
+        return vec![];
+    }
+    let path = match path.strip_prefix("~/") {
+        Some(rest) => match std::env::var_os("HOME") {
+            Some(home) => Path::new(&home).join(rest),
+            None => return vec![],
+        },
+        None => PathBuf::from(path),
+    };
+
+    let bases = if path.is_absolute() {
+        vec![path]
+    } else {
+        include_paths.iter().map(|dir| dir.join(&path)).collect()
+    };
+    bases
+        .into_iter()
+        .flat_map(|base| {
+            if base.extension().is_some_and(|e| e == "nu") {
+                vec![base]
+            } else {
+                // a module may be a directory, or a file named without its extension
+                let mut file = base.clone().into_os_string();
+                file.push(".nu");
+                vec![base.join("mod.nu"), PathBuf::from(file), base]
+            }
+        })
+        .collect()
+}
+
+/// The documents that import `uri`, directly or through other documents, excluding `uri` itself.
+pub(super) fn dependents(dependencies: &HashMap<Url, HashSet<Url>>, uri: &Url) -> Vec<Url> {
+    let mut found: Vec<Url> = vec![];
+    let mut pending = vec![uri];
+    while let Some(changed) = pending.pop() {
+        for (dependent, imported) in dependencies {
+            if imported.contains(changed) && dependent != uri && !found.contains(dependent) {
+                found.push(dependent.clone());
+                pending.push(dependent);
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates_search_include_paths() {
+        let include_paths = [PathBuf::from("/project/scripts"), PathBuf::from("/lib")];
+
+        assert_eq!(
+            candidates("utils.nu", &include_paths),
+            vec![
+                PathBuf::from("/project/scripts/utils.nu"),
+                PathBuf::from("/lib/utils.nu"),
+            ]
+        );
+        assert_eq!(
+            candidates("/abs/spam", &include_paths),
+            vec![
+                PathBuf::from("/abs/spam/mod.nu"),
+                PathBuf::from("/abs/spam.nu"),
+                PathBuf::from("/abs/spam"),
+            ]
+        );
+        assert!(candidates("$env.FILE_PWD", &include_paths).is_empty());
+    }
+
+    #[test]
+    fn dependents_are_transitive() {
+        let url = |name| Url::parse(&format!("file:///project/{name}")).expect("should parse URL");
+        let dependencies = HashMap::from([
+            (url("main.nu"), HashSet::from([url("lib.nu")])),
+            (url("lib.nu"), HashSet::from([url("utils.nu")])),
+            (url("utils.nu"), HashSet::from([url("main.nu")])),
+            (url("other.nu"), HashSet::new()),
+        ]);
+
+        let mut got = dependents(&dependencies, &url("utils.nu"));
+        got.sort();
+
+        assert_eq!(got, vec![url("lib.nu"), url("main.nu")]);
+    }
+}
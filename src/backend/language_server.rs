@@ -1,30 +1,43 @@
-use std::{borrow::Cow, ffi::OsStr};
+use std::borrow::Cow;
 
 use crate::{
-    backend::Backend,
-    error::map_err_to_parse_error,
-    nu::{run_compiler, IdeComplete, IdeGotoDef, IdeHover},
+    backend::{semantic_tokens, Backend, ClientSettingsPayload},
+    error::map_err_to_internal_error,
+    nu::{IdeCommand, IdeGotoDef, IdeHover},
+    offsets::LineIndex,
+    syntax,
 };
 
+use tower_lsp::jsonrpc::Result;
+use tower_lsp::lsp_types::notification::{DidChangeConfiguration, DidChangeWatchedFiles};
 #[allow(clippy::wildcard_imports)]
 use tower_lsp::lsp_types::*;
-use tower_lsp::{jsonrpc::Result, lsp_types::notification::DidChangeConfiguration};
 use tower_lsp::{lsp_types::notification::Notification, LanguageServer};
 
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
         let uri = params.text_document.uri.clone();
+        if let Err(e) = self.cancel_in_flight(&uri) {
+            self.client
+                .log_message(MessageType::ERROR, format!("{e:?}"))
+                .await;
+        }
         if let Err(e) = self.try_did_change(params) {
             self.client
                 .log_message(MessageType::ERROR, format!("{e:?}"))
                 .await;
         }
-        if let Err(e) = self.throttled_validate_document(&uri).await {
+        if let Err(e) = self.refresh_symbols(&uri).await {
             self.client
                 .log_message(MessageType::ERROR, format!("{e:?}"))
                 .await;
-        };
+        }
+        if let Err(e) = self.spawn_debounced_validation(uri) {
+            self.client
+                .log_message(MessageType::ERROR, format!("{e:?}"))
+                .await;
+        }
     }
 
     async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
@@ -35,6 +48,14 @@ impl LanguageServer for Backend {
         }
     }
 
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        if let Err(e) = self.try_did_change_watched_files(params).await {
+            self.client
+                .log_message(MessageType::ERROR, format!("{e:?}"))
+                .await;
+        }
+    }
+
     async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
         self.client
             .log_message(
@@ -45,14 +66,37 @@ impl LanguageServer for Backend {
                 ),
             )
             .await;
+        if let Err(e) = self.try_did_change_workspace_folders(params.event) {
+            self.client
+                .log_message(MessageType::ERROR, format!("{e:?}"))
+                .await;
+        }
+        if let Err(e) = self.reset_symbol_index() {
+            self.client
+                .log_message(MessageType::ERROR, format!("{e:?}"))
+                .await;
+        }
+        self.spawn_scan_workspace();
     }
 
     async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri.clone();
+        if let Err(e) = self.cancel_in_flight(&uri) {
+            self.client
+                .log_message(MessageType::ERROR, format!("{e:?}"))
+                .await;
+        }
         if let Err(e) = self.try_did_close(params) {
             self.client
                 .log_message(MessageType::ERROR, format!("{e:?}"))
                 .await;
         }
+        // back to what is saved on disk, if anything
+        if let Err(e) = self.refresh_symbols(&uri).await {
+            self.client
+                .log_message(MessageType::ERROR, format!("{e:?}"))
+                .await;
+        }
     }
 
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
@@ -62,6 +106,11 @@ impl LanguageServer for Backend {
                 .log_message(MessageType::ERROR, format!("{e:?}"))
                 .await;
         }
+        if let Err(e) = self.refresh_symbols(&uri).await {
+            self.client
+                .log_message(MessageType::ERROR, format!("{e:?}"))
+                .await;
+        }
         if let Err(e) = self.validate_document(&uri).await {
             self.client
                 .log_message(MessageType::ERROR, format!("{e:?}"))
@@ -70,53 +119,89 @@ impl LanguageServer for Backend {
     }
 
     async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
-        // panic: this is the only place we `OnceLock::set`,
-        // so we've entered strange territory if something else writes to them first
-
-        self.can_change_configuration
-            .set(matches!(
-                params.capabilities.workspace,
-                Some(WorkspaceClientCapabilities {
-                    did_change_configuration: Some(_),
-                    ..
-                })
-            ))
-            .expect("server value initialized out of sequence");
-
-        self.can_lookup_configuration
-            .set(matches!(
-                params.capabilities.workspace,
-                Some(WorkspaceClientCapabilities {
-                    configuration: Some(_),
-                    ..
-                })
-            ))
-            .expect("server value initialized out of sequence");
-
-        self.can_publish_diagnostics
-            .set(matches!(
-                params.capabilities.text_document,
-                Some(TextDocumentClientCapabilities {
-                    publish_diagnostics: Some(_),
-                    ..
+        self.set_client_capabilities(&params.capabilities);
+        let can_pull_diagnostics = *self.can_pull_diagnostics.get().unwrap_or(&false);
+        let encoding = self.encoding();
+
+        #[allow(deprecated)]
+        let workspace_folders = params.workspace_folders.unwrap_or_else(|| {
+            // from before clients could have more than one folder
+            params
+                .root_uri
+                .map(|uri| {
+                    vec![WorkspaceFolder {
+                        name: String::from(uri.path()),
+                        uri,
+                    }]
                 })
-            ))
-            .expect("server value initialized out of sequence");
+                .unwrap_or_default()
+        });
+        self.try_did_change_workspace_folders(WorkspaceFoldersChangeEvent {
+            added: workspace_folders,
+            removed: vec![],
+        })?;
+
+        if let Some(options) = params.initialization_options {
+            let settings: ClientSettingsPayload =
+                serde_json::from_value(options).unwrap_or_default();
+            let mut global_settings = self.global_settings.write().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot write global settings: {e:?}"))
+            })?;
+            *global_settings = settings.nushell_language_server;
+        }
+
+        // only advertise what the configured `nu` can actually do
+        let nu = self.probe_nu_capabilities().await?;
 
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
-                completion_provider: Some(CompletionOptions::default()),
-                definition_provider: Some(OneOf::Left(true)),
-                hover_provider: Some(HoverProviderCapability::Simple(true)),
-                inlay_hint_provider: Some(OneOf::Right(InlayHintServerCapabilities::Options(
-                    InlayHintOptions {
+                completion_provider: nu.ide_complete.then(|| CompletionOptions {
+                    // documentation and examples can be long, so they're only sent when asked for
+                    resolve_provider: Some(true),
+                    ..Default::default()
+                }),
+                definition_provider: nu.ide_goto_def.then_some(OneOf::Left(true)),
+                // found without `nu`, so available whatever its version
+                document_symbol_provider: Some(OneOf::Left(true)),
+                references_provider: Some(OneOf::Left(true)),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: WorkDoneProgressOptions::default(),
+                })),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
+                diagnostic_provider: (nu.ide_check && can_pull_diagnostics).then(|| {
+                    DiagnosticServerCapabilities::Options(DiagnosticOptions {
+                        identifier: Some(String::from(env!("CARGO_PKG_NAME"))),
+                        // `use` and `source` mean other files can affect a document's diagnostics
+                        inter_file_dependencies: true,
+                        workspace_diagnostics: true,
+                        ..Default::default()
+                    })
+                }),
+                hover_provider: nu
+                    .ide_hover
+                    .then_some(HoverProviderCapability::Simple(true)),
+                inlay_hint_provider: nu.ide_check.then(|| {
+                    OneOf::Right(InlayHintServerCapabilities::Options(InlayHintOptions {
                         resolve_provider: Some(false),
                         ..Default::default()
-                    },
-                ))),
-                // TODO: what do we do when the client doesn't support UTF-16 ?
-                // lsp-textdocument crate requires UTF-16
-                position_encoding: Some(PositionEncodingKind::UTF16),
+                    }))
+                }),
+                position_encoding: Some(encoding.kind()),
+                // built-ins need `nu --ide-hover`, but commands declared in the workspace don't
+                signature_help_provider: Some(SignatureHelpOptions {
+                    trigger_characters: Some(vec![String::from(" "), String::from("-")]),
+                    retrigger_characters: None,
+                    work_done_progress_options: WorkDoneProgressOptions::default(),
+                }),
+                semantic_tokens_provider: nu.ide_ast.then(|| {
+                    SemanticTokensServerCapabilities::SemanticTokensOptions(SemanticTokensOptions {
+                        legend: semantic_tokens::legend(),
+                        range: Some(true),
+                        full: Some(SemanticTokensFullOptions::Delta { delta: Some(true) }),
+                        ..Default::default()
+                    })
+                }),
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
                     TextDocumentSyncKind::INCREMENTAL,
                 )),
@@ -137,17 +222,32 @@ impl LanguageServer for Backend {
     }
 
     async fn initialized(&self, _params: InitializedParams) {
+        let mut registrations = vec![];
         if *self.can_change_configuration.get().unwrap_or(&false) {
             let method = String::from(DidChangeConfiguration::METHOD);
-            if let Err(e) = self
-                .client
-                .register_capability(vec![Registration {
-                    id: method.clone(),
-                    method,
-                    register_options: None,
-                }])
-                .await
-            {
+            registrations.push(Registration {
+                id: method.clone(),
+                method,
+                register_options: None,
+            });
+        }
+        // so that scripts are revalidated when a module they import changes on disk
+        if *self.can_watch_files.get().unwrap_or(&false) {
+            let method = String::from(DidChangeWatchedFiles::METHOD);
+            registrations.push(Registration {
+                id: method.clone(),
+                method,
+                register_options: serde_json::to_value(DidChangeWatchedFilesRegistrationOptions {
+                    watchers: vec![FileSystemWatcher {
+                        glob_pattern: GlobPattern::String(String::from("**/*.nu")),
+                        kind: None,
+                    }],
+                })
+                .ok(),
+            });
+        }
+        if !registrations.is_empty() {
+            if let Err(e) = self.client.register_capability(registrations).await {
                 self.client
                     .log_message(
                         MessageType::INFO,
@@ -157,42 +257,88 @@ impl LanguageServer for Backend {
             };
         }
 
+        if let Some(fallback) = &self.compiler_fallback {
+            self.client
+                .log_message(MessageType::WARNING, fallback)
+                .await;
+        }
+        if let Some(nu) = self.nu_capabilities.get() {
+            self.client
+                .log_message(
+                    MessageType::INFO,
+                    format!(
+                        "using nu version {}",
+                        nu.version.as_deref().unwrap_or("unknown")
+                    ),
+                )
+                .await;
+        }
+
+        // loaded now, rather than when first needed, so that doesn't have to wait
+        self.catalog().await;
+
         self.client
             .log_message(MessageType::INFO, "server initialized!")
             .await;
+
+        self.spawn_scan_workspace();
     }
 
     async fn shutdown(&self) -> Result<()> {
         self.client
             .log_message(MessageType::INFO, "server shutdown...!")
             .await;
+        self.compiler.shutdown().await;
+        self.client
+            .log_message(
+                MessageType::INFO,
+                format!(
+                    "nu processes drained, {} crashed or hung during this session",
+                    self.compiler.crashes()
+                ),
+            )
+            .await;
         Ok(())
     }
 
     async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
-        let uri = params.text_document_position.text_document.uri;
-        let (text, offset) = self.for_document(&uri, &|doc| {
-            (
-                String::from(doc.get_content(None)),
-                doc.offset_at(params.text_document_position.position),
-            )
-        })?;
-
-        let ide_settings = self.get_document_settings(&uri).await?;
-        let output = run_compiler(
-            &text,
-            vec![
-                OsStr::new("--ide-complete"),
-                OsStr::new(&format!("{offset}")),
-            ],
-            ide_settings,
-            &uri,
+        self.completion(
+            &params.text_document_position.text_document.uri,
+            params.text_document_position.position,
         )
-        .await?;
+        .await
+    }
 
-        let complete = IdeComplete::try_from(output)?;
+    async fn completion_resolve(&self, params: CompletionItem) -> Result<CompletionItem> {
+        self.completion_resolve(params).await
+    }
 
-        Ok(Some(CompletionResponse::from(complete)))
+    async fn diagnostic(
+        &self,
+        params: DocumentDiagnosticParams,
+    ) -> Result<DocumentDiagnosticReportResult> {
+        let report = self
+            .pull_diagnostics(
+                &params.text_document.uri,
+                params.previous_result_id.as_deref(),
+            )
+            .await?;
+        Ok(DocumentDiagnosticReportResult::Report(report))
+    }
+
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        let text = self.for_document(&params.text_document.uri, &|doc| {
+            String::from(doc.get_content(None))
+        })?;
+        let index = LineIndex::new(&text, self.encoding());
+        let symbols = syntax::declarations(&text)
+            .iter()
+            .map(|d| d.to_document_symbol(&index))
+            .collect();
+        Ok(Some(DocumentSymbolResponse::Nested(symbols)))
     }
 
     async fn goto_definition(
@@ -200,34 +346,31 @@ impl LanguageServer for Backend {
         params: GotoDefinitionParams,
     ) -> Result<Option<GotoDefinitionResponse>> {
         let uri = params.text_document_position_params.text_document.uri;
-        let (text, offset) = self.for_document(&uri, &|doc| {
-            (
-                String::from(doc.get_content(None)),
-                doc.offset_at(params.text_document_position_params.position),
-            )
-        })?;
+        let text = self.for_document(&uri, &|doc| String::from(doc.get_content(None)))?;
+        let index = LineIndex::new(&text, self.encoding());
+        let offset = index.offset(params.text_document_position_params.position);
 
         let ide_settings = self.get_document_settings(&uri).await?;
-        let output = run_compiler(
-            &text,
-            vec![
-                OsStr::new("--ide-goto-def"),
-                OsStr::new(&format!("{offset}")),
-            ],
-            ide_settings,
-            &uri,
-        )
-        .await?;
+        let output = self
+            .run_compiler(&text, IdeCommand::GotoDef(offset), ide_settings, &uri)
+            .await?;
 
         let goto_def: IdeGotoDef =
-            serde_json::from_slice(output.stdout.as_bytes()).map_err(|e| {
-                map_err_to_parse_error(e, format!("cannot parse response from {}", output.cmdline))
-            })?;
+            serde_json::from_slice(output.stdout.as_bytes()).map_err(|e| output.parse_error(e))?;
 
         if matches!(goto_def.file.to_str(), None | Some("" | "__prelude__")) {
             return Ok(None);
         }
 
+        // `nu` only knows the document by the (possibly temporary) file it read it from
+        if output.source_path.as_ref() == Some(&goto_def.file) {
+            let range = index.range(goto_def.start, goto_def.end);
+            return Ok(Some(GotoDefinitionResponse::Scalar(Location {
+                uri,
+                range,
+            })));
+        }
+
         if !goto_def.file.exists() {
             self.client
                 .log_message(
@@ -238,10 +381,15 @@ impl LanguageServer for Backend {
             return Ok(None);
         }
 
-        let range = self.for_document(&uri, &|doc| Range {
-            start: doc.position_at(goto_def.start),
-            end: doc.position_at(goto_def.end),
-        })?;
+        // spans are relative to the file the definition is in, not the document,
+        // and as it is saved: `nu` reads imports from disk, so unsaved edits to an open import aren't seen,
+        // and the range may be off until that file is saved
+        let target = tokio::fs::read_to_string(&goto_def.file)
+            .await
+            .map_err(|e| {
+                map_err_to_internal_error(e, format!("cannot read {}", goto_def.file.display()))
+            })?;
+        let range = LineIndex::new(&target, self.encoding()).range(goto_def.start, goto_def.end);
 
         Ok(Some(GotoDefinitionResponse::Scalar(Location {
             uri: Url::from_file_path(goto_def.file).map_err(|()| {
@@ -257,32 +405,22 @@ impl LanguageServer for Backend {
 
     async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
         let uri = params.text_document_position_params.text_document.uri;
-        let (text, offset) = self.for_document(&uri, &|doc| {
-            (
-                String::from(doc.get_content(None)),
-                doc.offset_at(params.text_document_position_params.position),
-            )
-        })?;
+        let text = self.for_document(&uri, &|doc| String::from(doc.get_content(None)))?;
+        let index = LineIndex::new(&text, self.encoding());
+        let offset = index.offset(params.text_document_position_params.position);
 
         let ide_settings = self.get_document_settings(&uri).await?;
-        let output = run_compiler(
-            &text,
-            vec![OsStr::new("--ide-hover"), OsStr::new(&format!("{offset}"))],
-            ide_settings,
-            &uri,
-        )
-        .await?;
+        let output = self
+            .run_compiler(&text, IdeCommand::Hover(offset), ide_settings, &uri)
+            .await?;
 
-        let hover: IdeHover = serde_json::from_slice(output.stdout.as_bytes()).map_err(|e| {
-            map_err_to_parse_error(e, format!("cannot parse response from {}", output.cmdline))
-        })?;
+        let hover: IdeHover =
+            serde_json::from_slice(output.stdout.as_bytes()).map_err(|e| output.parse_error(e))?;
 
-        let range = self.for_document(&uri, &|doc| {
-            hover.span.as_ref().map(|span| Range {
-                start: doc.position_at(span.start),
-                end: doc.position_at(span.end),
-            })
-        })?;
+        let range = hover
+            .span
+            .as_ref()
+            .map(|span| index.range(span.start, span.end));
 
         Ok(Some(Hover {
             contents: HoverContents::Scalar(MarkedString::String(hover.hover)),
@@ -298,4 +436,1588 @@ impl LanguageServer for Backend {
         })?;
         Ok(document_inlay_hints.get(&params.text_document.uri).cloned())
     }
+
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        self.prepare_rename(&params.text_document.uri, params.position)
+            .await
+    }
+
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        self.references(
+            &params.text_document_position.text_document.uri,
+            params.text_document_position.position,
+            params.context.include_declaration,
+        )
+        .await
+    }
+
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        self.rename(
+            &params.text_document_position.text_document.uri,
+            params.text_document_position.position,
+            &params.new_name,
+        )
+        .await
+    }
+
+    async fn semantic_tokens_full(
+        &self,
+        params: SemanticTokensParams,
+    ) -> Result<Option<SemanticTokensResult>> {
+        let tokens = self.semantic_tokens_full(&params.text_document.uri).await?;
+        Ok(Some(SemanticTokensResult::Tokens(tokens)))
+    }
+
+    async fn semantic_tokens_full_delta(
+        &self,
+        params: SemanticTokensDeltaParams,
+    ) -> Result<Option<SemanticTokensFullDeltaResult>> {
+        let delta = self
+            .semantic_tokens_delta(&params.text_document.uri, &params.previous_result_id)
+            .await?;
+        Ok(Some(delta))
+    }
+
+    async fn semantic_tokens_range(
+        &self,
+        params: SemanticTokensRangeParams,
+    ) -> Result<Option<SemanticTokensRangeResult>> {
+        let tokens = self
+            .semantic_tokens_range(&params.text_document.uri, params.range)
+            .await?;
+        Ok(Some(SemanticTokensRangeResult::Tokens(tokens)))
+    }
+
+    async fn signature_help(&self, params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
+        self.signature_help(
+            &params.text_document_position_params.text_document.uri,
+            params.text_document_position_params.position,
+        )
+        .await
+    }
+
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<Vec<SymbolInformation>>> {
+        Ok(Some(self.workspace_symbols(&params.query).await?))
+    }
+
+    async fn workspace_diagnostic(
+        &self,
+        params: WorkspaceDiagnosticParams,
+    ) -> Result<WorkspaceDiagnosticReportResult> {
+        let report = self.pull_workspace_diagnostics(params).await?;
+        Ok(WorkspaceDiagnosticReportResult::Report(report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+    use tokio::io::{
+        AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf,
+    };
+    use tokio::time::timeout;
+    use tower_lsp::{LspService, Server};
+
+    use super::*;
+    use crate::nu::{capabilities::NuCapabilities, fake::ScriptedCompiler};
+
+    const URI: &str = "file:///foo.nu";
+
+    /// Drives a real server over an in-memory pipe, speaking JSON-RPC like an editor would.
+    struct TestClient {
+        next_id: i64,
+        notifications: Vec<Value>,
+        reader: BufReader<ReadHalf<DuplexStream>>,
+        // from the server, already answered
+        requests: Vec<Value>,
+        writer: WriteHalf<DuplexStream>,
+    }
+
+    impl TestClient {
+        fn start(compiler: ScriptedCompiler) -> Self {
+            let (client, server) = tokio::io::duplex(1 << 16);
+            let (server_reader, server_writer) = tokio::io::split(server);
+            let (service, socket) =
+                LspService::new(|client| Backend::new(client, Box::new(compiler), None));
+            tokio::spawn(Server::new(server_reader, server_writer, socket).serve(service));
+
+            let (reader, writer) = tokio::io::split(client);
+            Self {
+                next_id: 0,
+                notifications: vec![],
+                requests: vec![],
+                reader: BufReader::new(reader),
+                writer,
+            }
+        }
+
+        async fn send(&mut self, message: Value) {
+            let body = message.to_string();
+            self.writer
+                .write_all(format!("Content-Length: {}\r\n\r\n{body}", body.len()).as_bytes())
+                .await
+                .expect("should write to server");
+        }
+
+        async fn receive(&mut self) -> Value {
+            timeout(Duration::from_secs(5), async {
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    self.reader
+                        .read_line(&mut line)
+                        .await
+                        .expect("should read header");
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.parse().expect("should parse Content-Length");
+                    }
+                }
+                let mut body = vec![0; length];
+                self.reader
+                    .read_exact(&mut body)
+                    .await
+                    .expect("should read body");
+                serde_json::from_slice(&body).expect("should parse body")
+            })
+            .await
+            .expect("server should respond in time")
+        }
+
+        /// Answers requests from the server, and keeps them and notifications for later.
+        async fn handle(&mut self, message: Value) {
+            match (message.get("id"), message.get("method")) {
+                (Some(id), Some(method)) => {
+                    let result = if method == "workspace/configuration" {
+                        json!(message["params"]["items"]
+                            .as_array()
+                            .map(|items| vec![Value::Null; items.len()]))
+                    } else {
+                        Value::Null
+                    };
+                    self.send(json!({ "id": id, "jsonrpc": "2.0", "result": result }))
+                        .await;
+                    self.requests.push(message);
+                }
+                (None, Some(_)) => self.notifications.push(message),
+                _ => {}
+            }
+        }
+
+        async fn notify(&mut self, method: &str, params: Value) {
+            self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+                .await;
+        }
+
+        /// Waits for the next notification with this method, returning its params.
+        async fn notification(&mut self, method: &str) -> Value {
+            loop {
+                if let Some(i) = self
+                    .notifications
+                    .iter()
+                    .position(|n| n["method"] == method)
+                {
+                    return self.notifications.remove(i)["params"].take();
+                }
+                let message = self.receive().await;
+                self.handle(message).await;
+            }
+        }
+
+        /// Waits for the next request from the server with this method, returning its params.
+        async fn server_request(&mut self, method: &str) -> Value {
+            loop {
+                if let Some(i) = self.requests.iter().position(|n| n["method"] == method) {
+                    return self.requests.remove(i)["params"].take();
+                }
+                let message = self.receive().await;
+                self.handle(message).await;
+            }
+        }
+
+        /// Sends a request and waits for its response, returning the result or error.
+        async fn request(&mut self, method: &str, params: Value) -> Value {
+            let id = self.start_request(method, params).await;
+            self.response(id).await
+        }
+
+        /// Waits for the response to an earlier request, returning the result or error.
+        async fn response(&mut self, id: Value) -> Value {
+            loop {
+                let mut message = self.receive().await;
+                if message["id"] == id && message.get("method").is_none() {
+                    return match message.get_mut("error") {
+                        Some(error) => error.take(),
+                        None => message["result"].take(),
+                    };
+                }
+                self.handle(message).await;
+            }
+        }
+
+        /// Sends a request without waiting for its response, returning its ID.
+        async fn start_request(&mut self, method: &str, params: Value) -> Value {
+            self.next_id += 1;
+            let id = json!(self.next_id);
+            let mut message = json!({ "id": id, "jsonrpc": "2.0", "method": method });
+            if !params.is_null() {
+                message["params"] = params;
+            }
+            self.send(message).await;
+            id
+        }
+
+        async fn initialize(&mut self) -> Value {
+            self.initialize_with(
+                json!({ "capabilities": { "textDocument": { "publishDiagnostics": {} } } }),
+            )
+            .await
+        }
+
+        async fn initialize_with(&mut self, params: Value) -> Value {
+            let result = self.request("initialize", params).await;
+            self.notify("initialized", json!({})).await;
+            result
+        }
+
+        async fn open(&mut self, text: &str) {
+            self.open_at(URI, text).await;
+        }
+
+        async fn open_at(&mut self, uri: &str, text: &str) {
+            self.notify(
+                "textDocument/didOpen",
+                json!({ "textDocument": { "languageId": "nushell", "text": text, "uri": uri, "version": 1 } }),
+            )
+            .await;
+        }
+
+        async fn change_at(&mut self, uri: &str, version: i32, text: &str) {
+            self.notify(
+                "textDocument/didChange",
+                json!({
+                    "contentChanges": [{ "text": text }],
+                    "textDocument": { "uri": uri, "version": version },
+                }),
+            )
+            .await;
+        }
+    }
+
+    fn position(line: u32, character: u32) -> Value {
+        json!({ "textDocument": { "uri": URI }, "position": { "character": character, "line": line } })
+    }
+
+    #[tokio::test]
+    async fn initialize_advertises_capabilities() {
+        let mut client = TestClient::start(ScriptedCompiler::default());
+
+        let got = client.initialize().await;
+
+        assert_eq!(got["capabilities"]["hoverProvider"], json!(true));
+        assert_eq!(got["capabilities"]["definitionProvider"], json!(true));
+        assert!(got["capabilities"]["completionProvider"].is_object());
+        assert_eq!(got["serverInfo"]["name"], json!("nuls"));
+    }
+
+    #[tokio::test]
+    async fn initialize_omits_capabilities_nu_lacks() {
+        let compiler = ScriptedCompiler::default().capabilities(NuCapabilities {
+            ide_goto_def: false,
+            ide_hover: false,
+            version: Some(String::from("0.79.0")),
+            ..NuCapabilities::all()
+        });
+        let mut client = TestClient::start(compiler);
+
+        let got = client.initialize().await;
+
+        assert!(got["capabilities"]["hoverProvider"].is_null());
+        assert!(got["capabilities"]["definitionProvider"].is_null());
+        assert!(got["capabilities"]["completionProvider"].is_object());
+        assert!(got["capabilities"]["inlayHintProvider"].is_object());
+    }
+
+    #[tokio::test]
+    async fn did_open_publishes_diagnostics_and_inlay_hints() {
+        let compiler = ScriptedCompiler::default().respond(
+            IdeCommand::Check,
+            [
+                r#"{"message":"The '||' operator is not supported in Nushell","severity":"Error","span":{"end":23,"start":21},"type":"diagnostic"}"#,
+                r#"{"position":{"end":7,"start":4},"type":"hint","typename":"list<string>"}"#,
+            ]
+            .join("\n"),
+        );
+        let mut client = TestClient::start(compiler.clone());
+        client.initialize().await;
+
+        client.open("let foo = ['one']\nls ||\n").await;
+        let got = client.notification("textDocument/publishDiagnostics").await;
+
+        assert_eq!(compiler.calls(), vec![IdeCommand::Check]);
+        assert_eq!(got["uri"], json!(URI));
+        assert_eq!(got["version"], json!(1));
+        assert_eq!(
+            got["diagnostics"],
+            json!([{
+                "message": "The '||' operator is not supported in Nushell",
+                "range": { "end": { "character": 5, "line": 1 }, "start": { "character": 3, "line": 1 } },
+                "severity": 1,
+                "source": URI,
+            }])
+        );
+
+        let got = client
+            .request(
+                "textDocument/inlayHint",
+                json!({
+                    "range": { "end": { "character": 0, "line": 2 }, "start": { "character": 0, "line": 0 } },
+                    "textDocument": { "uri": URI },
+                }),
+            )
+            .await;
+
+        assert_eq!(got[0]["label"], json!(": list<string>"));
+        assert_eq!(got[0]["position"], json!({ "character": 7, "line": 0 }));
+    }
+
+    #[tokio::test]
+    async fn did_open_reports_nu_crash() {
+        let compiler = ScriptedCompiler::default().crash(
+            IdeCommand::Check,
+            101,
+            "thread 'main' panicked at 'oops'\n",
+        );
+        let mut client = TestClient::start(compiler);
+        client.initialize().await;
+
+        client.open("ls").await;
+        let got = client.notification("textDocument/publishDiagnostics").await;
+
+        assert_eq!(
+            got["diagnostics"],
+            json!([{
+                "message": "`nu --ide-check` exited with status 101: thread 'main' panicked at 'oops'",
+                "range": { "end": { "character": 0, "line": 0 }, "start": { "character": 0, "line": 0 } },
+                "severity": 1,
+                "source": "nuls",
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn did_open_reports_nu_timeout() {
+        let compiler = ScriptedCompiler::default()
+            .fail(IdeCommand::Check, "`nu --ide-check` timed out after 10s");
+        let mut client = TestClient::start(compiler);
+        client.initialize().await;
+
+        client.open("ls").await;
+        let got = client.notification("textDocument/publishDiagnostics").await;
+
+        assert_eq!(got["version"], json!(1));
+        assert_eq!(
+            got["diagnostics"][0]["message"],
+            json!("`nu --ide-check` timed out after 10s")
+        );
+    }
+
+    #[tokio::test]
+    async fn diagnostic_pull_reuses_unchanged_results() {
+        let compiler = ScriptedCompiler::default().respond(
+            IdeCommand::Check,
+            r#"{"message":"Missing required positional argument.","severity":"Error","span":{"end":2,"start":0},"type":"diagnostic"}"#,
+        );
+        let mut client = TestClient::start(compiler.clone());
+        let got = client
+            .initialize_with(json!({
+                "capabilities": { "textDocument": { "diagnostic": {}, "publishDiagnostics": {} } },
+            }))
+            .await;
+        assert!(got["capabilities"]["diagnosticProvider"].is_object());
+        client.open("ls").await;
+        let document = json!({ "textDocument": { "uri": URI } });
+
+        let got = client
+            .request("textDocument/diagnostic", document.clone())
+            .await;
+
+        assert_eq!(got["kind"], json!("full"));
+        assert_eq!(
+            got["items"][0]["message"],
+            json!("Missing required positional argument.")
+        );
+        let checks = compiler.calls().len();
+
+        let result_id = got["resultId"].clone();
+        let got = client
+            .request(
+                "textDocument/diagnostic",
+                json!({ "previousResultId": result_id, "textDocument": { "uri": URI } }),
+            )
+            .await;
+
+        assert_eq!(got, json!({ "kind": "unchanged", "resultId": result_id }));
+        assert_eq!(compiler.calls().len(), checks);
+    }
+
+    #[tokio::test]
+    async fn debounced_validation_reuses_pulled_results() {
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler.clone());
+        client
+            .initialize_with(json!({
+                "capabilities": { "textDocument": { "diagnostic": {} } },
+                "initializationOptions": {
+                    "nushellLanguageServer": { "diagnosticsDebounceTime": 50 },
+                },
+            }))
+            .await;
+        client.open("l").await;
+        client.change_at(URI, 2, "ls").await;
+
+        // pulled before the edits pause
+        let got = client
+            .request(
+                "textDocument/diagnostic",
+                json!({ "textDocument": { "uri": URI } }),
+            )
+            .await;
+        assert_eq!(got["kind"], json!("full"));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(compiler.calls().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn dependents_revalidate_when_imports_change() {
+        let dir = mktemp::Temp::new_dir().expect("should create temporary directory");
+        let root: &std::path::Path = dir.as_ref();
+        std::fs::write(root.join("lib.nu"), "export def hello [] {}").expect("should write file");
+        let url = |file| {
+            Url::from_file_path(root.join(file))
+                .expect("should convert path to URL")
+                .to_string()
+        };
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler.clone());
+        client
+            .initialize_with(json!({
+                "capabilities": {
+                    "textDocument": { "publishDiagnostics": {} },
+                    "workspace": { "didChangeWatchedFiles": { "dynamicRegistration": true } },
+                },
+                "initializationOptions": {
+                    "nushellLanguageServer": { "diagnosticsDebounceTime": 0 },
+                },
+            }))
+            .await;
+
+        let got = client.server_request("client/registerCapability").await;
+        assert_eq!(
+            got["registrations"][0]["method"],
+            json!("workspace/didChangeWatchedFiles")
+        );
+
+        client.open_at(&url("main.nu"), "use lib.nu *\nhello").await;
+        client.notification("textDocument/publishDiagnostics").await;
+
+        // saved elsewhere, e.g. by `git checkout`
+        client
+            .notify(
+                "workspace/didChangeWatchedFiles",
+                json!({ "changes": [{ "type": 2, "uri": url("lib.nu") }] }),
+            )
+            .await;
+        let got = client.notification("textDocument/publishDiagnostics").await;
+        assert_eq!(got["uri"], json!(url("main.nu")));
+        assert_eq!(compiler.calls().len(), 2);
+
+        // edited in another buffer
+        client
+            .open_at(&url("lib.nu"), "export def hello [] {}")
+            .await;
+        client.notification("textDocument/publishDiagnostics").await;
+        client
+            .change_at(&url("lib.nu"), 2, "export def hello [] { 1 }")
+            .await;
+        let mut got: Vec<Value> = vec![
+            client.notification("textDocument/publishDiagnostics").await["uri"].take(),
+            client.notification("textDocument/publishDiagnostics").await["uri"].take(),
+        ];
+        got.sort_by_key(Value::to_string);
+        assert_eq!(got, vec![json!(url("lib.nu")), json!(url("main.nu"))]);
+    }
+
+    #[tokio::test]
+    async fn workspace_folders_are_include_roots() {
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler.clone());
+        client
+            .initialize_with(json!({
+                "capabilities": { "textDocument": { "publishDiagnostics": {} } },
+                "initializationOptions": {
+                    "nushellLanguageServer": { "includeDirs": ["lib", "/opt/nu"] },
+                },
+                "workspaceFolders": [
+                    { "name": "tools", "uri": "file:///monorepo/tools/" },
+                    { "name": "app", "uri": "file:///monorepo/app/" },
+                ],
+            }))
+            .await;
+
+        client
+            .open_at("file:///monorepo/app/scripts/build.nu", "use mylib")
+            .await;
+        client.notification("textDocument/publishDiagnostics").await;
+
+        let settings = compiler.settings();
+        assert_eq!(
+            settings[0].include_dirs,
+            vec![
+                std::path::PathBuf::from("/monorepo/app/lib"),
+                std::path::PathBuf::from("/opt/nu"),
+            ]
+        );
+        assert_eq!(
+            settings[0].workspace_folders,
+            vec![
+                std::path::PathBuf::from("/monorepo/app"),
+                std::path::PathBuf::from("/monorepo/tools"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn workspace_symbol_searches_unopened_and_edited_files() {
+        let dir = mktemp::Temp::new_dir().expect("should create temporary directory");
+        let root: &std::path::Path = dir.as_ref();
+        std::fs::create_dir_all(root.join("lib")).expect("should create directory");
+        std::fs::write(root.join("build.nu"), "def build [] {}").expect("should write file");
+        std::fs::write(
+            root.join("lib/tools.nu"),
+            "module tools {\n  export def 'tool run' [] {}\n}",
+        )
+        .expect("should write file");
+        let url = |file| {
+            Url::from_file_path(root.join(file))
+                .expect("should convert path to URL")
+                .to_string()
+        };
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler);
+        client
+            .initialize_with(json!({
+                "capabilities": {},
+                "initializationOptions": {
+                    "nushellLanguageServer": { "diagnosticsDebounceTime": 0 },
+                },
+                "workspaceFolders": [{
+                    "name": "project",
+                    "uri": Url::from_directory_path(root).expect("should convert path to URL"),
+                }],
+            }))
+            .await;
+
+        let got = client
+            .request("workspace/symbol", json!({ "query": "tr" }))
+            .await;
+
+        assert_eq!(got[0]["name"], json!("tool run"));
+        assert_eq!(got[0]["containerName"], json!("tools"));
+        assert_eq!(got[0]["location"]["uri"], json!(url("lib/tools.nu")));
+        assert_eq!(
+            got[0]["location"]["range"]["start"],
+            json!({ "character": 13, "line": 1 })
+        );
+
+        client.open_at(&url("build.nu"), "def build [] {}").await;
+        client
+            .change_at(&url("build.nu"), 2, "def rebuild [] {}")
+            .await;
+        let got = client
+            .request("workspace/symbol", json!({ "query": "build" }))
+            .await;
+
+        let names: Vec<&Value> = got
+            .as_array()
+            .expect("should list symbols")
+            .iter()
+            .map(|s| &s["name"])
+            .collect();
+        assert_eq!(names, vec![&json!("rebuild")]);
+    }
+
+    #[tokio::test]
+    async fn references_follow_imports_across_files() {
+        let dir = mktemp::Temp::new_dir().expect("should create temporary directory");
+        let root: &std::path::Path = dir.as_ref();
+        std::fs::write(root.join("lib.nu"), "export def build [] {}\n").expect("should write file");
+        std::fs::write(root.join("main.nu"), "use lib.nu build\nbuild\n")
+            .expect("should write file");
+        // declares a command of the same name, without importing lib.nu
+        std::fs::write(root.join("other.nu"), "def build [] {}\nbuild\n")
+            .expect("should write file");
+        let url = |file| {
+            Url::from_file_path(root.join(file))
+                .expect("should convert path to URL")
+                .to_string()
+        };
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler);
+        client
+            .initialize_with(json!({
+                "capabilities": {},
+                "initializationOptions": {
+                    "nushellLanguageServer": { "diagnosticsDebounceTime": 0 },
+                },
+                "workspaceFolders": [{
+                    "name": "project",
+                    "uri": Url::from_directory_path(root).expect("should convert path to URL"),
+                }],
+            }))
+            .await;
+        client
+            .open_at(&url("lib.nu"), "export def build [] {}\n")
+            .await;
+
+        let references = |include_declaration| {
+            json!({
+                "textDocument": { "uri": url("lib.nu") },
+                "position": { "line": 0, "character": 13 },
+                "context": { "includeDeclaration": include_declaration },
+            })
+        };
+        let got = client
+            .request("textDocument/references", references(true))
+            .await;
+
+        let mut got: Vec<(String, u64, u64, u64)> = got
+            .as_array()
+            .expect("should list locations")
+            .iter()
+            .map(|l| {
+                let start = &l["range"]["start"];
+                (
+                    l["uri"].as_str().unwrap_or_default().to_string(),
+                    start["line"].as_u64().unwrap_or_default(),
+                    start["character"].as_u64().unwrap_or_default(),
+                    l["range"]["end"]["character"].as_u64().unwrap_or_default(),
+                )
+            })
+            .collect();
+        got.sort();
+        assert_eq!(
+            got,
+            vec![
+                (url("lib.nu"), 0, 11, 16),
+                (url("main.nu"), 0, 11, 16),
+                (url("main.nu"), 1, 0, 5),
+            ]
+        );
+
+        let got = client
+            .request("textDocument/references", references(false))
+            .await;
+        assert_eq!(got.as_array().map(Vec::len), Some(2));
+    }
+
+    #[tokio::test]
+    async fn prepare_rename_survives_names_and_imports_being_typed() {
+        let dir = mktemp::Temp::new_dir().expect("should create temporary directory");
+        let root: &std::path::Path = dir.as_ref();
+        let main = "def \"\" [] {}\ndef greet [] {}\ngreet\nuse\n";
+        std::fs::write(root.join("main.nu"), main).expect("should write file");
+        std::fs::write(root.join("other.nu"), "export use").expect("should write file");
+        let uri = Url::from_file_path(root.join("main.nu"))
+            .expect("should convert path to URL")
+            .to_string();
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler);
+        client
+            .initialize_with(json!({
+                "capabilities": {},
+                "workspaceFolders": [{
+                    "name": "project",
+                    "uri": Url::from_directory_path(root).expect("should convert path to URL"),
+                }],
+            }))
+            .await;
+        client.open_at(&uri, main).await;
+        let at = |line, character| {
+            json!({
+                "textDocument": { "uri": uri },
+                "position": { "line": line, "character": character },
+            })
+        };
+
+        // within the quotes of `def ""`
+        let got = client.request("textDocument/prepareRename", at(0, 5)).await;
+        assert_eq!(got, Value::Null);
+        // a bare `use`
+        let got = client.request("textDocument/prepareRename", at(3, 1)).await;
+        assert_eq!(got, Value::Null);
+
+        // every file is searched for `greet`, including those with unfinished imports
+        let got = client.request("textDocument/prepareRename", at(2, 1)).await;
+        assert_eq!(got["placeholder"], json!("greet"));
+    }
+
+    #[tokio::test]
+    async fn rename_updates_declarations_imports_and_calls() {
+        let dir = mktemp::Temp::new_dir().expect("should create temporary directory");
+        let root: &std::path::Path = dir.as_ref();
+        let lib = "export def \"git st\" [] {}\n";
+        let main = "use lib.nu [\"git st\"]\ngit st --short\nls\n";
+        std::fs::write(root.join("lib.nu"), lib).expect("should write file");
+        std::fs::write(root.join("main.nu"), main).expect("should write file");
+        let url = |file| {
+            Url::from_file_path(root.join(file))
+                .expect("should convert path to URL")
+                .to_string()
+        };
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler);
+        client
+            .initialize_with(json!({
+                "capabilities": {},
+                "initializationOptions": {
+                    "nushellLanguageServer": { "diagnosticsDebounceTime": 0 },
+                },
+                "workspaceFolders": [{
+                    "name": "project",
+                    "uri": Url::from_directory_path(root).expect("should convert path to URL"),
+                }],
+            }))
+            .await;
+        client.open_at(&url("main.nu"), main).await;
+        let at = |line, character| {
+            json!({
+                "textDocument": { "uri": url("main.nu") },
+                "position": { "line": line, "character": character },
+            })
+        };
+        let range = |line, start, end| {
+            json!({
+                "start": { "line": line, "character": start },
+                "end": { "line": line, "character": end },
+            })
+        };
+
+        let got = client.request("textDocument/prepareRename", at(1, 5)).await;
+        assert_eq!(
+            got,
+            json!({ "range": range(1, 0, 6), "placeholder": "git st" })
+        );
+        // built-in, so not ours to rename
+        let got = client.request("textDocument/prepareRename", at(2, 1)).await;
+        assert_eq!(got, Value::Null);
+
+        let mut params = at(1, 5);
+        params["newName"] = json!("git status");
+        let got = client.request("textDocument/rename", params).await;
+
+        assert_eq!(
+            got["changes"][url("lib.nu")],
+            json!([{ "range": range(0, 12, 18), "newText": "git status" }])
+        );
+        assert_eq!(
+            got["changes"][url("main.nu")],
+            json!([
+                { "range": range(0, 13, 19), "newText": "git status" },
+                { "range": range(1, 0, 6), "newText": "git status" },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn references_to_variable_leave_others_with_the_same_name() {
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler);
+        client.initialize().await;
+        client
+            .open("def a [name] { $name }\ndef b [name] { $name }")
+            .await;
+
+        let got = client
+            .request(
+                "textDocument/references",
+                json!({
+                    "textDocument": { "uri": URI },
+                    "position": { "line": 1, "character": 17 },
+                    "context": { "includeDeclaration": true },
+                }),
+            )
+            .await;
+
+        assert_eq!(
+            got,
+            json!([
+                { "uri": URI, "range": { "start": { "line": 1, "character": 7 }, "end": { "line": 1, "character": 11 } } },
+                { "uri": URI, "range": { "start": { "line": 1, "character": 15 }, "end": { "line": 1, "character": 20 } } },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn rename_variable_leaves_others_with_the_same_name() {
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler);
+        client.initialize().await;
+        client
+            .open("def a [name] { $name }\ndef b [name] { $name }")
+            .await;
+        let range = |line, start, end| {
+            json!({
+                "start": { "line": line, "character": start },
+                "end": { "line": line, "character": end },
+            })
+        };
+
+        let mut params = position(0, 17);
+        params["newName"] = json!("who");
+        let got = client.request("textDocument/rename", params).await;
+
+        assert_eq!(
+            got["changes"][URI],
+            json!([
+                { "range": range(0, 7, 11), "newText": "who" },
+                { "range": range(0, 16, 20), "newText": "who" },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn workspace_diagnostic_checks_unopened_files() {
+        let dir = mktemp::Temp::new_dir().expect("should create temporary directory");
+        let root: &std::path::Path = dir.as_ref();
+        for file in [
+            "a.nu",
+            "lib/b.nu",
+            "lib/b.test.nu",
+            "lib/notes.txt",
+            "vendor/c.nu",
+        ] {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().expect("should have a parent"))
+                .expect("should create directory");
+            std::fs::write(path, "ls ||").expect("should write file");
+        }
+        let compiler = ScriptedCompiler::default().respond(
+            IdeCommand::Check,
+            r#"{"message":"The '||' operator is not supported in Nushell","severity":"Error","span":{"end":5,"start":3},"type":"diagnostic"}"#,
+        );
+        let mut client = TestClient::start(compiler.clone());
+        client
+            .initialize_with(json!({
+                "capabilities": { "textDocument": { "diagnostic": {} } },
+                "initializationOptions": {
+                    "nushellLanguageServer": { "workspace": { "ignore": ["**/vendor", "lib/*.test.nu"] } },
+                },
+                "workspaceFolders": [{
+                    "name": "project",
+                    "uri": Url::from_directory_path(root).expect("should convert path to URL"),
+                }],
+            }))
+            .await;
+
+        let got = client
+            .request("workspace/diagnostic", json!({ "previousResultIds": [] }))
+            .await;
+
+        let mut uris: Vec<&str> = got["items"]
+            .as_array()
+            .expect("should have items")
+            .iter()
+            .filter_map(|item| {
+                assert_eq!(item["kind"], json!("full"));
+                assert_eq!(item["items"][0]["range"]["start"]["character"], json!(3));
+                item["uri"].as_str()
+            })
+            .collect();
+        uris.sort_unstable();
+        let url = |file| Url::from_file_path(root.join(file)).expect("should convert path to URL");
+        assert_eq!(uris, vec![url("a.nu").as_str(), url("lib/b.nu").as_str()]);
+        assert_eq!(compiler.calls().len(), 2);
+
+        let previous: Vec<Value> = got["items"]
+            .as_array()
+            .expect("should have items")
+            .iter()
+            .map(|item| json!({ "uri": item["uri"], "value": item["resultId"] }))
+            .collect();
+        let got = client
+            .request(
+                "workspace/diagnostic",
+                json!({ "previousResultIds": previous }),
+            )
+            .await;
+
+        assert!(got["items"]
+            .as_array()
+            .expect("should have items")
+            .iter()
+            .all(|item| item["kind"] == json!("unchanged")));
+        assert_eq!(compiler.calls().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn completion_returns_nu_completions() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::Complete(0),
+                r#"{"completions":["where","which","while"]}"#,
+            );
+        let mut client = TestClient::start(compiler.clone());
+        client.initialize().await;
+        client.open("ls\nwh").await;
+
+        let got = client
+            .request("textDocument/completion", position(1, 2))
+            .await;
+
+        assert!(compiler.calls().contains(&IdeCommand::Complete(5)));
+        let labels: Vec<&Value> = got
+            .as_array()
+            .expect("completion should return an array")
+            .iter()
+            .map(|c| &c["label"])
+            .collect();
+        assert_eq!(labels, vec!["where", "which", "while"]);
+    }
+
+    #[tokio::test]
+    async fn completion_edits_replace_what_was_typed() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::Complete(0),
+                r#"{"completions":["--all","PATH","str join"]}"#,
+            )
+            .catalog(
+                &json!([{
+                    "name": "str join",
+                    "signatures": { "list<string>": [
+                        { "parameter_name": "separator", "parameter_type": "positional", "syntax_shape": "string" },
+                    ] },
+                }])
+                .to_string(),
+            );
+        let mut client = TestClient::start(compiler.clone());
+        client
+            .initialize_with(json!({
+                "capabilities": { "textDocument": {
+                    "completion": { "completionItem": { "snippetSupport": true } },
+                } },
+            }))
+            .await;
+        client.open("ls --al | get $env.PA | str jo").await;
+
+        let got = client
+            .request("textDocument/completion", position(0, 7))
+            .await;
+        assert_eq!(
+            got[0]["textEdit"],
+            json!({ "range": { "end": { "character": 7, "line": 0 }, "start": { "character": 3, "line": 0 } }, "newText": "--all" })
+        );
+
+        let got = client
+            .request("textDocument/completion", position(0, 21))
+            .await;
+        assert_eq!(
+            got[1]["textEdit"],
+            json!({ "range": { "end": { "character": 21, "line": 0 }, "start": { "character": 19, "line": 0 } }, "newText": "PATH" })
+        );
+
+        let got = client
+            .request("textDocument/completion", position(0, 30))
+            .await;
+        assert_eq!(
+            got[2]["textEdit"],
+            json!({ "range": { "end": { "character": 30, "line": 0 }, "start": { "character": 24, "line": 0 } }, "newText": "str join ${1:separator}" })
+        );
+        assert_eq!(got[2]["insertTextFormat"], json!(2));
+    }
+
+    #[tokio::test]
+    async fn completion_edits_replace_the_span_nu_reports() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::Complete(0),
+                r#"{"completions":[{"value":"all","span":{"start":5,"end":7}}]}"#,
+            );
+        let mut client = TestClient::start(compiler);
+        client.initialize().await;
+        client.open("ls --al").await;
+
+        let got = client
+            .request("textDocument/completion", position(0, 7))
+            .await;
+
+        assert_eq!(
+            got[0]["textEdit"],
+            json!({ "range": { "end": { "character": 7, "line": 0 }, "start": { "character": 5, "line": 0 } }, "newText": "all" })
+        );
+    }
+
+    #[tokio::test]
+    async fn completion_items_resolve_to_catalog_documentation() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::Complete(0),
+                r#"{"completions":["str join","rg"]}"#,
+            )
+            .catalog(
+                &json!([{
+                    "name": "str join",
+                    "usage": "Concatenate strings.",
+                    "signatures": { "list<string>": [
+                        { "parameter_name": "separator", "parameter_type": "positional", "syntax_shape": "string", "is_optional": true },
+                    ] },
+                    "examples": [{ "description": "Join", "example": "['a' 'b'] | str join" }],
+                }])
+                .to_string(),
+            );
+        let mut client = TestClient::start(compiler.clone());
+        client.initialize().await;
+        client.open("ls | str").await;
+
+        let got = client
+            .request("textDocument/completion", position(0, 8))
+            .await;
+
+        assert_eq!(
+            got[0],
+            json!({
+                "label": "str join",
+                "kind": 3,
+                "detail": "str join <separator?: string>",
+                "data": { "command": "str join", "uri": null },
+                "textEdit": { "range": { "end": { "character": 8, "line": 0 }, "start": { "character": 5, "line": 0 } }, "newText": "str join" },
+            })
+        );
+        assert_eq!(got[1]["kind"], json!(8));
+        assert_eq!(got[1]["detail"], json!("external"));
+
+        let got = client
+            .request("completionItem/resolve", got[0].clone())
+            .await;
+
+        assert_eq!(
+            got["documentation"],
+            json!({
+                "kind": "markdown",
+                "value": "Concatenate strings.\n\n### Examples\n\nJoin\n```nu\n['a' 'b'] | str join\n```",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn did_change_validates_each_document_once_after_edits_pause() {
+        let other = "file:///bar.nu";
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler.clone());
+        client.initialize().await;
+        client.open_at(URI, "l").await;
+        client.notification("textDocument/publishDiagnostics").await;
+        client.open_at(other, "p").await;
+        client.notification("textDocument/publishDiagnostics").await;
+
+        client.change_at(URI, 2, "ls").await;
+        client.change_at(other, 2, "pw").await;
+        client.change_at(URI, 3, "ls -").await;
+        client.change_at(URI, 4, "ls -a").await;
+        let mut got = [
+            client.notification("textDocument/publishDiagnostics").await,
+            client.notification("textDocument/publishDiagnostics").await,
+        ];
+        got.sort_by_key(|p| p["uri"].to_string());
+
+        assert_eq!(
+            got.iter()
+                .map(|p| (p["uri"].as_str(), p["version"].as_i64()))
+                .collect::<Vec<_>>(),
+            vec![(Some(other), Some(2)), (Some(URI), Some(4))]
+        );
+        assert_eq!(compiler.calls().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn diagnostics_for_an_outdated_version_are_dropped() {
+        let compiler = ScriptedCompiler::default()
+            .delay(Duration::from_millis(100))
+            .respond(
+                IdeCommand::Check,
+                r#"{"message":"Missing required positional argument.","severity":"Error","span":{"end":1,"start":0},"type":"diagnostic"}"#,
+            );
+        let mut client = TestClient::start(compiler.clone());
+        client
+            .initialize_with(json!({
+                "capabilities": { "textDocument": { "publishDiagnostics": {} } },
+                "initializationOptions": {
+                    "nushellLanguageServer": { "diagnosticsDebounceTime": 0 },
+                },
+            }))
+            .await;
+        client.open("l").await;
+        while !compiler.calls().contains(&IdeCommand::Check) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // while nu is still checking version 1
+        client.change_at(URI, 2, "ls").await;
+        let got = client.notification("textDocument/publishDiagnostics").await;
+
+        assert_eq!(got["version"], json!(2));
+        assert_eq!(compiler.texts(), vec!["l", "ls"]);
+    }
+
+    #[tokio::test]
+    async fn did_change_returns_before_edits_pause() {
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler);
+        client
+            .initialize_with(json!({
+                "capabilities": { "textDocument": { "diagnostic": {} } },
+                "initializationOptions": {
+                    "nushellLanguageServer": { "diagnosticsDebounceTime": 60000 },
+                },
+            }))
+            .await;
+        // more than the server handles at once, so waiting in any handler would stall the rest
+        let uris = (0..8)
+            .map(|i| format!("file:///{i}.nu"))
+            .collect::<Vec<_>>();
+        for uri in &uris {
+            client.open_at(uri, "l").await;
+            client.change_at(uri, 2, "ls").await;
+        }
+
+        let got = client
+            .request(
+                "textDocument/documentSymbol",
+                json!({ "textDocument": { "uri": uris[0] } }),
+            )
+            .await;
+
+        assert!(got.get("code").is_none(), "{got}");
+    }
+
+    #[tokio::test]
+    async fn goto_definition_returns_nu_location() {
+        let file = mktemp::Temp::new_file().expect("should create temporary file");
+        let path: &std::path::Path = file.as_ref();
+        std::fs::write(path, "# library\ndef bar [] {}\n").expect("should write temporary file");
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::GotoDef(0),
+                json!({ "end": 17, "file": path, "start": 14 }).to_string(),
+            );
+        let mut client = TestClient::start(compiler.clone());
+        client.initialize().await;
+        client.open("def foo [] {}\nfoo").await;
+
+        let got = client
+            .request("textDocument/definition", position(1, 1))
+            .await;
+
+        assert!(compiler.calls().contains(&IdeCommand::GotoDef(15)));
+        assert_eq!(
+            got["uri"],
+            json!(Url::from_file_path(path).expect("should convert path to URL"))
+        );
+        // "bar" on the second line of the other file, rather than "foo" in this document
+        assert_eq!(
+            got["range"],
+            json!({ "end": { "character": 7, "line": 1 }, "start": { "character": 4, "line": 1 } })
+        );
+
+        // `nu` reads the other file as saved, so its unsaved edits can't be taken into account
+        client
+            .open_at(
+                got["uri"].as_str().expect("should be a URI"),
+                "# unsaved\n# library\ndef bar [] {}\n",
+            )
+            .await;
+        let got = client
+            .request("textDocument/definition", position(1, 1))
+            .await;
+        assert_eq!(
+            got["range"],
+            json!({ "end": { "character": 7, "line": 1 }, "start": { "character": 4, "line": 1 } })
+        );
+    }
+
+    #[tokio::test]
+    async fn goto_definition_maps_source_file_to_document() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::GotoDef(0),
+                json!({ "end": 8, "file": ScriptedCompiler::source_path(), "start": 4 })
+                    .to_string(),
+            );
+        let mut client = TestClient::start(compiler);
+        client.initialize().await;
+        client.open("def foo [] {}\nfoo").await;
+
+        let got = client
+            .request("textDocument/definition", position(1, 1))
+            .await;
+
+        assert_eq!(got["uri"], json!(URI));
+        assert_eq!(
+            got["range"],
+            json!({ "end": { "character": 8, "line": 0 }, "start": { "character": 4, "line": 0 } })
+        );
+    }
+
+    #[tokio::test]
+    async fn goto_definition_ignores_prelude() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::GotoDef(0),
+                r#"{"end":0,"file":"__prelude__","start":0}"#,
+            );
+        let mut client = TestClient::start(compiler);
+        client.initialize().await;
+        client.open("ls").await;
+
+        let got = client
+            .request("textDocument/definition", position(0, 1))
+            .await;
+
+        assert_eq!(got, Value::Null);
+    }
+
+    #[tokio::test]
+    async fn hover_returns_nu_hover() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::Hover(0),
+                r#"{"hover":"List the filenames, sizes, and modification times of items in a directory.","span":{"end":2,"start":0}}"#,
+            );
+        let mut client = TestClient::start(compiler.clone());
+        client.initialize().await;
+        client.open("ls").await;
+
+        let got = client.request("textDocument/hover", position(0, 1)).await;
+
+        assert!(compiler.calls().contains(&IdeCommand::Hover(1)));
+        assert_eq!(
+            got,
+            json!({
+                "contents": "List the filenames, sizes, and modification times of items in a directory.",
+                "range": { "end": { "character": 2, "line": 0 }, "start": { "character": 0, "line": 0 } },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn signature_help_for_declared_command_needs_no_nu() {
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler.clone());
+        client.initialize().await;
+        client
+            .open("# Say hello\ndef greet [name: string, --loud (-l)] {}\ngreet --loud ")
+            .await;
+
+        let got = client
+            .request("textDocument/signatureHelp", position(2, 13))
+            .await;
+
+        assert!(!compiler
+            .calls()
+            .iter()
+            .any(|c| matches!(c, IdeCommand::Hover(_))));
+        assert_eq!(
+            got["signatures"][0]["label"],
+            json!("greet <name: string> --loud(-l)")
+        );
+        assert_eq!(
+            got["signatures"][0]["documentation"]["value"],
+            json!("Say hello")
+        );
+        assert_eq!(got["signatures"][0]["activeParameter"], json!(0));
+    }
+
+    #[tokio::test]
+    async fn signature_help_prefers_catalog_to_nu_hover() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .catalog(
+                &json!([{
+                    "name": "str join",
+                    "usage": "Concatenate strings.",
+                    "signatures": { "list<string>": [
+                        { "parameter_name": "separator", "parameter_type": "positional", "syntax_shape": "string", "is_optional": true },
+                        { "parameter_name": "max", "parameter_type": "named", "syntax_shape": "int", "short_flag": "m" },
+                    ] },
+                }])
+                .to_string(),
+            );
+        let mut client = TestClient::start(compiler.clone());
+        client.initialize().await;
+        client.open("ls | str join --max ").await;
+
+        let got = client
+            .request("textDocument/signatureHelp", position(0, 20))
+            .await;
+
+        assert!(!compiler
+            .calls()
+            .iter()
+            .any(|c| matches!(c, IdeCommand::Hover(_))));
+        assert_eq!(
+            got["signatures"][0]["label"],
+            json!("str join <separator?: string> --max(-m) <int>")
+        );
+        assert_eq!(got["signatures"][0]["activeParameter"], json!(1));
+
+        client.change_at(URI, 2, "ls | git log ").await;
+        let got = client
+            .request("textDocument/signatureHelp", position(0, 12))
+            .await;
+
+        assert_eq!(got, Value::Null);
+        assert!(!compiler
+            .calls()
+            .iter()
+            .any(|c| matches!(c, IdeCommand::Hover(_))));
+    }
+
+    #[tokio::test]
+    async fn signature_help_for_builtin_uses_nu_hover() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::Hover(0),
+                json!({
+                    "hover": "Concatenate strings.\n### Usage \n```\n  str join {flags} <separator?>\n```\n\n### Flags\n\n  `-h`, `--help` - Display the help message for this command\n\n### Parameters\n\n  `separator: string` - optional separator\n",
+                    "span": null,
+                })
+                .to_string(),
+            );
+        let mut client = TestClient::start(compiler.clone());
+        client.initialize().await;
+        client.open("ls | str join ").await;
+
+        let got = client
+            .request("textDocument/signatureHelp", position(0, 14))
+            .await;
+
+        assert!(compiler.calls().contains(&IdeCommand::Hover(5)));
+        assert_eq!(
+            got["signatures"][0]["label"],
+            json!("str join <separator?: string> --help(-h)")
+        );
+        assert_eq!(
+            got["signatures"][0]["parameters"][0],
+            json!({ "label": [9, 29], "documentation": "optional separator" })
+        );
+        assert_eq!(got["signatures"][0]["activeParameter"], json!(0));
+    }
+
+    #[tokio::test]
+    async fn hover_translates_non_ascii_offsets() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::Hover(0),
+                r#"{"hover":"string","span":{"end":18,"start":16}}"#,
+            );
+        let mut client = TestClient::start(compiler.clone());
+        client.initialize().await;
+        // the emoji is 4 bytes in UTF-8, but 2 code units in UTF-16
+        client.open("let s = '😀'; $s").await;
+
+        let got = client.request("textDocument/hover", position(0, 15)).await;
+
+        assert!(compiler.calls().contains(&IdeCommand::Hover(17)));
+        assert_eq!(
+            got["range"],
+            json!({ "end": { "character": 16, "line": 0 }, "start": { "character": 14, "line": 0 } })
+        );
+    }
+
+    #[tokio::test]
+    async fn document_symbol_lists_declarations() {
+        let compiler = ScriptedCompiler::default().respond(IdeCommand::Check, "");
+        let mut client = TestClient::start(compiler);
+        client.initialize().await;
+        client
+            .open("module ünï {\n  export def run [] { }\n}\nlet x = 1")
+            .await;
+
+        let got = client
+            .request(
+                "textDocument/documentSymbol",
+                json!({ "textDocument": { "uri": URI } }),
+            )
+            .await;
+
+        assert_eq!(got[0]["name"], json!("ünï"));
+        assert_eq!(got[0]["kind"], json!(2));
+        assert_eq!(
+            got[0]["range"],
+            json!({ "end": { "character": 1, "line": 2 }, "start": { "character": 0, "line": 0 } })
+        );
+        assert_eq!(
+            got[0]["selectionRange"],
+            json!({ "end": { "character": 10, "line": 0 }, "start": { "character": 7, "line": 0 } })
+        );
+        assert_eq!(got[0]["children"][0]["name"], json!("run"));
+        assert_eq!(got[0]["children"][0]["detail"], json!("export def"));
+        assert_eq!(got[1]["name"], json!("x"));
+        assert_eq!(got[1]["kind"], json!(13));
+    }
+
+    #[tokio::test]
+    async fn semantic_tokens_full_then_delta() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::Ast,
+                r#"[{"content":"ls","shape":"shape_internalcall","span":{"end":2,"start":0},"type":"ast"},{"content":"-a","shape":"shape_flag","span":{"end":5,"start":3},"type":"ast"},{"content":"$x","shape":"shape_variable","span":{"end":8,"start":6},"type":"ast"}]"#,
+            );
+        let mut client = TestClient::start(compiler);
+        let got = client.initialize().await;
+        assert_eq!(
+            got["capabilities"]["semanticTokensProvider"]["legend"]["tokenModifiers"],
+            json!(["declaration", "defaultLibrary"])
+        );
+        client.open("ls -a\n$x").await;
+        let document = json!({ "textDocument": { "uri": URI } });
+
+        let got = client
+            .request("textDocument/semanticTokens/full", document.clone())
+            .await;
+
+        // `ls` is a builtin, `-a` a parameter and `$x` a variable on the next line
+        assert_eq!(
+            got["data"],
+            json!([0, 0, 2, 0, 2, 0, 3, 2, 2, 0, 1, 0, 2, 1, 0])
+        );
+
+        let mut params = document.clone();
+        params["previousResultId"] = got["resultId"].clone();
+        let got = client
+            .request("textDocument/semanticTokens/full/delta", params)
+            .await;
+        assert_eq!(got["edits"], json!([]));
+
+        let mut params = document;
+        params["range"] =
+            json!({ "end": { "character": 2, "line": 1 }, "start": { "character": 0, "line": 1 } });
+        let got = client
+            .request("textDocument/semanticTokens/range", params)
+            .await;
+        assert_eq!(got["data"], json!([1, 0, 2, 1, 0]));
+    }
+
+    #[tokio::test]
+    async fn utf8_positions_when_client_prefers_them() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(
+                IdeCommand::Hover(0),
+                r#"{"hover":"string","span":{"end":18,"start":16}}"#,
+            );
+        let mut client = TestClient::start(compiler.clone());
+        let got = client
+            .initialize_with(json!({
+                "capabilities": { "general": { "positionEncodings": ["utf-8", "utf-16"] } },
+            }))
+            .await;
+        assert_eq!(got["capabilities"]["positionEncoding"], json!("utf-8"));
+
+        client.open("let s = '😀'; $x").await;
+        // byte offsets, which would be past the end of the line if read as UTF-16
+        client
+            .notify(
+                "textDocument/didChange",
+                json!({
+                    "contentChanges": [{
+                        "range": { "end": { "character": 18, "line": 0 }, "start": { "character": 17, "line": 0 } },
+                        "text": "s",
+                    }],
+                    "textDocument": { "uri": URI, "version": 2 },
+                }),
+            )
+            .await;
+        let got = client.request("textDocument/hover", position(0, 17)).await;
+
+        assert_eq!(
+            compiler.texts().last(),
+            Some(&String::from("let s = '😀'; $s"))
+        );
+        assert!(compiler.calls().contains(&IdeCommand::Hover(17)));
+        assert_eq!(
+            got["range"],
+            json!({ "end": { "character": 18, "line": 0 }, "start": { "character": 16, "line": 0 } })
+        );
+    }
+
+    #[tokio::test]
+    async fn hover_reports_unparseable_output() {
+        let compiler = ScriptedCompiler::default()
+            .respond(IdeCommand::Check, "")
+            .respond(IdeCommand::Hover(0), "not json");
+        let mut client = TestClient::start(compiler);
+        client.initialize().await;
+        client.open("ls").await;
+
+        let got = client.request("textDocument/hover", position(0, 1)).await;
+
+        assert_eq!(got["code"], json!(-32700));
+    }
+
+    #[tokio::test]
+    async fn hover_superseded_by_edit_is_content_modified() {
+        let compiler = ScriptedCompiler::default()
+            .delay(Duration::from_secs(30))
+            .respond(IdeCommand::Check, "")
+            .respond(IdeCommand::Hover(0), r#"{"hover":"stale","span":null}"#);
+        let mut client = TestClient::start(compiler.clone());
+        client.initialize().await;
+        client.open("ls").await;
+
+        let id = client
+            .start_request("textDocument/hover", position(0, 1))
+            .await;
+        while !compiler.calls().contains(&IdeCommand::Hover(1)) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        client.change_at(URI, 2, "ls -a").await;
+        let got = client.response(id).await;
+
+        assert_eq!(got["code"], json!(-32801));
+    }
+
+    #[tokio::test]
+    async fn shutdown_ok() {
+        let mut client = TestClient::start(ScriptedCompiler::default());
+        client.initialize().await;
+
+        let got = client.request("shutdown", Value::Null).await;
+
+        assert_eq!(got, Value::Null);
+    }
 }
@@ -1,17 +1,33 @@
-use std::collections::HashMap;
-use std::sync::OnceLock;
-use std::time::{Duration, Instant};
-use std::{ffi::OsStr, sync::RwLock};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
 
+mod completion;
+mod dependencies;
 pub(crate) mod language_server;
+mod references;
+mod rename;
+mod semantic_tokens;
+mod signature_help;
+mod symbol_index;
+mod workspace;
 use crate::nu::{IdeCheckHint, IdeCheckResponse};
 use crate::{
-    error::map_err_to_internal_error,
-    nu::{run_compiler, IdeCheckDiagnostic, IdeSettings},
+    error::{content_modified, map_err_to_internal_error},
+    nu::{
+        capabilities::NuCapabilities, catalog::Catalog, Compiler, CompilerResponse,
+        IdeCheckDiagnostic, IdeCommand, IdeSettings,
+    },
+    offsets::{Encoding, LineIndex},
 };
 use lsp_textdocument::{FullTextDocument, TextDocuments};
 
 use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
+use tower_lsp::jsonrpc::ErrorCode;
 use tower_lsp::lsp_types::notification::{
     DidChangeTextDocument, DidCloseTextDocument, Notification,
 };
@@ -20,19 +36,134 @@ use tower_lsp::lsp_types::*;
 use tower_lsp::Client;
 use tower_lsp::{jsonrpc::Result, lsp_types::notification::DidOpenTextDocument};
 
-pub(crate) struct Backend {
+/// The language server, which clones cheaply (sharing its state),
+/// so that handlers can hand long-running work off to a task of its own.
+#[derive(Clone)]
+pub(crate) struct Backend(Arc<State>);
+
+impl std::ops::Deref for Backend {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        &self.0
+    }
+}
+
+pub(crate) struct State {
+    // cancelled (and replaced) whenever a document changes or closes, to stop work on stale text
+    cancellation_tokens: RwLock<HashMap<Url, CancellationToken>>,
     can_change_configuration: OnceLock<bool>,
+    can_insert_snippets: OnceLock<bool>,
     can_lookup_configuration: OnceLock<bool>,
     can_publish_diagnostics: OnceLock<bool>,
+    can_pull_diagnostics: OnceLock<bool>,
+    can_refresh_diagnostics: OnceLock<bool>,
+    can_report_progress: OnceLock<bool>,
+    can_watch_files: OnceLock<bool>,
+    // what the globally-configured `nu` knows about its commands, loaded on first use
+    catalog: tokio::sync::OnceCell<Catalog>,
     client: Client,
+    compiler: Box<dyn Compiler>,
+    // why the compiler asked for on the command line isn't the one in use, told to the client once it's listening
+    compiler_fallback: Option<String>,
+    // the files each checked document imports, including those that don't exist (yet)
+    dependencies: RwLock<HashMap<Url, HashSet<Url>>>,
     documents: RwLock<TextDocuments>,
+    document_diagnostics: RwLock<HashMap<Url, DiagnosticsReport>>,
     document_inlay_hints: RwLock<HashMap<Url, Vec<InlayHint>>>,
+    // the last tokens sent for each document, which the next delta is relative to
+    document_semantic_tokens: RwLock<HashMap<Url, SemanticTokens>>,
     document_settings: RwLock<HashMap<Url, IdeSettings>>,
     global_settings: RwLock<IdeSettings>,
-    last_validated: RwLock<Instant>,
+    // distinguishes each set of diagnostics handed to a pulling client
+    next_result_id: AtomicUsize,
+    nu_capabilities: OnceLock<NuCapabilities>,
+    position_encoding: OnceLock<Encoding>,
+    // the declarations in each file, for `workspace/symbol`
+    symbol_index: RwLock<HashMap<Url, Vec<SymbolInformation>>>,
+    // whether every file in the workspace folders has been indexed, rather than just those opened
+    symbol_index_ready: AtomicBool,
+    workspace_folders: RwLock<Vec<WorkspaceFolder>>,
 }
 
 impl Backend {
+    fn cancel_in_flight(&self, uri: &Url) -> Result<()> {
+        let mut tokens = self.cancellation_tokens.write().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot write cancellation tokens: {e:?}"))
+        })?;
+        if let Some(token) = tokens.remove(uri) {
+            token.cancel();
+        }
+        Ok(())
+    }
+
+    /// A token cancelled by the next edit to `uri` (or its closing).
+    ///
+    /// Files that aren't open can't be edited, so they get a token that is never cancelled,
+    /// rather than an entry that would outlive the check (as nothing closes them).
+    fn cancellation_token(&self, uri: &Url) -> Result<CancellationToken> {
+        if !self.is_open(uri)? {
+            return Ok(CancellationToken::new());
+        }
+        let mut tokens = self.cancellation_tokens.write().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot write cancellation tokens: {e:?}"))
+        })?;
+        Ok(tokens.entry(uri.clone()).or_default().child_token())
+    }
+
+    fn is_open(&self, uri: &Url) -> Result<bool> {
+        let documents = self.documents.read().map_err(|e| {
+            tower_lsp::jsonrpc::Error::invalid_params(format!(
+                "cannot read from document cache: {e:?}"
+            ))
+        })?;
+        Ok(documents.get_document(uri).is_some())
+    }
+
+    /// Starts a timer that validates `uri` and whatever imports it once edits to it pause,
+    /// without holding up the `didChange` notification that started it.
+    ///
+    /// Each edit cancels the previous timer for the same document,
+    /// so only the last edit in a burst is validated, and edits to other documents don't interfere.
+    fn spawn_debounced_validation(&self, uri: Url) -> Result<()> {
+        // taken now, so that the next edit cancels this one even if the timer hasn't started yet
+        let cancel = self.cancellation_token(&uri)?;
+        let backend = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = backend.debounced_validate_document(&uri, &cancel).await {
+                backend
+                    .client
+                    .log_message(MessageType::ERROR, format!("{e:?}"))
+                    .await;
+            }
+        });
+        Ok(())
+    }
+
+    async fn debounced_validate_document(
+        &self,
+        uri: &Url,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let delay = self
+            .get_document_settings(uri)
+            .await?
+            .diagnostics_debounce_time;
+
+        tokio::select! {
+            () = cancel.cancelled() => return Ok(()),
+            () = tokio::time::sleep(delay) => {},
+        }
+
+        self.validate_document(uri).await?;
+        self.revalidate_dependents(uri).await
+    }
+
+    /// What the `character` of positions to and from the client counts, as agreed during `initialize`.
+    fn encoding(&self) -> Encoding {
+        self.position_encoding.get().copied().unwrap_or_default()
+    }
+
     fn for_document<T>(&self, uri: &Url, f: &dyn Fn(&FullTextDocument) -> T) -> Result<T> {
         let documents = self.documents.read().map_err(|e| {
             tower_lsp::jsonrpc::Error::invalid_params(format!(
@@ -48,7 +179,40 @@ impl Backend {
         Ok(f(doc))
     }
 
+    /// Settings for `uri`, as configured for the workspace folder it is in (if any).
+    ///
+    /// Relative `include_dirs` are resolved against that folder,
+    /// and every workspace folder is searched for modules.
     async fn get_document_settings(&self, uri: &Url) -> Result<IdeSettings> {
+        let folders = self.read_workspace_folders()?;
+        let path = uri.to_file_path().ok();
+        let containing = folders
+            .iter()
+            .filter_map(|f| f.uri.to_file_path().ok().map(|root| (f, root)))
+            .filter(|(_, root)| path.as_ref().is_some_and(|p| p.starts_with(root)))
+            // the innermost, for nested folders
+            .max_by_key(|(_, root)| root.components().count());
+
+        let scope = containing.as_ref().map_or(uri, |(f, _)| &f.uri);
+        let mut settings = self.get_scoped_settings(scope).await?;
+        if let Some((_, root)) = &containing {
+            settings.include_dirs = settings.include_dirs.iter().map(|d| root.join(d)).collect();
+        }
+        settings.workspace_folders = containing
+            .map(|(_, root)| root)
+            .into_iter()
+            .chain(folders.iter().filter_map(|f| f.uri.to_file_path().ok()))
+            .fold(vec![], |mut roots, root| {
+                if !roots.contains(&root) {
+                    roots.push(root);
+                }
+                roots
+            });
+        Ok(settings)
+    }
+
+    /// Settings for a document or workspace folder, cached until the configuration changes.
+    async fn get_scoped_settings(&self, uri: &Url) -> Result<IdeSettings> {
         if !self.can_lookup_configuration.get().unwrap_or(&false) {
             self.client
                 .log_message(
@@ -107,45 +271,284 @@ impl Backend {
         Ok(IdeSettings::default())
     }
 
-    pub fn new(client: Client) -> Self {
-        Self {
+    pub fn new(
+        client: Client,
+        compiler: Box<dyn Compiler>,
+        compiler_fallback: Option<String>,
+    ) -> Self {
+        Self(Arc::new(State {
+            cancellation_tokens: RwLock::new(HashMap::new()),
             can_change_configuration: OnceLock::new(),
+            can_insert_snippets: OnceLock::new(),
             can_lookup_configuration: OnceLock::new(),
             can_publish_diagnostics: OnceLock::new(),
+            can_pull_diagnostics: OnceLock::new(),
+            can_refresh_diagnostics: OnceLock::new(),
+            can_report_progress: OnceLock::new(),
+            can_watch_files: OnceLock::new(),
+            catalog: tokio::sync::OnceCell::new(),
             client,
+            compiler,
+            compiler_fallback,
+            dependencies: RwLock::new(HashMap::new()),
             documents: RwLock::new(TextDocuments::new()),
+            document_diagnostics: RwLock::new(HashMap::new()),
             document_inlay_hints: RwLock::new(HashMap::new()),
+            document_semantic_tokens: RwLock::new(HashMap::new()),
             document_settings: RwLock::new(HashMap::new()),
             global_settings: RwLock::new(IdeSettings::default()),
-            last_validated: RwLock::new(Instant::now()),
+            next_result_id: AtomicUsize::new(0),
+            nu_capabilities: OnceLock::new(),
+            position_encoding: OnceLock::new(),
+            symbol_index: RwLock::new(HashMap::new()),
+            symbol_index_ready: AtomicBool::new(false),
+            workspace_folders: RwLock::new(vec![]),
+        }))
+    }
+
+    /// Asks the globally-configured `nu` what it supports, assuming everything if that fails.
+    async fn probe_nu_capabilities(&self) -> Result<NuCapabilities> {
+        let settings = self.read_global_settings()?;
+        let capabilities = self
+            .compiler
+            .capabilities(&settings)
+            .await
+            .unwrap_or_else(|_| NuCapabilities::all());
+        Ok(self.nu_capabilities.get_or_init(|| capabilities).clone())
+    }
+
+    /// Asks the globally-configured `nu` about its commands the first time this is called,
+    /// leaving the catalog empty if that fails.
+    pub(super) async fn catalog(&self) -> &Catalog {
+        self.catalog
+            .get_or_init(|| async {
+                let loaded = match self.read_global_settings() {
+                    Ok(settings) => self.compiler.catalog(&settings).await,
+                    Err(e) => Err(e),
+                };
+                match loaded {
+                    Ok(catalog) => catalog,
+                    Err(e) => {
+                        self.client
+                            .log_message(MessageType::ERROR, format!("{e:?}"))
+                            .await;
+                        Catalog::default()
+                    }
+                }
+            })
+            .await
+    }
+
+    fn read_global_settings(&self) -> Result<IdeSettings> {
+        let global_settings = self.global_settings.read().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot read global settings: {e:?}"))
+        })?;
+        Ok(global_settings.clone())
+    }
+
+    fn read_workspace_folders(&self) -> Result<Vec<WorkspaceFolder>> {
+        let folders = self.workspace_folders.read().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot read workspace folders: {e:?}"))
+        })?;
+        Ok(folders.clone())
+    }
+
+    /// The text of `uri` and its revision, preferring an open document over what is saved on disk.
+    async fn read_document(&self, uri: &Url) -> Result<(String, Revision)> {
+        if self.is_open(uri)? {
+            return self.for_document(uri, &|doc| {
+                (
+                    String::from(doc.get_content(None)),
+                    Revision::Open(doc.version()),
+                )
+            });
         }
+        let path = saved_path(uri)?;
+        let revision = self.revision(uri).await?;
+        let text = tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| map_err_to_internal_error(e, format!("cannot read {}", path.display())))?;
+        Ok((text, revision))
     }
 
-    async fn throttled_validate_document(&self, uri: &Url) -> Result<()> {
-        // TODO: this is a quick imperfect hack, but eventually we probably want a thorough solution using threads/channels?
-        // TODO: ensure that we validate at least once after the most recent throttling (i.e. debounce instead of throttle)
-        let then = {
-            *self.last_validated.read().map_err(|e| {
-                map_err_to_internal_error(&e, format!("cannot read throttling marker: {e:?}"))
-            })?
-        };
-        if then.elapsed() < Duration::from_millis(500) {
-            return Ok(());
+    async fn revision(&self, uri: &Url) -> Result<Revision> {
+        if self.is_open(uri)? {
+            return self.for_document(uri, &|doc| Revision::Open(doc.version()));
         }
+        let path = saved_path(uri)?;
+        let modified = tokio::fs::metadata(&path)
+            .await
+            .and_then(|m| m.modified())
+            .map_err(|e| {
+                map_err_to_internal_error(e, format!("cannot read metadata for {}", path.display()))
+            })?;
+        Ok(Revision::Saved(modified))
+    }
 
-        self.validate_document(uri).await?;
+    /// Runs `nu` for `uri`, failing with `ContentModified` if the document changes or closes before it finishes.
+    async fn run_compiler(
+        &self,
+        text: &str,
+        command: IdeCommand,
+        settings: IdeSettings,
+        uri: &Url,
+    ) -> Result<CompilerResponse> {
+        let cancel = self.cancellation_token(uri)?;
+        let output = self
+            .compiler
+            .run(text, command, settings, uri, &cancel)
+            .await;
+        if cancel.is_cancelled() {
+            return Err(content_modified(format!(
+                "{uri} changed while `nu {}` was running",
+                command.flag()
+            )));
+        }
+        output
+    }
+
+    /// Remembers what the client told `initialize` it supports.
+    fn set_client_capabilities(&self, capabilities: &ClientCapabilities) {
+        // panic: this is the only place we `OnceLock::set` these,
+        // so we've entered strange territory if something else writes to them first
+        self.can_change_configuration
+            .set(matches!(
+                capabilities.workspace,
+                Some(WorkspaceClientCapabilities {
+                    did_change_configuration: Some(_),
+                    ..
+                })
+            ))
+            .expect("server value initialized out of sequence");
+
+        self.can_insert_snippets
+            .set(matches!(
+                capabilities.text_document,
+                Some(TextDocumentClientCapabilities {
+                    completion: Some(CompletionClientCapabilities {
+                        completion_item: Some(CompletionItemCapability {
+                            snippet_support: Some(true),
+                            ..
+                        }),
+                        ..
+                    }),
+                    ..
+                })
+            ))
+            .expect("server value initialized out of sequence");
+
+        self.can_lookup_configuration
+            .set(matches!(
+                capabilities.workspace,
+                Some(WorkspaceClientCapabilities {
+                    configuration: Some(_),
+                    ..
+                })
+            ))
+            .expect("server value initialized out of sequence");
+
+        self.can_publish_diagnostics
+            .set(matches!(
+                capabilities.text_document,
+                Some(TextDocumentClientCapabilities {
+                    publish_diagnostics: Some(_),
+                    ..
+                })
+            ))
+            .expect("server value initialized out of sequence");
+
+        // preferred over publishing, when the client supports it
+        self.can_pull_diagnostics
+            .set(matches!(
+                capabilities.text_document,
+                Some(TextDocumentClientCapabilities {
+                    diagnostic: Some(_),
+                    ..
+                })
+            ))
+            .expect("server value initialized out of sequence");
+
+        self.can_refresh_diagnostics
+            .set(matches!(
+                capabilities.workspace,
+                Some(WorkspaceClientCapabilities {
+                    diagnostic: Some(DiagnosticWorkspaceClientCapabilities {
+                        refresh_support: Some(true)
+                    }),
+                    ..
+                })
+            ))
+            .expect("server value initialized out of sequence");
+
+        self.can_report_progress
+            .set(matches!(
+                capabilities.window,
+                Some(WindowClientCapabilities {
+                    work_done_progress: Some(true),
+                    ..
+                })
+            ))
+            .expect("server value initialized out of sequence");
 
-        let mut then = self.last_validated.write().map_err(|e| {
-            map_err_to_internal_error(&e, format!("cannot write throttling marker: {e:?}"))
+        self.can_watch_files
+            .set(matches!(
+                capabilities.workspace,
+                Some(WorkspaceClientCapabilities {
+                    did_change_watched_files: Some(DidChangeWatchedFilesClientCapabilities {
+                        dynamic_registration: Some(true),
+                        ..
+                    }),
+                    ..
+                })
+            ))
+            .expect("server value initialized out of sequence");
+
+        let encoding = Encoding::negotiate(
+            capabilities
+                .general
+                .as_ref()
+                .and_then(|general| general.position_encodings.as_deref()),
+        );
+        self.position_encoding
+            .set(encoding)
+            .expect("server value initialized out of sequence");
+    }
+
+    fn try_did_change_workspace_folders(&self, event: WorkspaceFoldersChangeEvent) -> Result<()> {
+        let mut folders = self.workspace_folders.write().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot write workspace folders: {e:?}"))
         })?;
-        *then = Instant::now();
+        folders.retain(|f| !event.removed.contains(f));
+        folders.extend(event.added);
         Ok(())
     }
 
-    fn try_did_change(&self, params: DidChangeTextDocumentParams) -> Result<()> {
+    fn try_did_change(&self, mut params: DidChangeTextDocumentParams) -> Result<()> {
         let mut documents = self.documents.write().map_err(|e| {
             map_err_to_internal_error(&e, format!("cannot write to document cache: {e:?}"))
         })?;
+        // lsp-textdocument only understands UTF-16 ranges,
+        // so apply the edits here (in order, as each range refers to the text after the previous edit),
+        // then hand over the resulting text in full
+        if let Some(doc) = documents.get_document(&params.text_document.uri) {
+            let mut text = String::from(doc.get_content(None));
+            for change in params.content_changes.drain(..) {
+                match change.range {
+                    Some(range) => {
+                        let index = LineIndex::new(&text, self.encoding());
+                        let start = index.offset(range.start) as usize;
+                        let end = (index.offset(range.end) as usize).max(start);
+                        text.replace_range(start..end, &change.text);
+                    }
+                    None => text = change.text,
+                }
+            }
+            params.content_changes = vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text,
+            }];
+        }
         let params = serde_json::to_value(params).map_err(|e| {
             tower_lsp::jsonrpc::Error::invalid_params(format!(
                 "cannot convert client parameters: {e:?}"
@@ -182,14 +585,44 @@ impl Backend {
             })?;
             documents.documents().keys().cloned().collect()
         };
+        {
+            // checked with the old settings
+            let mut document_diagnostics = self.document_diagnostics.write().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot write diagnostics cache: {e:?}"))
+            })?;
+            document_diagnostics.clear();
+        }
         for uri in uris {
             self.validate_document(&uri).await?;
         }
+        // pulling clients don't know the results changed unless we tell them
+        if *self.can_pull_diagnostics.get().unwrap_or(&false)
+            && *self.can_refresh_diagnostics.get().unwrap_or(&false)
+        {
+            self.client.workspace_diagnostic_refresh().await?;
+        }
 
         Ok(())
     }
 
     fn try_did_close(&self, params: DidCloseTextDocumentParams) -> Result<()> {
+        self.forget_dependencies(&params.text_document.uri)?;
+        {
+            let mut document_diagnostics = self.document_diagnostics.write().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot write diagnostics cache: {e:?}"))
+            })?;
+            document_diagnostics.remove(&params.text_document.uri);
+        }
+        {
+            let mut document_semantic_tokens =
+                self.document_semantic_tokens.write().map_err(|e| {
+                    map_err_to_internal_error(
+                        &e,
+                        format!("cannot write semantic tokens cache: {e:?}"),
+                    )
+                })?;
+            document_semantic_tokens.remove(&params.text_document.uri);
+        }
         let mut documents = self.documents.write().map_err(|e| {
             map_err_to_internal_error(&e, format!("cannot write to document cache: {e:?}"))
         })?;
@@ -215,9 +648,140 @@ impl Backend {
         Ok(())
     }
 
+    /// Runs `nu --ide-check` for `uri` and caches the results,
+    /// or `None` if the document changed before they were ready.
+    async fn check_document(&self, uri: &Url) -> Result<Option<DiagnosticsReport>> {
+        // spans from nu only line up with the exact text it checked
+        let (text, revision) = self.read_document(uri).await?;
+
+        let ide_settings = self.get_document_settings(uri).await?;
+        self.record_dependencies(uri, &text, &ide_settings)?;
+        let show_inferred_types = ide_settings.hints.show_inferred_types;
+        let (ide_checks, failure) = match self
+            .run_compiler(&text, IdeCommand::Check, ide_settings, uri)
+            .await
+        {
+            Ok(output) => (
+                IdeCheckResponse::from_compiler_response(&output),
+                output
+                    .failure()
+                    .map(|f| format!("`nu {}` {f}", IdeCommand::Check.flag())),
+            ),
+            // superseded by a newer edit, which will be checked in turn
+            Err(e) if e.code == ErrorCode::ContentModified => return Ok(None),
+            // e.g. a timeout, or a `nu` that could not be spawned
+            Err(e) => {
+                self.client
+                    .log_message(MessageType::ERROR, format!("{e:?}"))
+                    .await;
+                (IdeCheckResponse::default(), Some(e.message.into_owned()))
+            }
+        };
+
+        if self.revision(uri).await? != revision {
+            // the document changed while nu was running, a newer check will replace these
+            self.client
+                .log_message(
+                    MessageType::INFO,
+                    format!("discarding diagnostics for outdated {revision:?} of {uri}"),
+                )
+                .await;
+            return Ok(None);
+        }
+
+        // spans are byte offsets into exactly the text that nu checked
+        let index = LineIndex::new(&text, self.encoding());
+        let diagnostics = ide_checks
+            .diagnostics
+            .iter()
+            .map(|d| IdeCheckDiagnostic::to_diagnostic(d, &index, uri))
+            .chain(failure.map(checker_failure))
+            .collect::<Vec<_>>();
+        let inlay_hints = ide_checks
+            .inlay_hints
+            .iter()
+            .map(|d| IdeCheckHint::to_inlay_hint(d, &index))
+            .collect::<Vec<_>>();
+
+        if show_inferred_types && matches!(revision, Revision::Open(_)) {
+            let mut documents = self.document_inlay_hints.write().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot write inlay hints cache: {e:?}"))
+            })?;
+            documents.insert(uri.clone(), inlay_hints);
+        }
+
+        let report = DiagnosticsReport {
+            items: diagnostics,
+            result_id: self
+                .next_result_id
+                .fetch_add(1, Ordering::Relaxed)
+                .to_string(),
+            revision,
+        };
+        let mut document_diagnostics = self.document_diagnostics.write().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot write diagnostics cache: {e:?}"))
+        })?;
+        document_diagnostics.insert(uri.clone(), report.clone());
+        Ok(Some(report))
+    }
+
+    /// The diagnostics for the current revision of `uri`, only running `nu` if it changed since last time.
+    async fn current_diagnostics(&self, uri: &Url) -> Result<Option<DiagnosticsReport>> {
+        let revision = self.revision(uri).await?;
+        let cached = {
+            let document_diagnostics = self.document_diagnostics.read().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot read diagnostics cache: {e:?}"))
+            })?;
+            document_diagnostics
+                .get(uri)
+                .filter(|report| report.revision == revision)
+                .cloned()
+        };
+        match cached {
+            Some(report) => Ok(Some(report)),
+            None => self.check_document(uri).await,
+        }
+    }
+
+    /// Answers `textDocument/diagnostic`,
+    /// reusing the results for this revision of the document if it has already been checked.
+    async fn pull_diagnostics(
+        &self,
+        uri: &Url,
+        previous_result_id: Option<&str>,
+    ) -> Result<DocumentDiagnosticReport> {
+        let report = self
+            .current_diagnostics(uri)
+            .await?
+            .ok_or_else(|| content_modified(format!("{uri} changed while it was being checked")))?;
+
+        if previous_result_id == Some(report.result_id.as_str()) {
+            return Ok(DocumentDiagnosticReport::Unchanged(
+                RelatedUnchangedDocumentDiagnosticReport {
+                    related_documents: None,
+                    unchanged_document_diagnostic_report: UnchangedDocumentDiagnosticReport {
+                        result_id: report.result_id,
+                    },
+                },
+            ));
+        }
+        Ok(DocumentDiagnosticReport::Full(
+            RelatedFullDocumentDiagnosticReport {
+                related_documents: None,
+                full_document_diagnostic_report: FullDocumentDiagnosticReport {
+                    result_id: Some(report.result_id),
+                    items: report.items,
+                },
+            },
+        ))
+    }
+
+    /// Publishes the diagnostics for the current revision of `uri` (unless the client pulls them),
+    /// checking it only if it hasn't been already.
     async fn validate_document(&self, uri: &Url) -> Result<()> {
-        let can_publish_diagnostics = self.can_publish_diagnostics.get().unwrap_or(&false);
-        if !can_publish_diagnostics {
+        let can_publish_diagnostics = *self.can_publish_diagnostics.get().unwrap_or(&false);
+        let can_pull_diagnostics = *self.can_pull_diagnostics.get().unwrap_or(&false);
+        if !can_publish_diagnostics && !can_pull_diagnostics {
             self.client
                 .log_message(
                     MessageType::INFO,
@@ -227,51 +791,94 @@ impl Backend {
             return Ok(());
         }
 
-        let text = self.for_document(uri, &|doc| String::from(doc.get_content(None)))?;
+        let Some(report) = self.current_diagnostics(uri).await? else {
+            return Ok(());
+        };
 
-        let ide_settings = self.get_document_settings(uri).await?;
-        let show_inferred_types = ide_settings.hints.show_inferred_types;
-        let output =
-            run_compiler(&text, vec![OsStr::new("--ide-check")], ide_settings, uri).await?;
-
-        let ide_checks = IdeCheckResponse::from_compiler_response(&output);
-
-        let (diagnostics, version) = self.for_document(uri, &|doc| {
-            (
-                ide_checks
-                    .diagnostics
-                    .iter()
-                    .map(|d| IdeCheckDiagnostic::to_diagnostic(d, doc, uri))
-                    .collect::<Vec<_>>(),
-                doc.version(),
-            )
-        })?;
+        // otherwise the results wait in the cache for the client to pull them
+        if !can_pull_diagnostics {
+            self.client
+                .publish_diagnostics(uri.clone(), report.items, report.revision.version())
+                .await;
+        }
 
-        self.client
-            .publish_diagnostics(uri.clone(), diagnostics, Some(version))
-            .await;
+        Ok(())
+    }
+}
 
-        if show_inferred_types {
-            let inlay_hints = self.for_document(uri, &|doc| {
-                ide_checks
-                    .inlay_hints
-                    .iter()
-                    .map(|d| IdeCheckHint::to_inlay_hint(d, doc))
-                    .collect::<Vec<_>>()
-            })?;
+/// The outcome of checking one revision of a document.
+#[derive(Clone)]
+struct DiagnosticsReport {
+    items: Vec<Diagnostic>,
+    result_id: String,
+    revision: Revision,
+}
 
-            let mut documents = self.document_inlay_hints.write().map_err(|e| {
-                map_err_to_internal_error(&e, format!("cannot write inlay hints cache: {e:?}"))
-            })?;
-            documents.insert(uri.clone(), inlay_hints);
+/// Identifies the text of a document that was checked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Revision {
+    /// a version of a document open in the editor
+    Open(i32),
+    /// a file that isn't open, as it was saved at this time
+    Saved(SystemTime),
+}
+impl Revision {
+    fn version(self) -> Option<i32> {
+        match self {
+            Self::Open(version) => Some(version),
+            Self::Saved(_) => None,
         }
+    }
+}
 
-        Ok(())
+fn saved_path(uri: &Url) -> Result<PathBuf> {
+    uri.to_file_path().map_err(|()| {
+        tower_lsp::jsonrpc::Error::invalid_params(format!("{uri} is neither open nor a file"))
+    })
+}
+
+/// Reported at the top of the document when nu couldn't check it at all,
+/// so that an empty list of problems isn't mistaken for a clean bill of health.
+fn checker_failure(message: String) -> Diagnostic {
+    Diagnostic {
+        message,
+        range: Range::default(),
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some(String::from("nuls")),
+        ..Diagnostic::default()
     }
 }
 
 #[derive(Default, Deserialize)]
 #[serde(default, rename_all = "camelCase")]
-struct ClientSettingsPayload {
+pub(crate) struct ClientSettingsPayload {
     nushell_language_server: IdeSettings,
 }
+
+#[cfg(test)]
+mod tests {
+    use tower_lsp::LspService;
+
+    use super::*;
+    use crate::nu::fake::ScriptedCompiler;
+
+    #[test]
+    fn cancellation_tokens_are_only_kept_for_open_documents() {
+        let (service, _) = LspService::new(|client| {
+            Backend::new(client, Box::new(ScriptedCompiler::default()), None)
+        });
+        let backend = service.inner();
+        let uri = Url::parse("file:///project/lib.nu").expect("should parse URL");
+
+        let token = backend
+            .cancellation_token(&uri)
+            .expect("should create token");
+
+        assert!(!token.is_cancelled());
+        assert!(backend
+            .cancellation_tokens
+            .read()
+            .expect("should read tokens")
+            .is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet};
+
+use tower_lsp::jsonrpc::Result;
+#[allow(clippy::wildcard_imports)]
+use tower_lsp::lsp_types::*;
+
+use super::{
+    dependencies::{dependents, imported_files},
+    Backend,
+};
+use crate::{
+    error::map_err_to_internal_error,
+    offsets::LineIndex,
+    syntax::{self, Reference, Symbol},
+};
+
+/// The mentions of a name in one file.
+pub(super) struct FileReferences {
+    pub uri: Url,
+    pub text: String,
+    pub references: Vec<Reference>,
+}
+
+impl Backend {
+    /// Answers `textDocument/references` for the variable or command at `position`.
+    pub(super) async fn references(
+        &self,
+        uri: &Url,
+        position: Position,
+        include_declaration: bool,
+    ) -> Result<Option<Vec<Location>>> {
+        let Some((_, found)) = self.find_references(uri, position).await? else {
+            return Ok(None);
+        };
+
+        let mut locations = vec![];
+        for FileReferences {
+            uri,
+            text,
+            references,
+        } in found
+        {
+            let index = LineIndex::new(&text, self.encoding());
+            let offset = |o: usize| u32::try_from(o).unwrap_or(u32::MAX);
+            locations.extend(
+                references
+                    .into_iter()
+                    .filter(|r| include_declaration || !r.declaration)
+                    .map(|r| Location {
+                        uri: uri.clone(),
+                        range: index.range(offset(r.start), offset(r.end)),
+                    }),
+            );
+        }
+        Ok(Some(locations))
+    }
+
+    /// The variable or command at `position`, and everywhere it is declared and used.
+    ///
+    /// Variables are only looked for in the document itself, within the block that declares them.
+    /// Commands (and modules) are looked for in every file that can see them,
+    /// i.e. the files declaring them and those that (indirectly) import those.
+    pub(super) async fn find_references(
+        &self,
+        uri: &Url,
+        position: Position,
+    ) -> Result<Option<(Symbol, Vec<FileReferences>)>> {
+        let text = self.for_document(uri, &|doc| String::from(doc.get_content(None)))?;
+        let offset = LineIndex::new(&text, self.encoding()).offset(position) as usize;
+
+        let Some(symbol) = syntax::symbol_at(&text, offset) else {
+            return Ok(None);
+        };
+        let found = match &symbol {
+            Symbol::Variable(name) => {
+                let references = syntax::variable_references(&text, name, offset);
+                vec![FileReferences {
+                    uri: uri.clone(),
+                    text,
+                    references,
+                }]
+            }
+            Symbol::Command { words, cursor } => {
+                let Some(found) = self.command_references(uri, words, *cursor).await? else {
+                    return Ok(None);
+                };
+                found
+            }
+        };
+        Ok(Some((symbol, found)))
+    }
+
+    /// Finds the command named by (some of) `words` in `uri`,
+    /// then its references in each file that can see it.
+    async fn command_references(
+        &self,
+        uri: &Url,
+        words: &[String],
+        cursor: usize,
+    ) -> Result<Option<Vec<FileReferences>>> {
+        self.ensure_symbol_index().await?;
+        let commands: Vec<(Url, SymbolInformation)> = {
+            let symbol_index = self.symbol_index.read().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot read symbol index: {e:?}"))
+            })?;
+            symbol_index
+                .iter()
+                .flat_map(|(uri, symbols)| symbols.iter().map(move |s| (uri.clone(), s.clone())))
+                .filter(|(_, s)| {
+                    matches!(
+                        s.kind,
+                        SymbolKind::FUNCTION | SymbolKind::INTERFACE | SymbolKind::MODULE
+                    )
+                })
+                .collect()
+        };
+        let known: HashSet<&str> = commands.iter().map(|(_, s)| s.name.as_str()).collect();
+
+        // the longest run of words, including the one under the cursor, that names a known command,
+        // possibly after the name of the module it was imported with
+        let name = (0..=cursor.min(1))
+            .flat_map(|skip| {
+                (cursor + 1..=words.len())
+                    .rev()
+                    .map(move |end| words[skip..end].join(" "))
+            })
+            .find(|name| known.contains(name.as_str()))
+            // e.g. a command from a file outside the workspace
+            .or_else(|| words.first().filter(|_| cursor == 0).cloned());
+        let Some(name) = name else {
+            return Ok(None);
+        };
+
+        let texts = self.indexed_texts().await?;
+        let mut imports = HashMap::new();
+        for (uri, text) in &texts {
+            let settings = self.get_document_settings(uri).await?;
+            imports.insert(uri.clone(), imported_files(uri, text, &settings)?);
+        }
+
+        let mut declared_in: Vec<&Url> = commands
+            .iter()
+            .filter(|(_, s)| s.name == name)
+            .map(|(uri, _)| uri)
+            .collect();
+        // when several files declare the name, prefer those this document can see
+        let seen = imported(&imports, uri);
+        if declared_in.iter().any(|d| seen.contains(*d)) {
+            declared_in.retain(|d| seen.contains(*d));
+        }
+        let mut modules: Vec<String> = commands
+            .iter()
+            .filter(|(d, s)| s.name == name && declared_in.contains(&d))
+            .filter_map(|(_, s)| s.container_name.clone())
+            .collect();
+        modules.extend(declared_in.iter().filter_map(|uri| {
+            uri.to_file_path()
+                .ok()
+                .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        }));
+        let modules: Vec<&str> = modules.iter().map(String::as_str).collect();
+
+        let visible: Option<HashSet<Url>> = (!declared_in.is_empty()).then(|| {
+            declared_in
+                .iter()
+                .flat_map(|uri| dependents(&imports, uri))
+                .chain(declared_in.iter().map(|uri| (*uri).clone()))
+                .collect()
+        });
+
+        Ok(Some(
+            texts
+                .into_iter()
+                .filter(|(uri, _)| visible.as_ref().is_none_or(|v| v.contains(uri)))
+                .map(|(uri, text)| {
+                    let references = syntax::command_references(&text, &name, &modules);
+                    FileReferences {
+                        uri,
+                        text,
+                        references,
+                    }
+                })
+                .filter(|found| !found.references.is_empty())
+                .collect(),
+        ))
+    }
+
+    /// The text of every open document and file in the workspace folders.
+    async fn indexed_texts(&self) -> Result<Vec<(Url, String)>> {
+        let uris: Vec<Url> = {
+            let symbol_index = self.symbol_index.read().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot read symbol index: {e:?}"))
+            })?;
+            symbol_index.keys().cloned().collect()
+        };
+        let mut texts = Vec::with_capacity(uris.len());
+        for uri in uris {
+            // e.g. deleted since it was indexed
+            if let Ok((text, _)) = self.read_document(&uri).await {
+                texts.push((uri, text));
+            }
+        }
+        Ok(texts)
+    }
+}
+
+/// `uri` and the files it imports, directly or through other files.
+fn imported(imports: &HashMap<Url, HashSet<Url>>, uri: &Url) -> HashSet<Url> {
+    let mut found = HashSet::from([uri.clone()]);
+    let mut pending = vec![uri];
+    while let Some(importer) = pending.pop() {
+        for imported in imports.get(importer).into_iter().flatten() {
+            if found.insert(imported.clone()) {
+                pending.push(imported);
+            }
+        }
+    }
+    found
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use tower_lsp::jsonrpc::Result;
+#[allow(clippy::wildcard_imports)]
+use tower_lsp::lsp_types::*;
+
+use super::{references::FileReferences, Backend};
+use crate::{
+    offsets::LineIndex,
+    syntax::{Reference, Symbol},
+};
+
+impl Backend {
+    /// Answers `textDocument/prepareRename` with the name at `position`,
+    /// if it's a variable or a command declared in the workspace.
+    pub(super) async fn prepare_rename(
+        &self,
+        uri: &Url,
+        position: Position,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        let Some((_, found)) = self.renameable_references(uri, position).await? else {
+            return Ok(None);
+        };
+        let Some(file) = found.iter().find(|f| &f.uri == uri) else {
+            return Ok(None);
+        };
+        let index = LineIndex::new(&file.text, self.encoding());
+        let offset = index.offset(position) as usize;
+        let Some(reference) = file
+            .references
+            .iter()
+            .find(|r| r.start <= offset && offset <= r.end)
+        else {
+            return Ok(None);
+        };
+
+        let (start, end) = name_span(&file.text, reference);
+        Ok(Some(PrepareRenameResponse::RangeWithPlaceholder {
+            range: index.range(to_offset(start), to_offset(end)),
+            placeholder: String::from(&file.text[start..end]),
+        }))
+    }
+
+    /// Answers `textDocument/rename` with edits to every declaration and use of the name at `position`,
+    /// including `use` import lists in other files.
+    pub(super) async fn rename(
+        &self,
+        uri: &Url,
+        position: Position,
+        new_name: &str,
+    ) -> Result<Option<WorkspaceEdit>> {
+        let Some((symbol, found)) = self.renameable_references(uri, position).await? else {
+            return Ok(None);
+        };
+        let new_name = match symbol {
+            Symbol::Variable(_) => {
+                let new_name = new_name.trim().trim_start_matches('$');
+                if new_name.is_empty() || !new_name.chars().all(|c| c.is_alphanumeric() || c == '_')
+                {
+                    return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                        "{new_name:?} is not a valid variable name"
+                    )));
+                }
+                new_name
+            }
+            Symbol::Command { .. } => {
+                let new_name = new_name.trim();
+                if new_name.is_empty()
+                    || new_name.starts_with('-')
+                    || new_name.contains(['"', '\'', '`', '\n'])
+                {
+                    return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                        "{new_name:?} is not a valid command name"
+                    )));
+                }
+                new_name
+            }
+        };
+
+        let changes = found
+            .into_iter()
+            .map(|file| {
+                let index = LineIndex::new(&file.text, self.encoding());
+                let edits = file
+                    .references
+                    .iter()
+                    .map(|r| {
+                        let (start, end) = name_span(&file.text, r);
+                        TextEdit {
+                            range: index.range(to_offset(start), to_offset(end)),
+                            new_text: replacement(&file.text, r, new_name),
+                        }
+                    })
+                    .collect();
+                (file.uri, edits)
+            })
+            .collect::<HashMap<_, _>>();
+        Ok(Some(WorkspaceEdit {
+            changes: Some(changes),
+            ..Default::default()
+        }))
+    }
+
+    /// The references to the name at `position`, as long as one of them declares it,
+    /// e.g. not for built-in commands.
+    async fn renameable_references(
+        &self,
+        uri: &Url,
+        position: Position,
+    ) -> Result<Option<(Symbol, Vec<FileReferences>)>> {
+        Ok(self
+            .find_references(uri, position)
+            .await?
+            .filter(|(_, found)| {
+                found
+                    .iter()
+                    .any(|f| f.references.iter().any(|r| r.declaration))
+            }))
+    }
+}
+
+fn to_offset(offset: usize) -> u32 {
+    u32::try_from(offset).unwrap_or(u32::MAX)
+}
+
+/// The part of `reference` that is the name itself, without any `$`, flag dashes or quotes.
+fn name_span(text: &str, reference: &Reference) -> (usize, usize) {
+    let written = &text[reference.start..reference.end];
+    if written.starts_with('$') {
+        (reference.start + 1, reference.end)
+    } else if written.starts_with("--") && !reference.call {
+        (reference.start + 2, reference.end)
+    } else if written.len() > 1
+        && written.starts_with(['"', '\'', '`'])
+        && written.ends_with(&written[..1])
+    {
+        (reference.start + 1, reference.end - 1)
+    } else {
+        (reference.start, reference.end)
+    }
+}
+
+/// What replaces the [`name_span`] of `reference` to rename it to `new_name`.
+fn replacement(text: &str, reference: &Reference, new_name: &str) -> String {
+    let written = &text[reference.start..reference.end];
+    if written.starts_with("--") && !reference.call {
+        // the variable for `--dry-run` is `$dry_run`
+        new_name.replace('_', "-")
+    } else if written.starts_with(['$', '"', '\'', '`'])
+        || reference.call
+        || !new_name.contains(char::is_whitespace)
+    {
+        String::from(new_name)
+    } else {
+        format!("\"{new_name}\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax;
+
+    #[test]
+    fn replacement_keeps_sigils_and_quotes_names_with_spaces() {
+        let text = "\
+def build [--dry-run] { $dry_run }
+def 'git st' [] {}
+use lib.nu [build]
+git st";
+        let rename = |name: &str, references: Vec<Reference>, new_name: &str| {
+            let mut renamed = String::from(text);
+            for r in references.iter().rev() {
+                let (start, end) = name_span(text, r);
+                renamed.replace_range(start..end, &replacement(text, r, new_name));
+            }
+            assert!(
+                references.iter().any(|r| r.declaration),
+                "{name} is declared"
+            );
+            renamed
+        };
+
+        assert_eq!(
+            rename(
+                "dry_run",
+                syntax::variable_references(
+                    text,
+                    "dry_run",
+                    text.find("$dry_run").unwrap_or_default()
+                ),
+                "check_only"
+            ),
+            text.replace("--dry-run", "--check-only")
+                .replace("$dry_run", "$check_only")
+        );
+        assert_eq!(
+            rename(
+                "build",
+                syntax::command_references(text, "build", &[]),
+                "build all"
+            ),
+            text.replace("def build", "def \"build all\"")
+                .replace("[build]", "[\"build all\"]")
+        );
+        assert_eq!(
+            rename(
+                "git st",
+                syntax::command_references(text, "git st", &[]),
+                "git status"
+            ),
+            text.replace("git st", "git status")
+        );
+    }
+}
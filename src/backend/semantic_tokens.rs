@@ -0,0 +1,452 @@
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+
+use tower_lsp::jsonrpc::Result;
+#[allow(clippy::wildcard_imports)]
+use tower_lsp::lsp_types::*;
+
+use super::Backend;
+use crate::{
+    error::map_err_to_internal_error,
+    nu::{IdeAst, IdeCommand},
+    offsets::LineIndex,
+};
+
+// the legend, in the order clients index into it:
+// - function: internal and external commands, and the names `def`, `extern` and `alias` declare
+// - variable: `$variables`, and the names `let`, `mut` and `const` declare
+// - parameter: `--flags`
+// - string: strings, interpolations, paths, globs, and arguments to external commands
+// - number: ints, floats, filesizes, durations, dates and binary
+// - keyword: keywords, `true`, `false` and `null`
+// - operator: operators, pipes, redirections and ranges
+// - namespace: the names `module` declares
+const TOKEN_TYPES: [SemanticTokenType; 8] = [
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::PARAMETER,
+    SemanticTokenType::STRING,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::KEYWORD,
+    SemanticTokenType::OPERATOR,
+    SemanticTokenType::NAMESPACE,
+];
+// - declaration: where a command, module or variable is named
+// - defaultLibrary: commands that the document doesn't define itself, i.e. builtins (or imports)
+const TOKEN_MODIFIERS: [SemanticTokenModifier; 2] = [
+    SemanticTokenModifier::DECLARATION,
+    SemanticTokenModifier::DEFAULT_LIBRARY,
+];
+const DECLARATION: u32 = 1;
+const DEFAULT_LIBRARY: u32 = 1 << 1;
+
+pub(super) fn legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: TOKEN_TYPES.to_vec(),
+        token_modifiers: TOKEN_MODIFIERS.to_vec(),
+    }
+}
+
+/// A token with an absolute position, before it is encoded relative to the one before it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Token {
+    start: Position,
+    /// in code units of the negotiated encoding
+    length: u32,
+    kind: u32,
+    modifiers: u32,
+}
+
+impl Backend {
+    /// Answers `textDocument/semanticTokens/full`, remembering the result for later deltas.
+    pub(super) async fn semantic_tokens_full(&self, uri: &Url) -> Result<SemanticTokens> {
+        let data = encode(&self.semantic_tokens(uri).await?);
+        let tokens = SemanticTokens {
+            result_id: Some(
+                self.next_result_id
+                    .fetch_add(1, Ordering::Relaxed)
+                    .to_string(),
+            ),
+            data,
+        };
+        let mut document_semantic_tokens = self.document_semantic_tokens.write().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot write semantic tokens cache: {e:?}"))
+        })?;
+        document_semantic_tokens.insert(uri.clone(), tokens.clone());
+        Ok(tokens)
+    }
+
+    /// Answers `textDocument/semanticTokens/full/delta`,
+    /// with edits to the previous result if the client still has it, or in full otherwise.
+    pub(super) async fn semantic_tokens_delta(
+        &self,
+        uri: &Url,
+        previous_result_id: &str,
+    ) -> Result<SemanticTokensFullDeltaResult> {
+        let previous = {
+            let document_semantic_tokens = self.document_semantic_tokens.read().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot read semantic tokens cache: {e:?}"))
+            })?;
+            document_semantic_tokens
+                .get(uri)
+                .filter(|t| t.result_id.as_deref() == Some(previous_result_id))
+                .map(|t| t.data.clone())
+        };
+        let current = self.semantic_tokens_full(uri).await?;
+        Ok(match previous {
+            Some(previous) => SemanticTokensFullDeltaResult::TokensDelta(SemanticTokensDelta {
+                result_id: current.result_id,
+                edits: edits(&previous, &current.data),
+            }),
+            None => SemanticTokensFullDeltaResult::Tokens(current),
+        })
+    }
+
+    /// Answers `textDocument/semanticTokens/range` with the tokens that overlap `range`.
+    pub(super) async fn semantic_tokens_range(
+        &self,
+        uri: &Url,
+        range: Range,
+    ) -> Result<SemanticTokens> {
+        let tokens: Vec<Token> = self
+            .semantic_tokens(uri)
+            .await?
+            .into_iter()
+            .filter(|t| {
+                let end = Position::new(t.start.line, t.start.character + t.length);
+                t.start < range.end && end > range.start
+            })
+            .collect();
+        Ok(SemanticTokens {
+            result_id: None,
+            data: encode(&tokens),
+        })
+    }
+
+    async fn semantic_tokens(&self, uri: &Url) -> Result<Vec<Token>> {
+        let text = self.for_document(uri, &|doc| String::from(doc.get_content(None)))?;
+        let ide_settings = self.get_document_settings(uri).await?;
+        let output = self
+            .run_compiler(&text, IdeCommand::Ast, ide_settings, uri)
+            .await?;
+        let items = IdeAst::from_compiler_response(&output)?;
+
+        // spans are byte offsets into exactly the text that nu parsed
+        let index = LineIndex::new(&text, self.encoding());
+        Ok(tokens(&items, &text, &index))
+    }
+}
+
+/// Classifies the flattened AST, skipping anything that overlaps an earlier token.
+///
+/// Tokens that span lines are split, as not every client supports multi-line tokens.
+fn tokens(items: &[IdeAst], text: &str, index: &LineIndex) -> Vec<Token> {
+    let mut items: Vec<&IdeAst> = items
+        .iter()
+        .filter(|i| i.span.start < i.span.end && i.span.end as usize <= text.len())
+        .collect();
+    items.sort_by_key(|i| i.span.start);
+
+    let declared: HashSet<&str> = items
+        .windows(2)
+        .filter(|pair| declares(pair[0]) == Some(SemanticTokenType::FUNCTION))
+        .map(|pair| unquoted(&pair[1].content))
+        .collect();
+
+    let mut tokens = vec![];
+    let mut end = 0;
+    let mut declaring = None;
+    for item in items {
+        let declared_type = declaring.take();
+        declaring = declares(item);
+        if item.span.start < end {
+            continue;
+        }
+        let classified = match declared_type {
+            Some(token_type) => Some((token_type, DECLARATION)),
+            None => classify(item, &declared),
+        };
+        let Some((token_type, modifiers)) = classified else {
+            continue;
+        };
+        let kind = TOKEN_TYPES
+            .iter()
+            .position(|t| *t == token_type)
+            .and_then(|i| u32::try_from(i).ok())
+            .unwrap_or_default();
+        let start = item.span.start as usize;
+        let Some(content) = text.get(start..item.span.end as usize) else {
+            continue;
+        };
+        end = item.span.end;
+
+        let mut line_start = start;
+        for line in content.split_inclusive('\n') {
+            let line_end = line_start + line.trim_end_matches(['\n', '\r']).len();
+            let (from, to) = (
+                index.position(u32::try_from(line_start).unwrap_or(u32::MAX)),
+                index.position(u32::try_from(line_end).unwrap_or(u32::MAX)),
+            );
+            if to.character > from.character {
+                tokens.push(Token {
+                    start: from,
+                    length: to.character - from.character,
+                    kind,
+                    modifiers,
+                });
+            }
+            line_start += line.len();
+        }
+    }
+    tokens
+}
+
+/// What sort of name the item after `item` declares, if `item` is a command that declares one.
+fn declares(item: &IdeAst) -> Option<SemanticTokenType> {
+    if item.shape != "shape_internalcall" {
+        return None;
+    }
+    // e.g. `export def`
+    match item.content.split_whitespace().last() {
+        Some("def" | "def-env" | "extern" | "extern-wrapped" | "alias") => {
+            Some(SemanticTokenType::FUNCTION)
+        }
+        Some("module") => Some(SemanticTokenType::NAMESPACE),
+        _ => None,
+    }
+}
+
+fn classify(item: &IdeAst, declared: &HashSet<&str>) -> Option<(SemanticTokenType, u32)> {
+    let token_type = match item.shape.as_str() {
+        "shape_internalcall" => {
+            let modifiers = if declared.contains(item.content.as_str()) {
+                0
+            } else {
+                DEFAULT_LIBRARY
+            };
+            return Some((SemanticTokenType::FUNCTION, modifiers));
+        }
+        "shape_vardecl" => return Some((SemanticTokenType::VARIABLE, DECLARATION)),
+        "shape_external" => SemanticTokenType::FUNCTION,
+        "shape_variable" => SemanticTokenType::VARIABLE,
+        "shape_flag" => SemanticTokenType::PARAMETER,
+        "shape_string"
+        | "shape_string_interpolation"
+        | "shape_raw_string"
+        | "shape_filepath"
+        | "shape_directory"
+        | "shape_globpattern"
+        | "shape_externalarg" => SemanticTokenType::STRING,
+        "shape_int" | "shape_float" | "shape_filesize" | "shape_duration" | "shape_datetime"
+        | "shape_binary" => SemanticTokenType::NUMBER,
+        "shape_keyword" | "shape_bool" | "shape_nothing" => SemanticTokenType::KEYWORD,
+        "shape_operator" | "shape_pipe" | "shape_redirection" | "shape_range" | "shape_and"
+        | "shape_or" => SemanticTokenType::OPERATOR,
+        // blocks, lists, records and the like contain other tokens, and garbage isn't anything
+        _ => return None,
+    };
+    Some((token_type, 0))
+}
+
+fn unquoted(content: &str) -> &str {
+    content.trim_matches(['"', '\'', '`'])
+}
+
+/// Encodes each token relative to the one before it, as the protocol requires.
+fn encode(tokens: &[Token]) -> Vec<SemanticToken> {
+    let mut previous = Position::default();
+    tokens
+        .iter()
+        .map(|t| {
+            let delta_line = t.start.line - previous.line;
+            let delta_start = if delta_line == 0 {
+                t.start.character - previous.character
+            } else {
+                t.start.character
+            };
+            previous = t.start;
+            SemanticToken {
+                delta_line,
+                delta_start,
+                length: t.length,
+                token_type: t.kind,
+                token_modifiers_bitset: t.modifiers,
+            }
+        })
+        .collect()
+}
+
+/// The edit that turns `previous` into `current`, replacing whatever lies between what they have in common.
+fn edits(previous: &[SemanticToken], current: &[SemanticToken]) -> Vec<SemanticTokensEdit> {
+    let prefix = previous
+        .iter()
+        .zip(current)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = previous[prefix..]
+        .iter()
+        .rev()
+        .zip(current[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if prefix == previous.len() && prefix == current.len() {
+        return vec![];
+    }
+
+    // each token is 5 integers in the array the edits refer to
+    let count = |tokens: usize| u32::try_from(tokens * 5).unwrap_or(u32::MAX);
+    let inserted = &current[prefix..current.len() - suffix];
+    vec![SemanticTokensEdit {
+        start: count(prefix),
+        delete_count: count(previous.len() - prefix - suffix),
+        data: (!inserted.is_empty()).then(|| inserted.to_vec()),
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{nu::IdeSpan, offsets::Encoding};
+
+    fn ast(text: &str, shapes: &[(&str, &str)]) -> Vec<IdeAst> {
+        let mut from = 0;
+        shapes
+            .iter()
+            .map(|(content, shape)| {
+                let start = from
+                    + text[from..]
+                        .find(content)
+                        .expect("content should be in text");
+                from = start + content.len();
+                IdeAst {
+                    content: String::from(*content),
+                    shape: String::from(*shape),
+                    span: IdeSpan {
+                        end: u32::try_from(from).expect("should fit"),
+                        start: u32::try_from(start).expect("should fit"),
+                    },
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tokens_mark_declarations_and_builtins() {
+        let text = "def greet [] { print $\"hi\" }\nlet x = 1\ngreet --verbose";
+        let items = ast(
+            text,
+            &[
+                ("def", "shape_internalcall"),
+                ("greet", "shape_string"),
+                ("[]", "shape_signature"),
+                ("print", "shape_internalcall"),
+                ("$\"hi\"", "shape_string_interpolation"),
+                ("let", "shape_internalcall"),
+                ("x", "shape_vardecl"),
+                ("1", "shape_int"),
+                ("greet", "shape_internalcall"),
+                ("--verbose", "shape_flag"),
+            ],
+        );
+        let index = LineIndex::new(text, Encoding::Utf16);
+
+        let got: Vec<(u32, u32, u32, u32, u32)> = tokens(&items, text, &index)
+            .iter()
+            .map(|t| {
+                (
+                    t.start.line,
+                    t.start.character,
+                    t.length,
+                    t.kind,
+                    t.modifiers,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            got,
+            vec![
+                (0, 0, 3, 0, DEFAULT_LIBRARY),
+                (0, 4, 5, 0, DECLARATION),
+                (0, 15, 5, 0, DEFAULT_LIBRARY),
+                (0, 21, 5, 3, 0),
+                (1, 0, 3, 0, DEFAULT_LIBRARY),
+                (1, 4, 1, 1, DECLARATION),
+                (1, 8, 1, 4, 0),
+                (2, 0, 5, 0, 0),
+                (2, 6, 9, 2, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokens_split_across_lines() {
+        let text = "print 'a\nbé'";
+        let items = ast(text, &[("'a\nbé'", "shape_string")]);
+        let index = LineIndex::new(text, Encoding::Utf16);
+
+        let got = tokens(&items, text, &index);
+
+        assert_eq!(
+            got,
+            vec![
+                Token {
+                    start: Position::new(0, 6),
+                    length: 2,
+                    kind: 3,
+                    modifiers: 0
+                },
+                Token {
+                    start: Position::new(1, 0),
+                    length: 3,
+                    kind: 3,
+                    modifiers: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_is_relative() {
+        let token = |line, character, length| Token {
+            start: Position::new(line, character),
+            length,
+            kind: 0,
+            modifiers: 0,
+        };
+
+        let got: Vec<(u32, u32, u32)> = encode(&[token(0, 2, 3), token(0, 6, 1), token(2, 4, 2)])
+            .iter()
+            .map(|t| (t.delta_line, t.delta_start, t.length))
+            .collect();
+
+        assert_eq!(got, vec![(0, 2, 3), (0, 4, 1), (2, 4, 2)]);
+    }
+
+    #[test]
+    fn edits_replace_the_middle() {
+        let token = |length| SemanticToken {
+            length,
+            ..SemanticToken::default()
+        };
+        let previous = [token(1), token(2), token(3)];
+
+        assert_eq!(edits(&previous, &previous), vec![]);
+        assert_eq!(
+            edits(&previous, &[token(1), token(4), token(5), token(3)]),
+            vec![SemanticTokensEdit {
+                start: 5,
+                delete_count: 5,
+                data: Some(vec![token(4), token(5)]),
+            }]
+        );
+        assert_eq!(
+            edits(&previous, &[token(1)]),
+            vec![SemanticTokensEdit {
+                start: 5,
+                delete_count: 10,
+                data: None,
+            }]
+        );
+    }
+}
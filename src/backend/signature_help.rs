@@ -0,0 +1,128 @@
+use tower_lsp::jsonrpc::Result;
+#[allow(clippy::wildcard_imports)]
+use tower_lsp::lsp_types::*;
+
+use super::Backend;
+use crate::{
+    error::map_err_to_internal_error,
+    nu::{IdeCommand, IdeHover},
+    offsets::LineIndex,
+    signature::Signature,
+    syntax,
+};
+
+impl Backend {
+    /// Answers `textDocument/signatureHelp` for the command being called at `position`,
+    /// preferring commands declared in the workspace, then those `nu` has built in.
+    pub(super) async fn signature_help(
+        &self,
+        uri: &Url,
+        position: Position,
+    ) -> Result<Option<SignatureHelp>> {
+        let text = self.for_document(uri, &|doc| String::from(doc.get_content(None)))?;
+        let offset = LineIndex::new(&text, self.encoding()).offset(position);
+        let Some(call) = syntax::call_at(&text, offset as usize) else {
+            return Ok(None);
+        };
+
+        let signature = match self.declared_signature(&text, &call).await? {
+            Some(signature) => Some(signature),
+            None => self.builtin_signature(uri, &text, &call).await?,
+        };
+        let Some(signature) = signature else {
+            return Ok(None);
+        };
+        let name_length = signature.name.split_whitespace().count();
+        // still typing the name
+        if call.cursor < name_length {
+            return Ok(None);
+        }
+
+        let args: Vec<&str> = call.words[name_length..]
+            .iter()
+            .map(String::as_str)
+            .collect();
+        let active_parameter = signature.active_parameter(&args, call.cursor - name_length);
+        Ok(Some(SignatureHelp {
+            signatures: vec![signature.to_signature_information(active_parameter)],
+            active_signature: Some(0),
+            active_parameter: None,
+        }))
+    }
+
+    /// The signature of the longest run of words before the cursor that names a command
+    /// declared in `text`, or in another file in the workspace.
+    async fn declared_signature(
+        &self,
+        text: &str,
+        call: &syntax::Call,
+    ) -> Result<Option<Signature>> {
+        let names: Vec<String> = (1..=call.cursor.min(call.words.len()))
+            .rev()
+            .map(|n| call.words[..n].join(" "))
+            .collect();
+        let find = |signatures: Vec<Signature>| {
+            names
+                .iter()
+                .find_map(|name| signatures.iter().find(|s| &s.name == name).cloned())
+        };
+        if let Some(signature) = find(syntax::signatures(text)) {
+            return Ok(Some(signature));
+        }
+
+        self.ensure_symbol_index().await?;
+        let declared_in: Vec<Url> = {
+            let symbol_index = self.symbol_index.read().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot read symbol index: {e:?}"))
+            })?;
+            symbol_index
+                .iter()
+                .filter(|(_, symbols)| {
+                    symbols.iter().any(|s| {
+                        matches!(s.kind, SymbolKind::FUNCTION | SymbolKind::INTERFACE)
+                            && names.contains(&s.name)
+                    })
+                })
+                .map(|(uri, _)| uri.clone())
+                .collect()
+        };
+        for uri in declared_in {
+            let Ok((text, _)) = self.read_document(&uri).await else {
+                continue;
+            };
+            if let Some(signature) = find(syntax::signatures(&text)) {
+                return Ok(Some(signature));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The signature of the built-in (or plugin) command `call` starts with, from the catalog,
+    /// or as `nu --ide-hover` describes it when there is no catalog to look in.
+    async fn builtin_signature(
+        &self,
+        uri: &Url,
+        text: &str,
+        call: &syntax::Call,
+    ) -> Result<Option<Signature>> {
+        let catalog = self.catalog().await;
+        if let Some(command) = catalog.find(&call.words, call.cursor) {
+            return Ok(Some(command.to_signature()));
+        }
+        // a command the catalog doesn't know is presumably external, so there's nothing to scrape
+        if !catalog.is_empty() || !self.nu_capabilities.get().is_some_and(|nu| nu.ide_hover) {
+            return Ok(None);
+        }
+        let Ok(offset) = u32::try_from(call.start) else {
+            return Ok(None);
+        };
+
+        let ide_settings = self.get_document_settings(uri).await?;
+        let output = self
+            .run_compiler(text, IdeCommand::Hover(offset), ide_settings, uri)
+            .await?;
+        let hover: IdeHover =
+            serde_json::from_slice(output.stdout.as_bytes()).map_err(|e| output.parse_error(e))?;
+        Ok(hover.to_signature())
+    }
+}
@@ -0,0 +1,231 @@
+use std::cmp::Reverse;
+use std::sync::atomic::Ordering;
+
+use tower_lsp::jsonrpc::Result;
+#[allow(clippy::wildcard_imports)]
+use tower_lsp::lsp_types::*;
+
+use super::Backend;
+use crate::{
+    error::map_err_to_internal_error,
+    offsets::LineIndex,
+    syntax::{self, Declaration},
+};
+
+// enough to find what you're after, without flooding the client's picker
+const MAX_WORKSPACE_SYMBOLS: usize = 256;
+
+impl Backend {
+    /// Answers `workspace/symbol` with the declarations that best match `query`,
+    /// indexing the workspace folders first if that hasn't happened yet.
+    pub(super) async fn workspace_symbols(&self, query: &str) -> Result<Vec<SymbolInformation>> {
+        self.ensure_symbol_index().await?;
+
+        let symbol_index = self.symbol_index.read().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot read symbol index: {e:?}"))
+        })?;
+        let mut matches: Vec<(u32, &SymbolInformation)> = symbol_index
+            .values()
+            .flatten()
+            .filter_map(|s| fuzzy_score(query, &s.name).map(|score| (score, s)))
+            .collect();
+        matches.sort_by_key(|(score, s)| (Reverse(*score), s.name.len(), s.name.as_str()));
+        Ok(matches
+            .into_iter()
+            .take(MAX_WORKSPACE_SYMBOLS)
+            .map(|(_, s)| s.clone())
+            .collect())
+    }
+
+    /// Re-reads the declarations in `uri`, preferring an open document over what is saved on disk,
+    /// and forgets them if it's neither open nor in a workspace folder (any more).
+    pub(super) async fn refresh_symbols(&self, uri: &Url) -> Result<()> {
+        let in_workspace = self.is_open(uri)? || {
+            let path = uri.to_file_path().ok();
+            self.read_workspace_folders()?.iter().any(|f| {
+                f.uri
+                    .to_file_path()
+                    .is_ok_and(|root| path.as_ref().is_some_and(|p| p.starts_with(root)))
+            })
+        };
+        let symbols = if in_workspace {
+            // e.g. deleted
+            self.read_document(uri)
+                .await
+                .ok()
+                .map(|(text, _)| self.symbols(uri, &text))
+        } else {
+            None
+        };
+
+        let mut symbol_index = self.symbol_index.write().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot write symbol index: {e:?}"))
+        })?;
+        match symbols {
+            Some(symbols) => symbol_index.insert(uri.clone(), symbols),
+            None => symbol_index.remove(uri),
+        };
+        Ok(())
+    }
+
+    /// Starts the index again from scratch, e.g. when workspace folders change.
+    pub(super) fn reset_symbol_index(&self) -> Result<()> {
+        let mut symbol_index = self.symbol_index.write().map_err(|e| {
+            map_err_to_internal_error(&e, format!("cannot write symbol index: {e:?}"))
+        })?;
+        symbol_index.clear();
+        self.symbol_index_ready.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    pub(super) async fn ensure_symbol_index(&self) -> Result<()> {
+        if self.symbol_index_ready.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        self.index_workspace().await
+    }
+
+    async fn index_workspace(&self) -> Result<()> {
+        let mut uris = self.workspace_files().await?;
+        {
+            let documents = self.documents.read().map_err(|e| {
+                map_err_to_internal_error(&e, format!("cannot read from document cache: {e:?}"))
+            })?;
+            uris.extend(documents.documents().keys().cloned());
+        }
+        for uri in uris {
+            self.refresh_symbols(&uri).await?;
+        }
+        self.symbol_index_ready.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn symbols(&self, uri: &Url, text: &str) -> Vec<SymbolInformation> {
+        let index = LineIndex::new(text, self.encoding());
+        let mut symbols = vec![];
+        flatten(&syntax::declarations(text), None, uri, &index, &mut symbols);
+        symbols
+    }
+}
+
+/// Lists declarations and those nested within them, naming the module each is in.
+fn flatten(
+    declarations: &[Declaration],
+    container: Option<&str>,
+    uri: &Url,
+    index: &LineIndex,
+    symbols: &mut Vec<SymbolInformation>,
+) {
+    for declaration in declarations {
+        let symbol = declaration.to_document_symbol(index);
+        #[allow(deprecated)]
+        symbols.push(SymbolInformation {
+            name: symbol.name,
+            kind: symbol.kind,
+            tags: None,
+            deprecated: None,
+            location: Location {
+                uri: uri.clone(),
+                range: symbol.selection_range,
+            },
+            container_name: container.map(String::from),
+        });
+        flatten(
+            &declaration.children,
+            Some(declaration.name.unquoted()),
+            uri,
+            index,
+            symbols,
+        );
+    }
+}
+
+/// How well `query` matches `candidate`, higher being better,
+/// or `None` if the characters of `query` don't all appear in order (ignoring case).
+///
+/// Matches at the start of words and runs of consecutive matches count for more,
+/// as do prefixes and exact matches.
+fn fuzzy_score(query: &str, candidate: &str) -> Option<u32> {
+    // everything matches nothing in particular
+    if query.is_empty() {
+        return Some(0);
+    }
+    let characters: Vec<char> = candidate.chars().collect();
+    let same = |a: char, b: char| a == b || a.to_lowercase().eq(b.to_lowercase());
+    // what matching `q` at `i` is worth, before any bonus for following the previous match
+    let worth = |i: usize, q: char| {
+        let starts_word = match i.checked_sub(1).map(|before| characters[before]) {
+            None => true,
+            Some(before) => {
+                matches!(before, ' ' | '-' | '_' | '.' | '/')
+                    || (before.is_lowercase() && characters[i].is_uppercase())
+            }
+        };
+        let mut worth = 1;
+        if starts_word {
+            worth += 8;
+        }
+        if characters[i] == q {
+            worth += 1;
+        }
+        worth
+    };
+
+    // the best score for the query so far, with its last character matched at each position,
+    // as the leftmost match isn't always the best one (e.g. `bu` in `pub-build`)
+    let mut best: Vec<Option<u32>> = vec![None; characters.len()];
+    for (n, q) in query.chars().enumerate() {
+        let mut next = vec![None; characters.len()];
+        for (i, c) in characters.iter().enumerate() {
+            if !same(*c, q) {
+                continue;
+            }
+            next[i] = if n == 0 {
+                Some(worth(i, q))
+            } else {
+                best[..i]
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(p, score)| score.map(|s| s + if p + 1 == i { 4 } else { 0 }))
+                    .max()
+                    .map(|s| s + worth(i, q))
+            };
+        }
+        best = next;
+    }
+    let mut score = best.into_iter().flatten().max()?;
+
+    let (query, candidate) = (query.to_lowercase(), candidate.to_lowercase());
+    if candidate == query {
+        score += 32;
+    } else if candidate.starts_with(&query) {
+        score += 16;
+    }
+    Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_score_needs_every_character_in_order() {
+        assert!(fuzzy_score("gp", "git push").is_some());
+        assert!(fuzzy_score("GP", "git push").is_some());
+        assert!(fuzzy_score("pg", "git push").is_none());
+        assert!(fuzzy_score("gitx", "git push").is_none());
+        assert_eq!(fuzzy_score("", "git push"), Some(0));
+    }
+
+    #[test]
+    fn fuzzy_score_ranks_word_starts_and_prefixes_first() {
+        let mut candidates = vec!["rebuild", "sub-unit", "b", "pub-build", "build"];
+        candidates.sort_by_key(|c| Reverse(fuzzy_score("bu", c)));
+
+        // `b` doesn't match at all, so it sorts after everything that does
+        assert_eq!(
+            candidates,
+            vec!["build", "pub-build", "sub-unit", "rebuild", "b"]
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use futures::{stream, StreamExt};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+#[allow(clippy::wildcard_imports)]
+use tower_lsp::lsp_types::*;
+use tower_lsp::lsp_types::{notification::Progress, request::WorkDoneProgressCreate};
+use tower_lsp::{jsonrpc::Result, Client};
+use walkdir::WalkDir;
+
+use super::{Backend, DiagnosticsReport};
+use crate::{error::map_err_to_internal_error, nu::IdeSettingsWorkspace};
+
+// leaves room under the process limit for the document the user is actually editing
+const MAX_CONCURRENT_WORKSPACE_CHECKS: usize = 2;
+
+impl Backend {
+    /// Answers `workspace/diagnostic` with a report for every `.nu` file in the workspace folders.
+    pub(super) async fn pull_workspace_diagnostics(
+        &self,
+        params: WorkspaceDiagnosticParams,
+    ) -> Result<WorkspaceDiagnosticReport> {
+        let previous: HashMap<Url, String> = params
+            .previous_result_ids
+            .into_iter()
+            .map(|p| (p.uri, p.value))
+            .collect();
+        let progress = WorkProgress::begin(
+            &self.client,
+            params.work_done_progress_params.work_done_token,
+            "checking workspace",
+        )
+        .await;
+
+        let reports = self.check_workspace(&progress).await?;
+        progress.end().await;
+
+        let items = reports
+            .into_iter()
+            .map(|(uri, report)| {
+                let version = report.revision.version().map(i64::from);
+                if previous.get(&uri) == Some(&report.result_id) {
+                    WorkspaceDocumentDiagnosticReport::Unchanged(
+                        WorkspaceUnchangedDocumentDiagnosticReport {
+                            uri,
+                            version,
+                            unchanged_document_diagnostic_report:
+                                UnchangedDocumentDiagnosticReport {
+                                    result_id: report.result_id,
+                                },
+                        },
+                    )
+                } else {
+                    WorkspaceDocumentDiagnosticReport::Full(WorkspaceFullDocumentDiagnosticReport {
+                        uri,
+                        version,
+                        full_document_diagnostic_report: FullDocumentDiagnosticReport {
+                            result_id: Some(report.result_id),
+                            items: report.items,
+                        },
+                    })
+                }
+            })
+            .collect();
+        Ok(WorkspaceDiagnosticReport { items })
+    }
+
+    /// Starts `scan_workspace` without waiting for it,
+    /// so that the notification handler doesn't hold up other messages for the whole scan.
+    pub(super) fn spawn_scan_workspace(&self) {
+        let backend = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = backend.scan_workspace().await {
+                backend
+                    .client
+                    .log_message(MessageType::ERROR, format!("{e:?}"))
+                    .await;
+            }
+        });
+    }
+
+    /// Checks the workspace folders unprompted, if the `workspace.scanInBackground` setting asks for it.
+    ///
+    /// Results are published for clients that don't pull diagnostics,
+    /// pulling clients are asked to pull again instead.
+    async fn scan_workspace(&self) -> Result<()> {
+        if !self.read_global_settings()?.workspace.scan_in_background {
+            return Ok(());
+        }
+
+        let token = if *self.can_report_progress.get().unwrap_or(&false) {
+            let token =
+                ProgressToken::String(format!("nuls/workspace/{}", self.client.next_request_id()));
+            self.client
+                .send_request::<WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
+                    token: token.clone(),
+                })
+                .await
+                .ok()
+                .map(|()| token)
+        } else {
+            None
+        };
+        let progress = WorkProgress::begin(&self.client, token, "checking workspace").await;
+        let reports = self.check_workspace(&progress).await;
+        progress.end().await;
+
+        if *self.can_pull_diagnostics.get().unwrap_or(&false) {
+            if *self.can_refresh_diagnostics.get().unwrap_or(&false) {
+                self.client.workspace_diagnostic_refresh().await?;
+            }
+            return Ok(());
+        }
+        for (uri, report) in reports? {
+            // open documents are published as they are edited
+            if report.revision.version().is_none() {
+                self.client
+                    .publish_diagnostics(uri, report.items, None)
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_workspace(
+        &self,
+        progress: &WorkProgress<'_>,
+    ) -> Result<Vec<(Url, DiagnosticsReport)>> {
+        let files = self.workspace_files().await?;
+        let total = files.len();
+
+        let mut done = 0;
+        let mut reports = Vec::with_capacity(total);
+        let mut checks = stream::iter(files)
+            .map(|uri| async move {
+                let report = self.current_diagnostics(&uri).await;
+                (uri, report)
+            })
+            .buffer_unordered(MAX_CONCURRENT_WORKSPACE_CHECKS);
+        while let Some((uri, report)) = checks.next().await {
+            match report {
+                Ok(Some(report)) => reports.push((uri, report)),
+                // changed while it was being checked, the newer revision will be reported instead
+                Ok(None) => {}
+                Err(e) => {
+                    self.client
+                        .log_message(MessageType::ERROR, format!("{uri}: {e:?}"))
+                        .await;
+                }
+            }
+            done += 1;
+            progress.report(done, total).await;
+        }
+        Ok(reports)
+    }
+
+    /// Every `.nu` file in the workspace folders, except those matching that folder's `workspace.ignore`.
+    pub(super) async fn workspace_files(&self) -> Result<Vec<Url>> {
+        let folders = self.read_workspace_folders()?;
+        let mut roots: Vec<(PathBuf, GlobSet)> = Vec::with_capacity(folders.len());
+        for folder in folders {
+            let Ok(root) = folder.uri.to_file_path() else {
+                continue;
+            };
+            let settings = self.get_scoped_settings(&folder.uri).await?;
+            roots.push((root, ignore_globs(&settings.workspace)?));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            roots
+                .iter()
+                .flat_map(|(root, ignore)| {
+                    WalkDir::new(root)
+                        .into_iter()
+                        // relative to the folder, so that e.g. `vendor/**` works
+                        .filter_entry(move |entry| {
+                            !entry
+                                .path()
+                                .strip_prefix(root)
+                                .is_ok_and(|path| ignore.is_match(path))
+                        })
+                        .filter_map(std::result::Result::ok)
+                })
+                .filter(|entry| {
+                    entry.file_type().is_file()
+                        && entry.path().extension().is_some_and(|e| e == "nu")
+                })
+                .filter_map(|entry| Url::from_file_path(entry.path()).ok())
+                .collect()
+        })
+        .await
+        .map_err(|e| map_err_to_internal_error(e, String::from("cannot list workspace files")))
+    }
+}
+
+fn ignore_globs(settings: &IdeSettingsWorkspace) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in &settings.ignore {
+        let glob = Glob::new(pattern).map_err(|e| {
+            tower_lsp::jsonrpc::Error::invalid_params(format!(
+                "invalid workspace.ignore glob `{pattern}`: {e}"
+            ))
+        })?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| map_err_to_internal_error(e, String::from("cannot build workspace.ignore")))
+}
+
+/// `$/progress` notifications for a long-running task, if the client gave (or accepted) a token for it.
+struct WorkProgress<'a> {
+    client: &'a Client,
+    token: Option<ProgressToken>,
+}
+
+impl<'a> WorkProgress<'a> {
+    async fn begin(client: &'a Client, token: Option<ProgressToken>, title: &str) -> Self {
+        let progress = Self { client, token };
+        progress
+            .notify(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                title: String::from(title),
+                percentage: Some(0),
+                ..WorkDoneProgressBegin::default()
+            }))
+            .await;
+        progress
+    }
+
+    async fn report(&self, done: usize, total: usize) {
+        let percentage = (done * 100).checked_div(total).unwrap_or(100);
+        self.notify(WorkDoneProgress::Report(WorkDoneProgressReport {
+            message: Some(format!("{done}/{total} files")),
+            percentage: u32::try_from(percentage).ok(),
+            ..WorkDoneProgressReport::default()
+        }))
+        .await;
+    }
+
+    async fn end(&self) {
+        self.notify(WorkDoneProgress::End(WorkDoneProgressEnd::default()))
+            .await;
+    }
+
+    async fn notify(&self, value: WorkDoneProgress) {
+        if let Some(token) = &self.token {
+            self.client
+                .send_notification::<Progress>(ProgressParams {
+                    token: token.clone(),
+                    value: ProgressParamsValue::WorkDone(value),
+                })
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn ignore_globs_match_directories_and_files() {
+        let ignore = ignore_globs(&IdeSettingsWorkspace {
+            ignore: vec![
+                String::from("**/node_modules"),
+                String::from("vendor/**"),
+                String::from("scripts/*.test.nu"),
+            ],
+            ..IdeSettingsWorkspace::default()
+        })
+        .expect("globs should be valid");
+
+        assert!(ignore.is_match(Path::new("node_modules")));
+        assert!(ignore.is_match(Path::new("lib/node_modules")));
+        assert!(ignore.is_match(Path::new("vendor/c.nu")));
+        assert!(ignore.is_match(Path::new("scripts/build.test.nu")));
+        assert!(!ignore.is_match(Path::new("scripts/build.nu")));
+        assert!(!ignore.is_match(Path::new("lib/scripts/build.test.nu")));
+    }
+}
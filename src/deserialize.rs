@@ -13,6 +13,15 @@ where
     }
 }
 
+/// Treats `null` like a missing field, e.g. for `nu` output where empty values may be either.
+pub(crate) fn null_as_default<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
 #[cfg(test)]
 mod tests {
     use serde_json::json;
@@ -27,4 +36,13 @@ mod tests {
 
         assert_eq!(got, Duration::from_millis(123));
     }
+
+    #[test]
+    fn null_as_default_ok() {
+        let got: String = null_as_default(json!(null)).expect("value should be deserialized");
+        assert_eq!(got, "");
+
+        let got: String = null_as_default(json!("-a")).expect("value should be deserialized");
+        assert_eq!(got, "-a");
+    }
 }
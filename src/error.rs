@@ -1,6 +1,13 @@
 use std::{borrow::Cow, error::Error};
 
 use serde_json::Value;
+use tower_lsp::jsonrpc::ErrorCode;
+
+pub(crate) fn content_modified(msg: String) -> tower_lsp::jsonrpc::Error {
+    let mut err = tower_lsp::jsonrpc::Error::new(ErrorCode::ContentModified);
+    err.message = Cow::from(msg);
+    err
+}
 
 pub(crate) fn map_err_to_internal_error(e: impl Error, msg: String) -> tower_lsp::jsonrpc::Error {
     let mut err = tower_lsp::jsonrpc::Error::internal_error();
@@ -9,6 +16,12 @@ pub(crate) fn map_err_to_internal_error(e: impl Error, msg: String) -> tower_lsp
     err
 }
 
+pub(crate) fn method_not_found(msg: String) -> tower_lsp::jsonrpc::Error {
+    let mut err = tower_lsp::jsonrpc::Error::method_not_found();
+    err.message = Cow::from(msg);
+    err
+}
+
 pub(crate) fn map_err_to_parse_error(e: impl Error, msg: String) -> tower_lsp::jsonrpc::Error {
     let mut err = tower_lsp::jsonrpc::Error::parse_error();
     err.data = Some(Value::String(format!("{e:?}")));
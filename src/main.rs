@@ -4,7 +4,11 @@ mod backend;
 mod deserialize;
 mod error;
 mod nu;
+mod offsets;
+mod signature;
+mod syntax;
 use backend::Backend;
+use nu::compiler_from_args;
 
 use tower_lsp::{LspService, Server};
 
@@ -13,6 +17,7 @@ async fn main() {
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
-    let (service, socket) = LspService::new(Backend::new);
+    let (compiler, fallback) = compiler_from_args(std::env::args().skip(1));
+    let (service, socket) = LspService::new(|client| Backend::new(client, compiler, fallback));
     Server::new(stdin, stdout, socket).serve(service).await;
 }
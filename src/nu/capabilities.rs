@@ -0,0 +1,238 @@
+use std::{ffi::OsStr, path::Path, time::Duration};
+
+use tokio::time::timeout;
+use tower_lsp::jsonrpc::Result;
+
+use super::IdeCommand;
+use crate::error::{map_err_to_internal_error, map_err_to_parse_error};
+
+/// Which IDE flags a particular `nu` binary understands.
+///
+/// `--ide-*` flags arrived in nushell 0.79 and have changed since,
+/// so we ask each binary rather than assume.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct NuCapabilities {
+    pub ide_ast: bool,
+    pub ide_check: bool,
+    pub ide_complete: bool,
+    pub ide_goto_def: bool,
+    pub ide_hover: bool,
+    pub include_path: bool,
+    /// `nu --lsp`, which stays running to answer one query after another
+    pub lsp: bool,
+    pub version: Option<String>,
+}
+impl NuCapabilities {
+    /// Everything nuls knows how to ask for, e.g. when nushell is linked in-process.
+    pub fn all() -> Self {
+        Self {
+            ide_ast: true,
+            ide_check: true,
+            ide_complete: true,
+            ide_goto_def: true,
+            ide_hover: true,
+            include_path: true,
+            // not a query, and nothing but `Subprocess` would start it
+            lsp: false,
+            version: None,
+        }
+    }
+
+    /// Interprets the output of `nu --version` and `nu --help`.
+    pub fn from_output(version: &str, help: &str) -> Self {
+        // `nu --help` colours each flag, even when piped
+        let help = strip_ansi(help);
+        let has_flag = |flag: &str| {
+            help.split(|c: char| c.is_whitespace() || c == ',')
+                .any(|word| word == flag)
+        };
+        Self {
+            ide_ast: has_flag("--ide-ast"),
+            ide_check: has_flag("--ide-check"),
+            ide_complete: has_flag("--ide-complete"),
+            ide_goto_def: has_flag("--ide-goto-def"),
+            ide_hover: has_flag("--ide-hover"),
+            include_path: has_flag("--include-path"),
+            lsp: has_flag("--lsp"),
+            version: version
+                .split_whitespace()
+                .next()
+                .map(String::from)
+                .filter(|v| v.chars().next().is_some_and(|c| c.is_ascii_digit())),
+        }
+    }
+
+    pub fn supports(&self, command: IdeCommand) -> bool {
+        match command {
+            IdeCommand::Ast => self.ide_ast,
+            IdeCommand::Check => self.ide_check,
+            IdeCommand::Complete(_) => self.ide_complete,
+            IdeCommand::GotoDef(_) => self.ide_goto_def,
+            IdeCommand::Hover(_) => self.ide_hover,
+        }
+    }
+}
+
+/// Runs `nu --version` and `nu --help` to find out what `executable` supports.
+pub(crate) async fn probe(executable: &Path, time_limit: Duration) -> Result<NuCapabilities> {
+    let version = run(executable, &["--version"], time_limit).await?;
+    let help = run(executable, &["--help"], time_limit).await?;
+    Ok(NuCapabilities::from_output(&version, &help))
+}
+
+/// `text` without any ANSI escape sequences, e.g. `\x1b[36m` for cyan.
+pub(crate) fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        match chars.next() {
+            // a control sequence runs until its final byte, e.g. the `m` in `\x1b[0m`
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // an operating system command, e.g. a hyperlink, runs until BEL or ST
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next() == Some('\\')) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    stripped
+}
+
+/// Runs `executable` with `args`, returning what it prints.
+pub(super) async fn run(executable: &Path, args: &[&str], time_limit: Duration) -> Result<String> {
+    let cmdline = format!("{} {}", executable.display(), args.join(" "));
+    let output = timeout(
+        time_limit,
+        tokio::process::Command::new(executable)
+            .args(args.iter().map(OsStr::new))
+            // plain output is easier to read, though `from_output` copes with colour too
+            .env("NO_COLOR", "1")
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .map_err(|e| {
+        map_err_to_internal_error(e, format!("`{cmdline}` timed out after {time_limit:?}"))
+    })?
+    .map_err(|e| map_err_to_internal_error(e, format!("`{cmdline}` failed")))?;
+
+    String::from_utf8(output.stdout)
+        .map_err(|e| map_err_to_parse_error(e, format!("`{cmdline}` did not return valid UTF-8")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_output_with_ide_flags() {
+        let help = "
+Usage:
+  > nu {flags} (script file) ...(script args)
+
+Flags:
+  -h, --help - Display the help message for this command
+  --include-path, -I <String> - set the NU_LIB_DIRS for the given script (semicolon-delimited)
+  --ide-goto-def <Int> - go to the definition of the item at the given position
+  --ide-hover <Int> - give information about the item at the given position
+  --ide-complete <Int> - list completions for the item at the given position
+  --ide-check <Int> - run a diagnostic check on the given source
+  --ide-ast - generate the ast on the given source
+";
+
+        let got = NuCapabilities::from_output("0.85.0\n", help);
+
+        assert_eq!(
+            got,
+            NuCapabilities {
+                version: Some(String::from("0.85.0")),
+                ..NuCapabilities::all()
+            }
+        );
+    }
+
+    #[test]
+    fn from_output_with_coloured_help() {
+        // as nu 0.85 prints it, even when piped
+        let help = "\x1b[32mFlags\x1b[0m:
+  \x1b[36m-h\x1b[0m, \x1b[36m--help\x1b[0m - Display the help message for this command
+  \x1b[36m--include-path\x1b[0m, \x1b[36m-I\x1b[0m <\x1b[34mString\x1b[0m> - set the NU_LIB_DIRS for the given script
+  \x1b[36m--ide-goto-def\x1b[0m <\x1b[34mInt\x1b[0m> - go to the definition of the item at the given position
+  \x1b[36m--ide-hover\x1b[0m <\x1b[34mInt\x1b[0m> - give information about the item at the given position
+  \x1b[36m--ide-complete\x1b[0m <\x1b[34mInt\x1b[0m> - list completions for the item at the given position
+  \x1b[36m--ide-check\x1b[0m <\x1b[34mInt\x1b[0m> - run a diagnostic check on the given source
+  \x1b[36m--ide-ast\x1b[0m - generate the ast on the given source
+";
+
+        let got = NuCapabilities::from_output("0.85.0\n", help);
+
+        assert_eq!(
+            got,
+            NuCapabilities {
+                version: Some(String::from("0.85.0")),
+                ..NuCapabilities::all()
+            }
+        );
+        assert_eq!(
+            strip_ansi("\x1b]8;;https://nushell.sh\x1b\\nu\x1b]8;;\x07 \x1b[1;36m--ide-ast\x1b[0m"),
+            "nu --ide-ast"
+        );
+    }
+
+    #[test]
+    fn from_output_without_ide_flags() {
+        let help = "
+Flags:
+  -h, --help - Display the help message for this command
+  --ide-hover-extra <Int> - not the flag we're looking for
+";
+
+        let got = NuCapabilities::from_output("0.78.0\n", help);
+
+        assert_eq!(
+            got,
+            NuCapabilities {
+                ide_ast: false,
+                ide_check: false,
+                ide_complete: false,
+                ide_goto_def: false,
+                ide_hover: false,
+                include_path: false,
+                lsp: false,
+                version: Some(String::from("0.78.0")),
+            }
+        );
+        assert!(!got.supports(IdeCommand::Hover(0)));
+    }
+
+    #[test]
+    fn from_output_with_lsp() {
+        let help = "
+Flags:
+  -h, --help - Display the help message for this command
+  --lsp - start nu's language server protocol
+  --ide-hover <Int> - give information about the item at the given position
+";
+
+        let got = NuCapabilities::from_output("0.92.0\n", help);
+
+        assert!(got.lsp);
+        assert!(got.ide_hover);
+        assert!(!got.include_path);
+    }
+}
@@ -0,0 +1,433 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tower_lsp::jsonrpc::Result;
+
+use super::capabilities::run;
+use crate::{
+    deserialize::null_as_default,
+    error::map_err_to_parse_error,
+    signature::{Parameter, ParameterKind, Signature},
+};
+
+// everything `nu` can call, including plugins and any commands from its config
+const DUMP: &str = "scope commands | to json --raw";
+
+/// What a particular `nu` knows about its commands, by name.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Catalog {
+    commands: HashMap<String, CatalogCommand>,
+}
+
+/// One row of `scope commands`.
+///
+/// Older versions describe what sort of command it is with `is_*` columns,
+/// newer ones with `command_type`.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub(crate) struct CatalogCommand {
+    #[serde(deserialize_with = "null_as_default")]
+    pub name: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub category: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub usage: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub extra_usage: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub command_type: String,
+    pub is_builtin: bool,
+    pub is_custom: bool,
+    pub is_extern: bool,
+    pub is_keyword: bool,
+    pub is_plugin: bool,
+    /// parameters for each type of input, which only differ in their `input` and `output` rows
+    #[serde(deserialize_with = "null_as_default")]
+    pub signatures: BTreeMap<String, Vec<CatalogParameter>>,
+    #[serde(deserialize_with = "null_as_default")]
+    pub examples: Vec<CatalogExample>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub(crate) struct CatalogParameter {
+    #[serde(deserialize_with = "null_as_default")]
+    pub parameter_name: String,
+    /// e.g. `input`, `positional`, `rest`, `named`, `switch` or `output`
+    #[serde(deserialize_with = "null_as_default")]
+    pub parameter_type: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub syntax_shape: String,
+    pub is_optional: bool,
+    #[serde(deserialize_with = "null_as_default")]
+    pub short_flag: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub description: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub(crate) struct CatalogExample {
+    #[serde(deserialize_with = "null_as_default")]
+    pub description: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub example: String,
+}
+
+impl Catalog {
+    /// Reads `scope commands | to json`, or a catalog previously saved with [`Catalog::to_json`].
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        let commands: Vec<CatalogCommand> = serde_json::from_str(json)?;
+        Ok(Self {
+            commands: commands.into_iter().map(|c| (c.name.clone(), c)).collect(),
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        let mut commands: Vec<&CatalogCommand> = self.commands.values().collect();
+        commands.sort_by(|a, b| a.name.cmp(&b.name));
+        serde_json::to_string(&commands)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CatalogCommand> {
+        self.commands.get(name)
+    }
+
+    /// Whether there's nothing in it, e.g. because `nu` couldn't dump its commands.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// The command named by the most leading `words`, using no more than `limit` of them,
+    /// e.g. `str join` rather than `str` for `str join ","`.
+    pub fn find(&self, words: &[String], limit: usize) -> Option<&CatalogCommand> {
+        (1..=limit.min(words.len()))
+            .rev()
+            .find_map(|n| self.get(&words[..n].join(" ")))
+    }
+}
+
+impl CatalogCommand {
+    /// e.g. `built-in`, `keyword`, `plugin`, `custom`, `alias` or `external`,
+    /// however this version of `nu` says so.
+    pub fn kind(&self) -> &str {
+        if !self.command_type.is_empty() {
+            return &self.command_type;
+        }
+        if self.is_keyword {
+            "keyword"
+        } else if self.is_plugin {
+            "plugin"
+        } else if self.is_custom {
+            "custom"
+        } else if self.is_extern {
+            "external"
+        } else {
+            "built-in"
+        }
+    }
+
+    /// Everything `help` would say about this command, in Markdown.
+    pub fn to_markdown(&self) -> String {
+        let mut sections = vec![self.usage.clone()];
+        if !self.extra_usage.is_empty() {
+            sections.push(self.extra_usage.clone());
+        }
+        if !self.examples.is_empty() {
+            sections.push(String::from("### Examples"));
+        }
+        sections.extend(
+            self.examples
+                .iter()
+                .map(|e| format!("{}\n```nu\n{}\n```", e.description, e.example)),
+        );
+        sections.join("\n\n")
+    }
+
+    pub fn to_signature(&self) -> Signature {
+        let description = if self.extra_usage.is_empty() {
+            self.usage.clone()
+        } else {
+            format!("{}\n\n{}", self.usage, self.extra_usage)
+        };
+        let (mut positionals, mut flags) = (vec![], vec![]);
+        for parameter in self.signatures.values().next().into_iter().flatten() {
+            let shape = Some(parameter.syntax_shape.clone()).filter(|s| !s.is_empty());
+            let (kind, shape) = match parameter.parameter_type.as_str() {
+                "positional" if parameter.is_optional => (ParameterKind::Optional, shape),
+                "positional" => (ParameterKind::Required, shape),
+                "rest" => (ParameterKind::Rest, shape),
+                "named" | "switch" => (
+                    ParameterKind::Flag {
+                        short: parameter.short_flag.chars().next(),
+                    },
+                    shape.filter(|_| parameter.parameter_type == "named"),
+                ),
+                // `input` and `output`
+                _ => continue,
+            };
+            let parameter = Parameter {
+                name: parameter.parameter_name.clone(),
+                kind,
+                shape,
+                description: parameter.description.clone(),
+            };
+            if matches!(kind, ParameterKind::Flag { .. }) {
+                flags.push(parameter);
+            } else {
+                positionals.push(parameter);
+            }
+        }
+        positionals.extend(flags);
+        Signature {
+            name: self.name.clone(),
+            description,
+            parameters: positionals,
+        }
+    }
+}
+
+/// Dumps `scope commands` from `executable`,
+/// or reads the dump cached on disk for the same version of `nu` with the same plugins.
+///
+/// Saving a new dump removes any others for the same version, as their plugins have since changed.
+pub(crate) async fn load(
+    executable: &Path,
+    version: Option<&str>,
+    time_limit: Duration,
+) -> Result<Catalog> {
+    // without a version, there's no telling when the cache would be stale
+    let cached = match version {
+        Some(version) => {
+            let plugins = plugins(executable, time_limit).await;
+            Some(cache_path(&cache_dir(), version, &plugins))
+        }
+        None => None,
+    };
+    if let Some(path) = &cached {
+        let catalog = tokio::fs::read_to_string(path)
+            .await
+            .ok()
+            .and_then(|json| Catalog::from_json(&json).ok());
+        if let Some(catalog) = catalog {
+            return Ok(catalog);
+        }
+    }
+
+    let json = run(executable, &["--commands", DUMP], time_limit).await?;
+    let catalog = Catalog::from_json(&json)
+        .map_err(|e| map_err_to_parse_error(e, format!("cannot read `{DUMP}` output")))?;
+    if let Some(path) = &cached {
+        // only an optimisation, so no need to fail over it
+        save(path, &catalog).await.ok();
+    }
+    Ok(catalog)
+}
+
+/// Which plugins `executable` registers, as the path and contents of its plugin file, if any.
+async fn plugins(executable: &Path, time_limit: Duration) -> Vec<u8> {
+    let Ok(path) = run(executable, &["--commands", "$nu.plugin-path"], time_limit).await else {
+        return vec![];
+    };
+    let path = path.trim();
+    let mut plugins = path.as_bytes().to_vec();
+    plugins.extend(tokio::fs::read(path).await.unwrap_or_default());
+    plugins
+}
+
+fn cache_dir() -> PathBuf {
+    let var = |name| std::env::var_os(name).map(PathBuf::from);
+    var("XDG_CACHE_HOME")
+        .or_else(|| var("LOCALAPPDATA"))
+        .or_else(|| var("HOME").map(|home| home.join(".cache")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join(env!("CARGO_PKG_NAME"))
+}
+
+/// Where the catalog for this `version` and these `plugins` is kept, within `dir`.
+fn cache_path(dir: &Path, version: &str, plugins: &[u8]) -> PathBuf {
+    dir.join(format!(
+        "{}{:016x}.json",
+        cache_prefix(version),
+        fnv1a(plugins)
+    ))
+}
+
+/// What the names of every catalog cached for this `version` start with.
+fn cache_prefix(version: &str) -> String {
+    let version: String = version
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("catalog-{version}-")
+}
+
+/// 64-bit FNV-1a, which (unlike `DefaultHasher`) gives the same result from one build of nuls to the next.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+async fn save(path: &Path, catalog: &Catalog) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    // renamed into place, so another nuls never reads half a file
+    let partial = path.with_extension(format!("{}.tmp", std::process::id()));
+    tokio::fs::write(&partial, catalog.to_json()?).await?;
+    tokio::fs::rename(&partial, path).await?;
+    remove_stale(path).await;
+    Ok(())
+}
+
+/// Removes catalogs cached for the same version as `path`, with different plugins.
+async fn remove_stale(path: &Path) {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return;
+    };
+    let name = name.to_string_lossy();
+    let Some(prefix) = name.rfind('-').map(|dash| &name[..=dash]) else {
+        return;
+    };
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let other = entry.file_name();
+        let other = other.to_string_lossy();
+        if other != name
+            && other
+                .strip_prefix(prefix)
+                .and_then(|rest| rest.strip_suffix(".json"))
+                .is_some_and(|hash| hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        {
+            let _ = tokio::fs::remove_file(entry.path()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // trimmed from `scope commands | to json` with nu 0.85
+    const SCOPE_COMMANDS: &str = r#"[
+        {
+            "name": "str join",
+            "category": "strings",
+            "usage": "Concatenate multiple strings into a single string.",
+            "extra_usage": "",
+            "is_builtin": true,
+            "is_custom": false,
+            "signatures": {
+                "list<string>": [
+                    { "parameter_name": "", "parameter_type": "input", "syntax_shape": "list<string>", "is_optional": false, "short_flag": null, "description": null },
+                    { "parameter_name": "separator", "parameter_type": "positional", "syntax_shape": "string", "is_optional": true, "short_flag": null, "description": "optional separator" },
+                    { "parameter_name": "help", "parameter_type": "switch", "syntax_shape": null, "is_optional": true, "short_flag": "h", "description": "Display the help message for this command" },
+                    { "parameter_name": "max", "parameter_type": "named", "syntax_shape": "int", "is_optional": true, "short_flag": "", "description": "" },
+                    { "parameter_name": "", "parameter_type": "output", "syntax_shape": "string", "is_optional": false, "short_flag": null, "description": null }
+                ]
+            },
+            "examples": [{ "description": "Join", "example": "['a' 'b'] | str join", "result": "ab" }],
+            "is_sub": true
+        },
+        { "name": "if", "command_type": "keyword", "signatures": null, "examples": null }
+    ]"#;
+
+    #[test]
+    fn from_json_reads_scope_commands() {
+        let got = Catalog::from_json(SCOPE_COMMANDS).expect("should parse");
+
+        let join = got.get("str join").expect("should have `str join`");
+        assert!(join.is_builtin);
+        assert_eq!(join.kind(), "built-in");
+        assert_eq!(join.examples[0].example, "['a' 'b'] | str join");
+        assert_eq!(
+            join.to_markdown(),
+            "Concatenate multiple strings into a single string.\n\n### Examples\n\nJoin\n```nu\n['a' 'b'] | str join\n```"
+        );
+        assert_eq!(got.get("if").map(CatalogCommand::kind), Some("keyword"));
+
+        let words =
+            |words: &[&str]| -> Vec<String> { words.iter().map(|w| String::from(*w)).collect() };
+        assert_eq!(
+            got.find(&words(&["str", "join", "str"]), 3)
+                .map(|c| c.name.as_str()),
+            Some("str join")
+        );
+        assert!(got.find(&words(&["str", "join"]), 1).is_none());
+    }
+
+    #[test]
+    fn to_signature_puts_flags_last() {
+        let catalog = Catalog::from_json(SCOPE_COMMANDS).expect("should parse");
+
+        let got = catalog
+            .get("str join")
+            .map(CatalogCommand::to_signature)
+            .expect("should have `str join`");
+
+        assert_eq!(
+            got.description,
+            "Concatenate multiple strings into a single string."
+        );
+        let labels: Vec<String> = got.parameters.iter().map(Parameter::label).collect();
+        assert_eq!(
+            labels,
+            vec!["<separator?: string>", "--help(-h)", "--max <int>"]
+        );
+    }
+
+    #[tokio::test]
+    async fn saved_catalog_is_read_back() {
+        let dir = mktemp::Temp::new_dir().expect("should create temporary directory");
+        let catalog = Catalog::from_json(SCOPE_COMMANDS).expect("should parse");
+        let path = cache_path(dir.as_ref(), "0.85.0", b"plugin.nu");
+
+        save(&path, &catalog).await.expect("should save");
+        let json = tokio::fs::read_to_string(&path).await.expect("should read");
+        let got = Catalog::from_json(&json).expect("should parse");
+
+        assert_eq!(got.get("str join"), catalog.get("str join"));
+        assert_ne!(path, cache_path(dir.as_ref(), "0.85.0", b"other-plugin.nu"));
+        assert_ne!(path, cache_path(dir.as_ref(), "0.86.0", b"plugin.nu"));
+    }
+
+    #[test]
+    fn cache_path_is_stable() {
+        assert_eq!(
+            cache_path(Path::new("/cache"), "0.85.0", b"a"),
+            PathBuf::from("/cache/catalog-0.85.0-af63dc4c8601ec8c.json")
+        );
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+    }
+
+    #[tokio::test]
+    async fn saving_removes_catalogs_for_old_plugins() {
+        let dir = mktemp::Temp::new_dir().expect("should create temporary directory");
+        let catalog = Catalog::from_json(SCOPE_COMMANDS).expect("should parse");
+        let old = cache_path(dir.as_ref(), "0.85.0", b"old-plugin.nu");
+        let other_version = cache_path(dir.as_ref(), "0.86.0", b"old-plugin.nu");
+        save(&old, &catalog).await.expect("should save");
+        save(&other_version, &catalog).await.expect("should save");
+
+        let new = cache_path(dir.as_ref(), "0.85.0", b"plugin.nu");
+        save(&new, &catalog).await.expect("should save");
+
+        assert!(new.exists());
+        assert!(!old.exists());
+        assert!(other_version.exists());
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tokio::io::BufReader;
+use tokio_util::sync::CancellationToken;
+use tower_lsp::{jsonrpc::Result, lsp_types::Url};
+
+use super::{
+    worker::{read_message, write_message, LspWorker},
+    Catalog, Compiler, CompilerResponse, IdeCommand, IdeSettings, NuCapabilities,
+};
+
+/// Replays canned `nu --ide-*` output, so the backend can be tested without nushell installed.
+///
+/// Clones share the same script, so a test can keep one to inspect calls after handing another to the backend.
+#[derive(Clone, Default)]
+pub(crate) struct ScriptedCompiler {
+    script: Arc<Mutex<Script>>,
+}
+
+#[derive(Default)]
+struct Script {
+    calls: Vec<IdeCommand>,
+    // the document and settings as they were for each call
+    settings: Vec<IdeSettings>,
+    texts: Vec<String>,
+    capabilities: Option<NuCapabilities>,
+    catalog: Catalog,
+    delay: Duration,
+    responses: HashMap<&'static str, std::result::Result<ScriptedResponse, String>>,
+}
+
+#[derive(Clone, Default)]
+struct ScriptedResponse {
+    exit_code: i32,
+    stderr: String,
+    stdout: String,
+}
+
+impl ScriptedCompiler {
+    /// Every query sharing the same `--ide-*` flag as `command` will print `stdout`.
+    pub fn respond(self, command: IdeCommand, stdout: impl Into<String>) -> Self {
+        self.insert(
+            command,
+            Ok(ScriptedResponse {
+                stdout: stdout.into(),
+                ..ScriptedResponse::default()
+            }),
+        )
+    }
+
+    /// Every query sharing the same `--ide-*` flag as `command` will exit early, like a panicking `nu` would.
+    pub fn crash(self, command: IdeCommand, exit_code: i32, stderr: impl Into<String>) -> Self {
+        self.insert(
+            command,
+            Ok(ScriptedResponse {
+                exit_code,
+                stderr: stderr.into(),
+                ..ScriptedResponse::default()
+            }),
+        )
+    }
+
+    /// Every query sharing the same `--ide-*` flag as `command` won't produce any output at all,
+    /// like a `nu` that could not be spawned or timed out.
+    pub fn fail(self, command: IdeCommand, message: impl Into<String>) -> Self {
+        self.insert(command, Err(message.into()))
+    }
+
+    fn insert(
+        self,
+        command: IdeCommand,
+        response: std::result::Result<ScriptedResponse, String>,
+    ) -> Self {
+        self.script
+            .lock()
+            .expect("script should not be poisoned")
+            .responses
+            .insert(command.flag(), response);
+        self
+    }
+
+    /// Pretend to be a `nu` with only these capabilities.
+    pub fn capabilities(self, capabilities: NuCapabilities) -> Self {
+        self.script
+            .lock()
+            .expect("script should not be poisoned")
+            .capabilities = Some(capabilities);
+        self
+    }
+
+    /// Pretend to be a `nu` whose `scope commands` prints `json`.
+    pub fn catalog(self, json: &str) -> Self {
+        self.script
+            .lock()
+            .expect("script should not be poisoned")
+            .catalog = Catalog::from_json(json).expect("catalog should parse");
+        self
+    }
+
+    /// Every query will take this long to answer, like a slow `nu` would.
+    pub fn delay(self, delay: Duration) -> Self {
+        self.script
+            .lock()
+            .expect("script should not be poisoned")
+            .delay = delay;
+        self
+    }
+
+    /// Where every document is pretend-copied to before `nu` reads it.
+    pub fn source_path() -> PathBuf {
+        std::env::temp_dir().join("nuls-scripted.nu")
+    }
+
+    pub fn calls(&self) -> Vec<IdeCommand> {
+        self.script
+            .lock()
+            .expect("script should not be poisoned")
+            .calls
+            .clone()
+    }
+
+    pub fn settings(&self) -> Vec<IdeSettings> {
+        self.script
+            .lock()
+            .expect("script should not be poisoned")
+            .settings
+            .clone()
+    }
+
+    pub fn texts(&self) -> Vec<String> {
+        self.script
+            .lock()
+            .expect("script should not be poisoned")
+            .texts
+            .clone()
+    }
+}
+
+#[tower_lsp::async_trait]
+impl Compiler for ScriptedCompiler {
+    async fn capabilities(&self, _settings: &IdeSettings) -> Result<NuCapabilities> {
+        let script = self.script.lock().expect("script should not be poisoned");
+        Ok(script
+            .capabilities
+            .clone()
+            .unwrap_or_else(NuCapabilities::all))
+    }
+
+    async fn catalog(&self, _settings: &IdeSettings) -> Result<Catalog> {
+        let script = self.script.lock().expect("script should not be poisoned");
+        Ok(script.catalog.clone())
+    }
+
+    async fn run(
+        &self,
+        text: &str,
+        command: IdeCommand,
+        settings: IdeSettings,
+        _uri: &Url,
+        cancel: &CancellationToken,
+    ) -> Result<CompilerResponse> {
+        let delay = {
+            let mut script = self.script.lock().expect("script should not be poisoned");
+            script.calls.push(command);
+            script.settings.push(settings);
+            script.texts.push(String::from(text));
+            script.delay
+        };
+        tokio::select! {
+            () = cancel.cancelled() => return Err(tower_lsp::jsonrpc::Error::request_cancelled()),
+            () = tokio::time::sleep(delay) => {},
+        }
+
+        let script = self.script.lock().expect("script should not be poisoned");
+
+        let cmdline = format!("nu {}", command.flag());
+        let response = script
+            .responses
+            .get(command.flag())
+            .cloned()
+            .ok_or_else(|| {
+                tower_lsp::jsonrpc::Error::invalid_params(format!(
+                    "no scripted response for {cmdline}"
+                ))
+            })?
+            .map_err(|message| {
+                let mut err = tower_lsp::jsonrpc::Error::internal_error();
+                err.message = Cow::from(message);
+                err
+            })?;
+        Ok(CompilerResponse {
+            cmdline,
+            exit_code: Some(response.exit_code),
+            source_path: Some(Self::source_path()),
+            stderr: response.stderr,
+            stdout: response.stdout,
+        })
+    }
+}
+
+/// Connects a worker to a stand-in for `nu --lsp`, which answers each message it reads with `answer`,
+/// or hangs up (like a crashing `nu` would) when `answer` returns `None`.
+pub(crate) async fn lsp_worker(
+    answer: impl Fn(&Value) -> Option<Vec<Value>> + Send + 'static,
+) -> io::Result<LspWorker> {
+    let (ours, theirs) = tokio::io::duplex(1 << 16);
+    let (reader, mut writer) = tokio::io::split(theirs);
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        while let Ok(message) = read_message(&mut reader).await {
+            let Some(replies) = answer(&message) else {
+                return;
+            };
+            for reply in replies {
+                if write_message(&mut writer, &reply).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    let (reader, writer) = tokio::io::split(ours);
+    LspWorker::connect(reader, writer, None).await
+}
+
+/// The response to `request`, or nothing if it's a notification, as `nu --lsp` would answer.
+pub(crate) fn lsp_reply(request: &Value, result: Value) -> Vec<Value> {
+    let Some(id) = request.get("id") else {
+        return vec![];
+    };
+    let mut reply = json!({ "jsonrpc": "2.0", "id": id });
+    reply["result"] = result;
+    vec![reply]
+}
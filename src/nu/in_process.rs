@@ -0,0 +1,329 @@
+// ported from the `nu --ide-*` implementation in https://github.com/nushell/nushell (crates/nu-cli/src/ide.rs),
+// but returning the same JSON instead of printing it, so responses parse exactly as they do from a subprocess
+
+use std::{path::Path, sync::Arc};
+
+use nu_cli::NuCompleter;
+use nu_parser::{flatten_block, parse, FlatShape};
+use nu_protocol::{
+    engine::{EngineState, Stack, StateWorkingSet},
+    DeclId, Span, Value, VarId,
+};
+use reedline::Completer;
+use serde_json::json;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tower_lsp::{jsonrpc::Result, lsp_types::Url};
+
+use super::{include_paths, Compiler, CompilerResponse, IdeCommand, IdeSettings};
+use crate::error::map_err_to_internal_error;
+
+pub(crate) struct InProcessEngine {
+    // built once, then cloned per query so per-document environment doesn't leak between queries
+    base: Arc<EngineState>,
+}
+
+impl InProcessEngine {
+    pub fn new() -> Self {
+        let engine_state = nu_cmd_lang::create_default_context();
+        let engine_state = nu_command::add_shell_command_context(engine_state);
+        Self {
+            base: Arc::new(engine_state),
+        }
+    }
+}
+
+#[tower_lsp::async_trait]
+impl Compiler for InProcessEngine {
+    async fn run(
+        &self,
+        text: &str,
+        command: IdeCommand,
+        settings: IdeSettings,
+        uri: &Url,
+        cancel: &CancellationToken,
+    ) -> Result<CompilerResponse> {
+        let cmdline = format!("nu-parser {} {uri}", command.flag());
+
+        let mut engine_state = EngineState::clone(&self.base);
+        let include_paths = include_paths(&settings, uri)?;
+        if !include_paths.is_empty() {
+            let span = Span::unknown();
+            let dirs = include_paths
+                .iter()
+                .map(|p| Value::string(p.to_string_lossy(), span))
+                .collect();
+            engine_state.add_env_var(String::from("NU_LIB_DIRS"), Value::list(dirs, span));
+        }
+
+        // same as `nu script.nu` run from the script's directory
+        if let Some(dir) = uri
+            .to_file_path()
+            .ok()
+            .and_then(|p| p.parent().map(Path::to_path_buf))
+        {
+            let span = Span::unknown();
+            let dir = Value::string(dir.to_string_lossy(), span);
+            engine_state.add_env_var(String::from("PWD"), dir.clone());
+            engine_state.add_env_var(String::from("FILE_PWD"), dir);
+        }
+        let file_path = uri
+            .to_file_path()
+            .map_or_else(|()| uri.to_string(), |p| p.to_string_lossy().into_owned());
+        engine_state.add_env_var(
+            String::from("CURRENT_FILE"),
+            Value::string(&file_path, Span::unknown()),
+        );
+        let contents = text.as_bytes().to_vec();
+        let max_number_of_problems = settings.max_number_of_problems as usize;
+
+        // parsing is CPU-bound, so keep it off the async workers
+        let task = tokio::task::spawn_blocking(move || match command {
+            IdeCommand::Ast => ast(&engine_state, &file_path, &contents),
+            IdeCommand::Check => {
+                check(&engine_state, &file_path, &contents, max_number_of_problems)
+            }
+            IdeCommand::Complete(location) => complete(engine_state, &contents, location as usize),
+            IdeCommand::GotoDef(location) => {
+                goto_def(&engine_state, &file_path, &contents, location as usize)
+            }
+            IdeCommand::Hover(location) => {
+                hover(&engine_state, &file_path, &contents, location as usize)
+            }
+        });
+
+        // the parser can't be interrupted part-way, but there's no need to wait for a result nobody wants
+        let running = timeout(settings.max_nushell_invocation_time, task);
+        let stdout = tokio::select! {
+            biased;
+            () = cancel.cancelled() => return Err(tower_lsp::jsonrpc::Error::request_cancelled()),
+            output = running => output,
+        }
+        .map_err(|e| {
+            map_err_to_internal_error(
+                e,
+                format!(
+                    "`{cmdline}` timed out after {:?}",
+                    &settings.max_nushell_invocation_time
+                ),
+            )
+        })?
+        .map_err(|e| map_err_to_internal_error(e, format!("`{cmdline}` failed")))?;
+
+        // parser errors are reported in `stdout`, same as they are by `nu`
+        Ok(CompilerResponse {
+            cmdline,
+            exit_code: Some(0),
+            source_path: uri.to_file_path().ok(),
+            stderr: String::new(),
+            stdout,
+        })
+    }
+}
+
+enum Id {
+    Declaration(DeclId),
+    Value(FlatShape),
+    Variable(VarId),
+}
+
+fn ast(engine_state: &EngineState, file_path: &str, contents: &[u8]) -> String {
+    let mut working_set = StateWorkingSet::new(engine_state);
+    let offset = working_set.next_span_start();
+    let block = parse(&mut working_set, Some(file_path), contents, false);
+    let within_file = |span: Span| span.start >= offset && span.end <= offset + contents.len();
+
+    let items: Vec<serde_json::Value> = flatten_block(&working_set, &block)
+        .into_iter()
+        .filter(|(span, _)| within_file(*span))
+        .map(|(span, shape)| {
+            json!({
+                "content": String::from_utf8_lossy(working_set.get_span_contents(span)),
+                "shape": shape.to_string(),
+                "span": { "end": span.end - offset, "start": span.start - offset },
+                "type": "ast",
+            })
+        })
+        .collect();
+    serde_json::Value::from(items).to_string()
+}
+
+fn check(
+    engine_state: &EngineState,
+    file_path: &str,
+    contents: &[u8],
+    max_number_of_problems: usize,
+) -> String {
+    let mut working_set = StateWorkingSet::new(engine_state);
+    let offset = working_set.next_span_start();
+    let block = parse(&mut working_set, Some(file_path), contents, false);
+    let within_file = |span: Span| span.start >= offset && span.end <= offset + contents.len();
+
+    let mut lines: Vec<String> = vec![];
+    for err in working_set
+        .parse_errors
+        .iter()
+        .filter(|e| within_file(e.span()))
+        .take(max_number_of_problems)
+    {
+        let span = err.span();
+        lines.push(
+            json!({
+                "message": err.to_string(),
+                "severity": "Error",
+                "span": { "end": span.end - offset, "start": span.start - offset },
+                "type": "diagnostic",
+            })
+            .to_string(),
+        );
+    }
+
+    for (span, shape) in flatten_block(&working_set, &block) {
+        if let FlatShape::VarDecl(var_id) = shape {
+            if !within_file(span) {
+                continue;
+            }
+            let var = working_set.get_variable(var_id);
+            lines.push(
+                json!({
+                    "position": { "end": span.end - offset, "start": span.start - offset },
+                    "type": "hint",
+                    "typename": var.ty.to_string(),
+                })
+                .to_string(),
+            );
+        }
+    }
+
+    lines.join("\n")
+}
+
+fn complete(engine_state: EngineState, contents: &[u8], location: usize) -> String {
+    let mut completer = NuCompleter::new(Arc::new(engine_state), Stack::new());
+    let text = String::from_utf8_lossy(contents);
+    let line = text.get(..location).unwrap_or(&text);
+
+    let completions: Vec<String> = completer
+        .complete(line, location)
+        .into_iter()
+        .map(|s| s.value)
+        .collect();
+
+    json!({ "completions": completions }).to_string()
+}
+
+fn find_id(
+    working_set: &mut StateWorkingSet,
+    file_path: &str,
+    contents: &[u8],
+    location: usize,
+) -> Option<(Id, usize, Span)> {
+    let offset = working_set.next_span_start();
+    let block = parse(working_set, Some(file_path), contents, false);
+    let location = location + offset;
+
+    flatten_block(working_set, &block)
+        .into_iter()
+        .find(|(span, _)| location >= span.start && location < span.end)
+        .map(|(span, shape)| {
+            let id = match shape {
+                FlatShape::InternalCall(decl_id) => Id::Declaration(decl_id),
+                FlatShape::Variable(var_id) | FlatShape::VarDecl(var_id) => Id::Variable(var_id),
+                _ => Id::Value(shape),
+            };
+            (id, offset, span)
+        })
+}
+
+fn goto_def(
+    engine_state: &EngineState,
+    file_path: &str,
+    contents: &[u8],
+    location: usize,
+) -> String {
+    let mut working_set = StateWorkingSet::new(engine_state);
+
+    let target = match find_id(&mut working_set, file_path, contents, location) {
+        Some((Id::Declaration(decl_id), _, _)) => {
+            let decl = working_set.get_decl(decl_id);
+            decl.get_block_id()
+                .and_then(|block_id| working_set.get_block(block_id).span)
+        }
+        Some((Id::Variable(var_id), _, _)) => {
+            Some(working_set.get_variable(var_id).declaration_span)
+        }
+        Some((Id::Value(_), _, _)) | None => None,
+    };
+
+    if let Some(span) = target {
+        for (file, start, end) in working_set.files() {
+            if span.start >= *start && span.start < *end {
+                return json!({
+                    "end": span.end - start,
+                    "file": file,
+                    "start": span.start - start,
+                })
+                .to_string();
+            }
+        }
+    }
+
+    String::from("{}")
+}
+
+fn hover(engine_state: &EngineState, file_path: &str, contents: &[u8], location: usize) -> String {
+    let mut working_set = StateWorkingSet::new(engine_state);
+
+    let Some((id, offset, span)) = find_id(&mut working_set, file_path, contents, location) else {
+        return String::from("{}");
+    };
+
+    let description = match id {
+        Id::Declaration(decl_id) => {
+            let decl = working_set.get_decl(decl_id);
+            let signature = decl.signature();
+
+            let mut description = format!("{}\n", decl.usage());
+            if !decl.extra_usage().is_empty() {
+                description.push_str(&format!("\n{}\n", decl.extra_usage()));
+            }
+
+            description.push_str(&format!("### Usage\n```\n  {}", signature.name));
+            if !signature.named.is_empty() {
+                description.push_str(" {flags}");
+            }
+            for arg in &signature.required_positional {
+                description.push_str(&format!(" <{}>", arg.name));
+            }
+            for arg in &signature.optional_positional {
+                description.push_str(&format!(" <{}?>", arg.name));
+            }
+            if let Some(arg) = &signature.rest_positional {
+                description.push_str(&format!(" <...{}>", arg.name));
+            }
+            description.push_str("\n```\n");
+
+            if !signature.named.is_empty() {
+                description.push_str("\n### Flags\n\n");
+                for flag in &signature.named {
+                    let short = flag.short.map(|c| format!("`-{c}`, ")).unwrap_or_default();
+                    description
+                        .push_str(&format!("  {short}`--{}` - {}\n\n", flag.long, flag.desc));
+                }
+            }
+            description
+        }
+        Id::Variable(var_id) => {
+            let var = working_set.get_variable(var_id);
+            let mutable = if var.mutable { "mutable " } else { "" };
+            format!("{mutable}{}", var.ty)
+        }
+        Id::Value(shape) => shape.to_string(),
+    };
+
+    json!({
+        "hover": description,
+        "span": { "end": span.end - offset, "start": span.start - offset },
+    })
+    .to_string()
+}
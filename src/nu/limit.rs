@@ -0,0 +1,112 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tower_lsp::jsonrpc::Result;
+
+use crate::error::map_err_to_internal_error;
+
+// upper bound for the default limit,
+// `nu` is memory-hungry enough that we don't want one per core on large machines
+const MAX_DEFAULT_PROCESSES: usize = 4;
+
+/// Caps how many `nu` processes answer queries at once, and lets shutdown wait for those still answering.
+///
+/// This stops a burst of queries from starting more than the machine can handle,
+/// whether each query starts its own `nu --ide-*` or borrows a `nu --lsp` from the [`super::pool::WorkerPool`].
+pub(crate) struct ProcessLimit {
+    crashes: AtomicUsize,
+    permits: Semaphore,
+    size: u32,
+}
+
+impl ProcessLimit {
+    pub fn new(size: NonZeroUsize) -> Self {
+        let size = u32::try_from(size.get()).unwrap_or(u32::MAX);
+        Self {
+            crashes: AtomicUsize::new(0),
+            permits: Semaphore::new(size as usize),
+            size,
+        }
+    }
+
+    /// Waits until fewer than the limit are running, and holds a place until the permit is dropped.
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>> {
+        self.permits
+            .acquire()
+            .await
+            .map_err(|e| map_err_to_internal_error(e, String::from("nu has shut down")))
+    }
+
+    /// How many `nu` processes may answer queries at once.
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Counts a `nu` process that crashed or hung.
+    pub fn record_crash(&self) {
+        self.crashes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How many `nu` processes crashed or hung.
+    pub fn crashes(&self) -> usize {
+        self.crashes.load(Ordering::Relaxed)
+    }
+
+    /// Waits for running processes to finish, then refuses to start any more.
+    pub async fn shutdown(&self) {
+        if let Ok(permits) = self.permits.acquire_many(self.size).await {
+            permits.forget();
+        }
+        self.permits.close();
+    }
+}
+
+impl Default for ProcessLimit {
+    fn default() -> Self {
+        let size = std::thread::available_parallelism()
+            .map_or(1, NonZeroUsize::get)
+            .min(MAX_DEFAULT_PROCESSES);
+        Self::new(NonZeroUsize::new(size).unwrap_or(NonZeroUsize::MIN))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn acquire_waits_for_a_free_place() {
+        let limit = ProcessLimit::new(NonZeroUsize::MIN);
+        let permit = limit.acquire().await.expect("should acquire a permit");
+
+        let blocked = timeout(Duration::from_millis(50), limit.acquire()).await;
+        assert!(blocked.is_err(), "second acquire should wait");
+
+        drop(permit);
+        let _permit = timeout(Duration::from_millis(50), limit.acquire())
+            .await
+            .expect("second acquire should proceed")
+            .expect("should acquire a permit");
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_then_refuses() {
+        let limit = ProcessLimit::new(NonZeroUsize::MIN);
+        let permit = limit.acquire().await.expect("should acquire a permit");
+
+        let draining = timeout(Duration::from_millis(50), limit.shutdown()).await;
+        assert!(
+            draining.is_err(),
+            "shutdown should wait for running processes"
+        );
+
+        drop(permit);
+        limit.shutdown().await;
+        assert!(limit.acquire().await.is_err());
+    }
+}
@@ -0,0 +1,842 @@
+use std::{path::PathBuf, time::Duration};
+
+use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
+use tower_lsp::lsp_types::{DiagnosticSeverity, InlayHint, InlayHintKind, Url};
+use tower_lsp::{jsonrpc::Result, lsp_types::Diagnostic};
+
+use crate::{
+    error::map_err_to_parse_error,
+    offsets::LineIndex,
+    signature::{Parameter, ParameterKind, Signature},
+};
+
+pub(crate) mod capabilities;
+pub(crate) mod catalog;
+#[cfg(test)]
+pub(crate) mod fake;
+#[cfg(feature = "in-process")]
+mod in_process;
+mod limit;
+mod pool;
+mod shadow;
+mod subprocess;
+mod worker;
+use capabilities::NuCapabilities;
+use catalog::Catalog;
+pub(crate) use subprocess::Subprocess;
+
+/// One span of the flattened AST, as `nu --ide-ast` lists them.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) struct IdeAst {
+    pub content: String,
+    /// e.g. `shape_internalcall`
+    pub shape: String,
+    pub span: IdeSpan,
+}
+impl IdeAst {
+    pub fn from_compiler_response(value: &CompilerResponse) -> Result<Vec<Self>> {
+        serde_json::from_slice(value.stdout.as_bytes()).map_err(|e| value.parse_error(e))
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase", tag = "type")]
+pub(crate) enum IdeCheck {
+    Diagnostic(IdeCheckDiagnostic),
+    Hint(IdeCheckHint),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) struct IdeCheckDiagnostic {
+    pub message: String,
+    pub severity: IdeDiagnosticSeverity,
+    pub span: IdeSpan,
+}
+impl IdeCheckDiagnostic {
+    pub fn to_diagnostic(&self, index: &LineIndex, uri: &Url) -> Diagnostic {
+        Diagnostic {
+            message: self.message.clone(),
+            range: index.range(self.span.start, self.span.end),
+            severity: Some(DiagnosticSeverity::from(&self.severity)),
+            source: Some(String::from(uri.clone())),
+            ..Diagnostic::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) struct IdeCheckHint {
+    pub position: IdeSpan,
+    pub typename: String,
+}
+impl IdeCheckHint {
+    pub fn to_inlay_hint(&self, index: &LineIndex) -> InlayHint {
+        InlayHint {
+            position: index.position(self.position.end),
+            label: tower_lsp::lsp_types::InlayHintLabel::String(format!(": {}", &self.typename)),
+            kind: Some(InlayHintKind::TYPE),
+            text_edits: None,
+            tooltip: None,
+            padding_left: None,
+            padding_right: None,
+            data: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct IdeCheckResponse {
+    pub diagnostics: Vec<IdeCheckDiagnostic>,
+    pub inlay_hints: Vec<IdeCheckHint>,
+}
+impl IdeCheckResponse {
+    pub fn from_compiler_response(value: &CompilerResponse) -> Self {
+        let ide_checks: Vec<IdeCheck> = value
+            .stdout
+            .lines()
+            .filter_map(|l| serde_json::from_slice(l.as_bytes()).ok())
+            .collect();
+
+        let diagnostics = ide_checks
+            .iter()
+            .filter_map(|c| match c {
+                IdeCheck::Diagnostic(d) => Some(d),
+                IdeCheck::Hint(_) => None,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let inlay_hints = ide_checks
+            .iter()
+            .filter_map(|c| match c {
+                IdeCheck::Diagnostic(_) => None,
+                IdeCheck::Hint(h) => Some(h),
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        Self {
+            diagnostics,
+            inlay_hints,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IdeComplete {
+    pub completions: Vec<IdeSuggestion>,
+}
+impl TryFrom<CompilerResponse> for IdeComplete {
+    type Error = tower_lsp::jsonrpc::Error;
+
+    fn try_from(value: CompilerResponse) -> std::result::Result<Self, Self::Error> {
+        serde_json::from_slice(value.stdout.as_bytes()).map_err(|e| value.parse_error(e))
+    }
+}
+
+/// Either just the text to insert (as nu 0.85 prints it),
+/// or that along with the span of the text it replaces, where nu reports one.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub(crate) enum IdeSuggestion {
+    Value(String),
+    Spanned { value: String, span: IdeSpan },
+}
+impl IdeSuggestion {
+    pub fn into_parts(self) -> (String, Option<IdeSpan>) {
+        match self {
+            Self::Value(value) => (value, None),
+            Self::Spanned { value, span } => (value, Some(span)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) enum IdeDiagnosticSeverity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+impl From<&IdeDiagnosticSeverity> for DiagnosticSeverity {
+    fn from(value: &IdeDiagnosticSeverity) -> Self {
+        match value {
+            IdeDiagnosticSeverity::Error => Self::ERROR,
+            IdeDiagnosticSeverity::Warning => Self::WARNING,
+            IdeDiagnosticSeverity::Information => Self::INFORMATION,
+            IdeDiagnosticSeverity::Hint => Self::HINT,
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct IdeGotoDef {
+    pub end: u32,
+    pub file: PathBuf,
+    pub start: u32,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IdeHover {
+    pub hover: String,
+    pub span: Option<IdeSpan>,
+}
+impl IdeHover {
+    /// The signature of the command this hover describes, from its "Usage", "Flags" and "Parameters" sections,
+    /// or `None` if it isn't describing a command (e.g. it's the type of a variable).
+    ///
+    /// This scrapes markdown meant for people, so the catalog is a better source wherever there is one.
+    pub fn to_signature(&self) -> Option<Signature> {
+        let hover = capabilities::strip_ansi(&self.hover);
+        let mut description = vec![];
+        let mut section = "";
+        let mut signature: Option<Signature> = None;
+        for line in hover.lines() {
+            let line = line.trim_end_matches('\\').trim();
+            if let Some(header) = line.strip_prefix("###") {
+                section = header.trim();
+                continue;
+            }
+            if line.starts_with("```") || line.starts_with("---") {
+                continue;
+            }
+            match (section, signature.as_mut()) {
+                ("", _) => description.push(line),
+                ("Usage", None) if !line.is_empty() => signature = Some(usage_to_signature(line)),
+                ("Flags", Some(signature)) => {
+                    signature.parameters.extend(hover_line_to_flag(line));
+                }
+                ("Parameters", Some(signature)) => describe_positional(signature, line),
+                _ => {}
+            }
+        }
+        let mut signature = signature?;
+        signature.description = description.join("\n").trim().to_string();
+        Some(signature)
+    }
+}
+
+/// e.g. `str join {flags} <separator?>`, or in the style of `help`, `str join {flags} (separator)`
+fn usage_to_signature(line: &str) -> Signature {
+    let mut name = vec![];
+    let mut parameters = vec![];
+    for word in line.split_whitespace() {
+        let (inner, optional) = if let Some(inner) = word.strip_prefix('<') {
+            (inner.trim_end_matches('>'), false)
+        } else if let Some(inner) = word.strip_prefix('(') {
+            (inner.trim_end_matches(')'), true)
+        } else if let Some(inner) = word.strip_prefix("...(") {
+            (inner.trim_end_matches(')'), true)
+        } else {
+            if parameters.is_empty() && !word.starts_with('{') {
+                name.push(word);
+            }
+            continue;
+        };
+        let (kind, name) = if let Some(rest) = inner.strip_prefix("...") {
+            (ParameterKind::Rest, rest)
+        } else if word.starts_with("...") {
+            (ParameterKind::Rest, inner)
+        } else if let Some(name) = inner.strip_suffix('?') {
+            (ParameterKind::Optional, name)
+        } else if optional {
+            (ParameterKind::Optional, inner)
+        } else {
+            (ParameterKind::Required, inner)
+        };
+        parameters.push(Parameter {
+            name: String::from(name),
+            kind,
+            shape: None,
+            description: String::new(),
+        });
+    }
+    Signature {
+        name: name.join(" "),
+        description: String::new(),
+        parameters,
+    }
+}
+
+/// From a line like `` `-d`, `--max-depth` `<int>` - how deep to go ``
+fn hover_line_to_flag(line: &str) -> Option<Parameter> {
+    let (spec, description) = line.split_once(" - ").unwrap_or((line, ""));
+    let mut flag = Parameter {
+        name: String::new(),
+        kind: ParameterKind::Flag { short: None },
+        shape: None,
+        description: String::from(description.trim()),
+    };
+    for quoted in spec.split('`').skip(1).step_by(2) {
+        if let Some(long) = quoted.strip_prefix("--") {
+            flag.name = String::from(long);
+        } else if let Some(short) = quoted.strip_prefix('-') {
+            flag.kind = ParameterKind::Flag {
+                short: short.chars().next(),
+            };
+        } else if let Some(shape) = quoted.strip_prefix('<') {
+            flag.shape = Some(String::from(shape.trim_end_matches('>')));
+        }
+    }
+    (!flag.name.is_empty() || flag.kind != ParameterKind::Flag { short: None }).then_some(flag)
+}
+
+/// Adds the type and description from a line like `` `...rest: string` - the strings to join ``
+/// to the positional of that name from the usage line.
+fn describe_positional(signature: &mut Signature, line: &str) {
+    let (spec, description) = line.split_once(" - ").unwrap_or((line, ""));
+    let Some(quoted) = spec.split('`').nth(1) else {
+        return;
+    };
+    let (name, shape) = quoted.split_once(':').unwrap_or((quoted, ""));
+    let name = name.trim().trim_start_matches("...");
+    if let Some(parameter) = signature
+        .parameters
+        .iter_mut()
+        .find(|p| p.name == name && !matches!(p.kind, ParameterKind::Flag { .. }))
+    {
+        let shape = shape.trim();
+        parameter.shape = (!shape.is_empty()).then(|| String::from(shape));
+        parameter.description =
+            String::from(description.trim().trim_end_matches("(optional)").trim());
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) struct IdeSpan {
+    pub end: u32,
+    pub start: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct IdeSettings {
+    /// how long to wait after the last edit to a document before checking it
+    #[serde(deserialize_with = "crate::deserialize::into_duration_ms")]
+    pub diagnostics_debounce_time: Duration,
+    pub hints: IdeSettingsHints,
+    pub include_dirs: Vec<PathBuf>,
+    pub max_number_of_problems: u32,
+    #[serde(deserialize_with = "crate::deserialize::into_duration_ms")]
+    pub max_nushell_invocation_time: Duration,
+    pub nushell_executable_path: PathBuf,
+    pub workspace: IdeSettingsWorkspace,
+    /// the workspace folders (the one containing the document first), which aren't a setting as such,
+    /// but are always searched for modules, like `include_dirs` are
+    #[serde(skip)]
+    pub workspace_folders: Vec<PathBuf>,
+}
+impl Default for IdeSettings {
+    fn default() -> Self {
+        Self {
+            diagnostics_debounce_time: Duration::from_millis(500),
+            hints: IdeSettingsHints::default(),
+            include_dirs: vec![],
+            max_number_of_problems: 1000,
+            max_nushell_invocation_time: Duration::from_secs(10),
+            nushell_executable_path: PathBuf::from("nu"),
+            workspace: IdeSettingsWorkspace::default(),
+            workspace_folders: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct IdeSettingsHints {
+    pub show_inferred_types: bool,
+}
+impl Default for IdeSettingsHints {
+    fn default() -> Self {
+        Self {
+            show_inferred_types: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct IdeSettingsWorkspace {
+    /// globs for files and directories in the workspace folders that are never checked
+    pub ignore: Vec<String>,
+    /// check every file in the workspace folders when they are opened, not only when asked
+    pub scan_in_background: bool,
+}
+impl Default for IdeSettingsWorkspace {
+    fn default() -> Self {
+        Self {
+            ignore: vec![
+                String::from("**/.git"),
+                String::from("**/node_modules"),
+                String::from("**/target"),
+            ],
+            scan_in_background: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CompilerResponse {
+    pub cmdline: String,
+    /// `None` when `nu` was terminated (e.g. by a signal) instead of exiting
+    pub exit_code: Option<i32>,
+    /// the file `nu` read the document from, which may be a temporary copy,
+    /// and which is how `nu` refers to the document in its responses
+    pub source_path: Option<PathBuf>,
+    pub stderr: String,
+    pub stdout: String,
+}
+impl CompilerResponse {
+    /// Describes how `nu` went wrong (e.g. a panic or a broken config), if it did.
+    pub fn failure(&self) -> Option<String> {
+        let status = match self.exit_code {
+            Some(0) => return None,
+            Some(code) => format!("exited with status {code}"),
+            None => String::from("was terminated"),
+        };
+        let stderr = self.stderr.trim();
+        if stderr.is_empty() {
+            Some(status)
+        } else {
+            Some(format!("{status}: {stderr}"))
+        }
+    }
+
+    /// For when `stdout` isn't what we expected, which is usually explained by `failure()`.
+    pub fn parse_error(&self, e: impl std::error::Error) -> tower_lsp::jsonrpc::Error {
+        let msg = match self.failure() {
+            Some(failure) => format!(
+                "cannot parse response from {}, which {failure}",
+                self.cmdline
+            ),
+            None => format!("cannot parse response from {}", self.cmdline),
+        };
+        map_err_to_parse_error(e, msg)
+    }
+}
+
+/// A single `nu --ide-*` query, with the byte offset it applies to (if any).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum IdeCommand {
+    Ast,
+    Check,
+    Complete(u32),
+    GotoDef(u32),
+    Hover(u32),
+}
+impl IdeCommand {
+    pub fn flag(self) -> &'static str {
+        match self {
+            Self::Ast => "--ide-ast",
+            Self::Check => "--ide-check",
+            Self::Complete(_) => "--ide-complete",
+            Self::GotoDef(_) => "--ide-goto-def",
+            Self::Hover(_) => "--ide-hover",
+        }
+    }
+}
+
+/// Answers `--ide-*` queries on behalf of the backend.
+///
+/// Implementations should give up as soon as `cancel` fires,
+/// failing with [`tower_lsp::jsonrpc::Error::request_cancelled`].
+#[tower_lsp::async_trait]
+pub(crate) trait Compiler: Send + Sync {
+    async fn run(
+        &self,
+        text: &str,
+        command: IdeCommand,
+        settings: IdeSettings,
+        uri: &Url,
+        cancel: &CancellationToken,
+    ) -> Result<CompilerResponse>;
+
+    /// Which `--ide-*` queries can be answered with these settings.
+    async fn capabilities(&self, _settings: &IdeSettings) -> Result<NuCapabilities> {
+        Ok(NuCapabilities::all())
+    }
+
+    /// What `nu` knows about its commands, for these settings.
+    async fn catalog(&self, _settings: &IdeSettings) -> Result<Catalog> {
+        Ok(Catalog::default())
+    }
+
+    /// How many `nu` processes crashed or hung.
+    fn crashes(&self) -> usize {
+        0
+    }
+
+    /// Waits for in-flight queries to finish, then refuses any further queries.
+    async fn shutdown(&self) {}
+}
+
+/// Selects the in-process compiler when `--in-process` is passed and the feature is enabled,
+/// otherwise spawns `nu` for every query.
+///
+/// Also returns why `--in-process` was ignored, if it was,
+/// for the client's log (as there's nowhere to report it before the client connects).
+pub(crate) fn compiler_from_args(
+    mut args: impl Iterator<Item = String>,
+) -> (Box<dyn Compiler>, Option<String>) {
+    if args.any(|a| a == "--in-process") {
+        #[cfg(feature = "in-process")]
+        return (Box::new(in_process::InProcessEngine::new()), None);
+        #[cfg(not(feature = "in-process"))]
+        return (
+            Box::<Subprocess>::default(),
+            Some(String::from(
+                "nuls was built without the `in-process` feature, spawning `nu` instead",
+            )),
+        );
+    }
+    (Box::<Subprocess>::default(), None)
+}
+
+/// The directory containing the document, followed by any configured `include_dirs`,
+/// then the workspace folders.
+pub(crate) fn include_paths(settings: &IdeSettings, uri: &Url) -> Result<Vec<PathBuf>> {
+    let mut include_paths: Vec<PathBuf> = vec![];
+    if uri.scheme() == "file" {
+        let file_path = uri.to_file_path().map_err(|e| {
+            tower_lsp::jsonrpc::Error::invalid_params(format!(
+                "cannot convert URI to filesystem path: {e:?}",
+            ))
+        })?;
+        if let Some(p) = file_path.parent() {
+            include_paths.push(p.to_path_buf());
+        }
+    }
+    for path in settings
+        .include_dirs
+        .iter()
+        .chain(&settings.workspace_folders)
+    {
+        if !include_paths.contains(path) {
+            include_paths.push(path.clone());
+        }
+    }
+    Ok(include_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::offsets::Encoding;
+    use lsp_textdocument::FullTextDocument;
+    use tower_lsp::lsp_types::{DiagnosticSeverity, Position, Range};
+
+    use super::*;
+
+    #[test]
+    fn compiler_response_failure() {
+        let response = |exit_code, stderr: &str| CompilerResponse {
+            cmdline: String::from("nu --ide-check"),
+            exit_code,
+            source_path: None,
+            stderr: String::from(stderr),
+            stdout: String::new(),
+        };
+
+        assert_eq!(response(Some(0), "warning\n").failure(), None);
+        assert_eq!(
+            response(Some(1), "Error: nu::shell::config\n").failure(),
+            Some(String::from(
+                "exited with status 1: Error: nu::shell::config"
+            ))
+        );
+        assert_eq!(
+            response(None, "").failure(),
+            Some(String::from("was terminated"))
+        );
+    }
+
+    #[test]
+    fn ide_hover_to_signature() {
+        let hover = IdeHover {
+            hover: String::from(
+                "Concatenate multiple strings into a single string.\n\
+                 -----\n\
+                 ### Usage \n\
+                 ```\n  str join {flags} <separator?>\n```\n\
+                 \n### Flags\n\n  \
+                 `-h`, `--help` - Display the help message for this command\\\n  \
+                 `--max` `<int>` - the most to join\n\
+                 \n### Parameters\n\n  \
+                 `separator: string` - optional separator to use when creating string\n\
+                 \n### Input/output types\n\n```\n  list<string> | string\n```\n",
+            ),
+            span: None,
+        };
+
+        let got = hover.to_signature().expect("should describe a command");
+
+        assert_eq!(got.name, "str join");
+        assert_eq!(
+            got.description,
+            "Concatenate multiple strings into a single string."
+        );
+        let labels: Vec<String> = got.parameters.iter().map(Parameter::label).collect();
+        assert_eq!(
+            labels,
+            vec!["<separator?: string>", "--help(-h)", "--max <int>"]
+        );
+        assert_eq!(
+            got.parameters[0].description,
+            "optional separator to use when creating string"
+        );
+
+        let variable = IdeHover {
+            hover: String::from("string"),
+            span: None,
+        };
+        assert_eq!(variable.to_signature(), None);
+    }
+
+    // `nu --ide-hover` for `str join` with nu 0.85, examples and all
+    const STR_JOIN_HOVER: &str = concat!(
+        "Concatenate multiple strings into a single string, with an optional separator between each.\n",
+        "-----\n",
+        "### Usage \n",
+        "```\n",
+        "  str join {flags} (separator)\n",
+        "```\n",
+        "\n",
+        "### Flags\n",
+        "\n",
+        "  `-h`, `--help` - Display the help message for this command\n",
+        "\n",
+        "### Parameters\n",
+        "\n",
+        "  `separator: string` - optional separator to use when creating string\n",
+        "\n",
+        "### Input/output types\n",
+        "\n",
+        "```\n",
+        "  list<any> | string\n",
+        "  string | string\n",
+        "\n",
+        "```\n",
+        "### Example(s)\n",
+        "  Create a string from input\n",
+        "```\n",
+        "  ['nu', 'shell'] | str join\n",
+        "```\n",
+        "  Create a string from input with a separator\n",
+        "```\n",
+        "  ['nu', 'shell'] | str join '-'\n",
+        "```\n",
+    );
+
+    #[test]
+    fn ide_hover_to_signature_from_nu_0_85() {
+        let hover: IdeHover = serde_json::from_value(serde_json::json!({
+            "hover": STR_JOIN_HOVER,
+            "span": { "end": 13, "start": 5 },
+        }))
+        .expect("should deserialize");
+        let want = Signature {
+            name: String::from("str join"),
+            description: String::from("Concatenate multiple strings into a single string, with an optional separator between each."),
+            parameters: vec![
+                Parameter {
+                    name: String::from("separator"),
+                    kind: ParameterKind::Optional,
+                    shape: Some(String::from("string")),
+                    description: String::from("optional separator to use when creating string"),
+                },
+                Parameter {
+                    name: String::from("help"),
+                    kind: ParameterKind::Flag { short: Some('h') },
+                    shape: None,
+                    description: String::from("Display the help message for this command"),
+                },
+            ],
+        };
+
+        assert_eq!(hover.to_signature(), Some(want.clone()));
+
+        // the same, should a config make nu colour it
+        let coloured = IdeHover {
+            hover: hover
+                .hover
+                .replace("str join", "\x1b[1;36mstr join\x1b[0m")
+                .replace("`--help`", "`\x1b[36m--help\x1b[0m`"),
+            span: None,
+        };
+        assert_eq!(coloured.to_signature(), Some(want));
+    }
+
+    #[test]
+    fn compiler_from_args_explains_a_fallback() {
+        let (_, fallback) = compiler_from_args(std::iter::empty());
+        assert_eq!(fallback, None);
+
+        let (_, fallback) = compiler_from_args([String::from("--in-process")].into_iter());
+        assert_eq!(fallback.is_some(), cfg!(not(feature = "in-process")));
+    }
+
+    #[test]
+    fn include_paths_end_with_workspace_folders() {
+        let settings = IdeSettings {
+            include_dirs: vec![PathBuf::from("/project/lib")],
+            workspace_folders: vec![PathBuf::from("/project"), PathBuf::from("/other")],
+            ..IdeSettings::default()
+        };
+        let uri = Url::parse("file:///project/scripts/build.nu").expect("cannot parse URL");
+
+        let got = include_paths(&settings, &uri).expect("should list include paths");
+
+        assert_eq!(
+            got,
+            vec![
+                PathBuf::from("/project/scripts"),
+                PathBuf::from("/project/lib"),
+                PathBuf::from("/project"),
+                PathBuf::from("/other"),
+            ]
+        );
+    }
+
+    #[test]
+    fn deserialize_ide_complete_with_and_without_spans() {
+        let input = r#"{"completions":["where",{"value":"while","span":{"start":0,"end":2}}]}"#;
+
+        let got: IdeComplete = serde_json::from_str(input).expect("cannot deserialize");
+
+        assert_eq!(
+            got.completions,
+            vec![
+                IdeSuggestion::Value(String::from("where")),
+                IdeSuggestion::Spanned {
+                    value: String::from("while"),
+                    span: IdeSpan { end: 2, start: 0 },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn deserialize_ide_check_diagnostic() {
+        let input = r#"{"message":"Missing required positional argument.","severity":"Error","span":{"end":1026,"start":1026},"type":"diagnostic"}"#;
+
+        let got: IdeCheck = serde_json::from_str(input).expect("cannot deserialize");
+
+        assert_eq!(
+            got,
+            IdeCheck::Diagnostic(IdeCheckDiagnostic {
+                message: String::from("Missing required positional argument."),
+                severity: IdeDiagnosticSeverity::Error,
+                span: IdeSpan {
+                    end: 1026,
+                    start: 1026
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn ide_check_diagnostic_to_diagnostic() {
+        let input = IdeCheckDiagnostic {
+            message: String::from("Missing required positional argument."),
+            severity: IdeDiagnosticSeverity::Error,
+            span: IdeSpan { end: 0, start: 0 },
+        };
+        let index = LineIndex::new("foo", Encoding::Utf16);
+        let uri = Url::parse("file:///foo").expect("cannot parse URL");
+
+        let got = input.to_diagnostic(&index, &uri);
+
+        assert_eq!(
+            got,
+            Diagnostic {
+                message: String::from("Missing required positional argument."),
+                range: Range {
+                    end: Position {
+                        line: 0,
+                        character: 0
+                    },
+                    start: Position {
+                        line: 0,
+                        character: 0
+                    },
+                },
+                severity: Some(DiagnosticSeverity::ERROR),
+                source: Some(uri.to_string()),
+                ..Diagnostic::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn run_compiler_for_completion_ok() {
+        let output = Subprocess::default()
+            .run(
+                "wh",
+                IdeCommand::Complete(2),
+                IdeSettings::default(),
+                &Url::parse("file:///foo.nu").expect("unable to parse test URL"),
+                &CancellationToken::new(),
+            )
+            .await
+            .expect("unable to run `nu --ide-complete ...`");
+
+        let got: Vec<String> = IdeComplete::try_from(output)
+            .expect("unable to convert output from `nu --ide-complete ...`")
+            .completions
+            .into_iter()
+            .map(|c| c.into_parts().0)
+            .collect();
+
+        // sequence is non-deterministic,
+        // so this is more reliable than using an assert_eq!() for the whole collection
+        for expected in ["where", "which", "while"] {
+            assert!(
+                got.iter().any(|c| c == expected),
+                "{expected:?} not in list"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn run_compiler_for_diagnostic_ok() {
+        let doc = FullTextDocument::new(
+            String::from("nushell"),
+            1,
+            String::from(
+                "
+                let foo = ['one', 'two', 'three']
+                ls ||
+                ",
+            ),
+        );
+        let uri = Url::parse("file:///foo.nu").expect("unable to parse test URL");
+        let output = Subprocess::default()
+            .run(
+                doc.get_content(None),
+                IdeCommand::Check,
+                IdeSettings::default(),
+                &uri,
+                &CancellationToken::new(),
+            )
+            .await
+            .expect("unable to run `nu --ide-check ...`");
+
+        let got = IdeCheckResponse::from_compiler_response(&output);
+
+        assert_eq!(
+            got,
+            IdeCheckResponse {
+                diagnostics: vec![IdeCheckDiagnostic {
+                    message: String::from("The '||' operator is not supported in Nushell"),
+                    severity: IdeDiagnosticSeverity::Error,
+                    span: IdeSpan { end: 72, start: 70 }
+                }],
+                inlay_hints: vec![IdeCheckHint {
+                    position: IdeSpan { end: 24, start: 21 },
+                    typename: String::from("list<string>")
+                }],
+            }
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use std::{io, sync::Mutex};
+
+use futures::future::BoxFuture;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tower_lsp::{jsonrpc::Result, lsp_types::Url};
+
+use super::{
+    limit::ProcessLimit,
+    worker::{LspWorker, WorkerKey},
+    CompilerResponse, IdeCommand, IdeSettings,
+};
+use crate::error::map_err_to_internal_error;
+
+/// Starts a worker for a key, e.g. [`LspWorker::spawn`], or a stand-in for tests.
+pub(crate) type StartWorker =
+    Box<dyn Fn(&WorkerKey) -> BoxFuture<'static, io::Result<LspWorker>> + Send + Sync>;
+
+/// Keeps `nu --lsp` workers running between queries, so most queries don't start a process at all.
+///
+/// Every `nu` running on behalf of a query counts towards `limit`, whether it's a worker or not,
+/// and a worker that crashes, hangs or is cancelled part way through a query is killed rather than reused,
+/// so the next query for it starts a fresh one.
+pub(crate) struct WorkerPool {
+    // oldest first
+    idle: Mutex<Vec<(WorkerKey, LspWorker)>>,
+    limit: ProcessLimit,
+    start: StartWorker,
+}
+
+impl WorkerPool {
+    pub fn new(limit: ProcessLimit, start: StartWorker) -> Self {
+        Self {
+            idle: Mutex::new(vec![]),
+            limit,
+            start,
+        }
+    }
+
+    pub fn limit(&self) -> &ProcessLimit {
+        &self.limit
+    }
+
+    /// Asks a worker started as `key` describes what `nu --ide-*` would answer,
+    /// reusing an idle one if there is one.
+    ///
+    /// The answer refers to the document by its own path, as there's no temporary copy of it.
+    pub async fn ask(
+        &self,
+        key: WorkerKey,
+        command: IdeCommand,
+        text: &str,
+        uri: &Url,
+        settings: &IdeSettings,
+        cancel: &CancellationToken,
+    ) -> Result<CompilerResponse> {
+        let _permit = self.limit.acquire().await?;
+        let idle = self.take_idle(&key);
+        let asking = async {
+            let mut worker = match idle {
+                Some(worker) => worker,
+                None => (self.start)(&key).await?,
+            };
+            let answer = worker
+                .query(command, text, uri, settings.max_number_of_problems)
+                .await?;
+            Ok::<_, io::Error>((worker, answer))
+        };
+        // dropping the worker (on cancellation, or when tower-lsp aborts a request for `$/cancelRequest`) kills it
+        let answered = tokio::select! {
+            biased;
+            () = cancel.cancelled() => return Err(tower_lsp::jsonrpc::Error::request_cancelled()),
+            answered = timeout(settings.max_nushell_invocation_time, asking) => answered,
+        };
+        match answered {
+            Ok(Ok((worker, stdout))) => {
+                let cmdline = format!("{} --lsp ({})", key.executable.display(), command.flag());
+                self.checkin(key, worker);
+                Ok(CompilerResponse {
+                    cmdline,
+                    // still running, ready for the next query
+                    exit_code: Some(0),
+                    source_path: uri.to_file_path().ok(),
+                    stderr: String::new(),
+                    stdout,
+                })
+            }
+            Ok(Err(e)) => {
+                self.limit.record_crash();
+                Err(map_err_to_internal_error(
+                    e,
+                    format!("`{} --lsp` failed", key.executable.display()),
+                ))
+            }
+            Err(e) => {
+                self.limit.record_crash();
+                Err(map_err_to_internal_error(
+                    e,
+                    format!(
+                        "`nu --lsp` timed out after {:?} answering {}",
+                        settings.max_nushell_invocation_time,
+                        command.flag()
+                    ),
+                ))
+            }
+        }
+    }
+
+    /// Waits for queries in flight, refuses any more, then asks the idle workers to exit.
+    pub async fn shutdown(&self) {
+        self.limit.shutdown().await;
+        let idle = self
+            .idle
+            .lock()
+            .map(|mut idle| std::mem::take(&mut *idle))
+            .unwrap_or_default();
+        for (_, worker) in idle {
+            worker.shutdown().await;
+        }
+    }
+
+    /// The most recently used idle worker started as `key` describes, if any.
+    fn take_idle(&self, key: &WorkerKey) -> Option<LspWorker> {
+        let mut idle = self.idle.lock().ok()?;
+        let found = idle.iter().rposition(|(k, _)| k == key)?;
+        Some(idle.remove(found).1)
+    }
+
+    /// Keeps a worker that answered its query for the next query started the same way.
+    fn checkin(&self, key: WorkerKey, worker: LspWorker) {
+        let Ok(mut idle) = self.idle.lock() else {
+            return;
+        };
+        idle.push((key, worker));
+        // one idle worker per place in the limit is plenty, and the oldest is the least likely to be asked again
+        if idle.len() > self.limit.size() {
+            idle.remove(0);
+        }
+    }
+}
+
+impl Default for WorkerPool {
+    fn default() -> Self {
+        Self::new(
+            ProcessLimit::default(),
+            Box::new(|key| {
+                let key = key.clone();
+                Box::pin(async move { LspWorker::spawn(&key).await })
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZeroUsize,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::nu::fake::{lsp_reply, lsp_worker};
+
+    fn uri() -> Url {
+        Url::parse("file:///tmp/script.nu").expect("should parse URI")
+    }
+
+    fn key() -> WorkerKey {
+        WorkerKey {
+            dir: std::path::PathBuf::from("/tmp"),
+            executable: std::path::PathBuf::from("nu"),
+            include_paths: None,
+        }
+    }
+
+    /// A pool of one, whose workers answer with `answer` (told which worker they are, from 0),
+    /// along with how many workers it has started.
+    fn pool(answer: fn(usize, &Value) -> Option<Vec<Value>>) -> (WorkerPool, Arc<AtomicUsize>) {
+        let started = Arc::new(AtomicUsize::new(0));
+        let pool = WorkerPool::new(
+            ProcessLimit::new(NonZeroUsize::MIN),
+            Box::new({
+                let started = Arc::clone(&started);
+                move |_| {
+                    let worker = started.fetch_add(1, Ordering::SeqCst);
+                    Box::pin(lsp_worker(move |message| answer(worker, message)))
+                }
+            }),
+        );
+        (pool, started)
+    }
+
+    fn hover(message: &Value) -> Option<Vec<Value>> {
+        Some(lsp_reply(
+            message,
+            match message["method"].as_str()? {
+                "initialize" => json!({ "capabilities": {} }),
+                "textDocument/hover" => json!({ "contents": "ls" }),
+                _ => Value::Null,
+            },
+        ))
+    }
+
+    async fn ask(pool: &WorkerPool, settings: &IdeSettings) -> Result<CompilerResponse> {
+        pool.ask(
+            key(),
+            IdeCommand::Hover(0),
+            "ls",
+            &uri(),
+            settings,
+            &CancellationToken::new(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn ask_reuses_an_idle_worker() {
+        let (pool, started) = pool(|_, message| hover(message));
+        let settings = IdeSettings::default();
+
+        for _ in 0..3 {
+            let got = ask(&pool, &settings).await.expect("should answer");
+            assert_eq!(
+                got.stdout,
+                json!({ "hover": "ls", "span": null }).to_string()
+            );
+            assert_eq!(got.source_path, uri().to_file_path().ok());
+        }
+
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.limit().crashes(), 0);
+    }
+
+    #[tokio::test]
+    async fn ask_restarts_a_worker_that_crashed() {
+        // the first worker crashes on its first query
+        let (pool, started) = pool(|worker, message| match message["method"].as_str() {
+            Some("textDocument/hover") if worker == 0 => None,
+            _ => hover(message),
+        });
+        let settings = IdeSettings::default();
+
+        assert!(ask(&pool, &settings).await.is_err());
+        assert_eq!(pool.limit().crashes(), 1);
+
+        ask(&pool, &settings).await.expect("should answer");
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn ask_replaces_a_worker_that_hangs() {
+        // the first worker never answers its first query
+        let (pool, started) = pool(|worker, message| match message["method"].as_str() {
+            Some("textDocument/hover") if worker == 0 => Some(vec![]),
+            _ => hover(message),
+        });
+        let settings = IdeSettings {
+            max_nushell_invocation_time: Duration::from_millis(50),
+            ..IdeSettings::default()
+        };
+
+        assert!(ask(&pool, &settings).await.is_err());
+        assert_eq!(pool.limit().crashes(), 1);
+
+        ask(&pool, &settings).await.expect("should answer");
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_then_refuses() {
+        static EXITS: AtomicUsize = AtomicUsize::new(0);
+        let (pool, started) = pool(|_, message| {
+            if message["method"] == "exit" {
+                EXITS.fetch_add(1, Ordering::SeqCst);
+                return None;
+            }
+            hover(message)
+        });
+        let settings = IdeSettings::default();
+        ask(&pool, &settings).await.expect("should answer");
+
+        pool.shutdown().await;
+
+        assert_eq!(EXITS.load(Ordering::SeqCst), 1, "idle worker should exit");
+        assert!(pool.idle.lock().expect("should not be poisoned").is_empty());
+        assert!(ask(&pool, &settings).await.is_err());
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+    }
+}
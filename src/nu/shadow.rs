@@ -0,0 +1,123 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::fs;
+
+// distinguishes concurrent shadows of the same document, e.g. a check and a hover
+static NEXT_SHADOW: AtomicUsize = AtomicUsize::new(0);
+
+/// A hidden copy of an unsaved buffer, right next to the document it belongs to.
+///
+/// A `nu` without `--include-path` resolves relative `source`/`use` against the file it is given,
+/// so a sibling shadow finds the same imports as the real file where a file in the temp directory doesn't.
+/// The copy is removed when this is dropped, and by [`remove_stale`] if the server never got the chance.
+pub(crate) struct ShadowFile {
+    path: PathBuf,
+}
+
+impl ShadowFile {
+    pub async fn create(document: &Path, text: &str) -> std::io::Result<Self> {
+        let (Some(dir), Some(name)) = (document.parent(), document.file_name()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is not a file in a directory", document.display()),
+            ));
+        };
+        let path = dir.join(format!(
+            ".{}.nuls-{}-{}~",
+            name.to_string_lossy(),
+            std::process::id(),
+            NEXT_SHADOW.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        // never clobber something that happens to have the same name
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        let shadow = Self { path };
+        fs::write(&shadow.path, text).await?;
+        Ok(shadow)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ShadowFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Removes shadows in `dir` left behind by other (e.g. killed) server processes.
+pub(crate) async fn remove_stale(dir: &Path) {
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return;
+    };
+    let ours = std::process::id().to_string();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let pid = name
+            .strip_prefix('.')
+            .filter(|_| name.ends_with('~'))
+            .and_then(|rest| rest.rsplit_once(".nuls-"))
+            .and_then(|(_, suffix)| suffix.split_once('-'))
+            .map(|(pid, _)| pid);
+        if pid.is_some_and(|pid| pid != ours) {
+            let _ = fs::remove_file(entry.path()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn create_hidden_sibling_then_remove() {
+        let dir = mktemp::Temp::new_dir().expect("should create temporary directory");
+        let document = dir.join("script.nu");
+
+        let shadow = ShadowFile::create(&document, "ls")
+            .await
+            .expect("should create shadow file");
+        let path = shadow.path().to_path_buf();
+
+        assert_eq!(path.parent(), document.parent());
+        assert!(path
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with(".script.nu.nuls-")));
+        assert_eq!(
+            fs::read_to_string(&path).await.expect("should read shadow"),
+            "ls"
+        );
+
+        drop(shadow);
+        assert!(!path.exists());
+        assert!(!document.exists());
+    }
+
+    #[tokio::test]
+    async fn remove_stale_keeps_our_own_and_other_files() {
+        let dir = mktemp::Temp::new_dir().expect("should create temporary directory");
+        let document = dir.join("script.nu");
+        std::fs::write(&document, "ls").expect("should write document");
+        let stale = dir.join(".script.nu.nuls-1-0~");
+        std::fs::write(&stale, "ls -a").expect("should write stale shadow");
+        let shadow = ShadowFile::create(&document, "ls -l")
+            .await
+            .expect("should create shadow file");
+
+        remove_stale(&dir).await;
+
+        assert!(!stale.exists());
+        assert!(shadow.path().exists());
+        assert!(document.exists());
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::{
+    fs,
+    sync::{Mutex, OnceCell},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use tower_lsp::{jsonrpc::Result, lsp_types::Url};
+
+use super::{
+    capabilities::{probe, NuCapabilities},
+    catalog::{self, Catalog},
+    include_paths,
+    pool::WorkerPool,
+    shadow::{self, ShadowFile},
+    worker::WorkerKey,
+    Compiler, CompilerResponse, IdeCommand, IdeSettings,
+};
+use crate::error::{map_err_to_internal_error, map_err_to_parse_error, method_not_found};
+
+/// Runs the configured `nushell_executable_path` (the default),
+/// keeping it running between queries where it has `nu --lsp`, and spawning it for every query otherwise,
+/// with at most a few answering at once.
+#[derive(Default)]
+pub(crate) struct Subprocess {
+    // probed once per `nushell_executable_path`, including a `nu` that can't be run,
+    // so that it isn't retried for every query,
+    // and without holding up queries for other executables while the probe runs
+    capabilities: std::sync::Mutex<HashMap<PathBuf, Arc<OnceCell<Result<NuCapabilities>>>>>,
+    pool: WorkerPool,
+    // directories already cleared of shadows left behind by earlier sessions
+    shadow_dirs: Mutex<HashSet<PathBuf>>,
+}
+
+#[tower_lsp::async_trait]
+impl Compiler for Subprocess {
+    // ported from https://github.com/nushell/vscode-nushell-lang
+    async fn run(
+        &self,
+        text: &str,
+        command: IdeCommand,
+        settings: IdeSettings,
+        uri: &Url,
+        cancel: &CancellationToken,
+    ) -> Result<CompilerResponse> {
+        let capabilities = self.capabilities(&settings).await?;
+        if !capabilities.supports(command) {
+            return Err(method_not_found(format!(
+                "`{}` (version {}) does not support {}",
+                settings.nushell_executable_path.display(),
+                capabilities.version.as_deref().unwrap_or("unknown"),
+                command.flag()
+            )));
+        }
+
+        let mut flags: Vec<&OsStr> = vec![OsStr::new(command.flag())];
+        let argument = match command {
+            // a switch, without a value
+            IdeCommand::Ast => None,
+            IdeCommand::Check => Some(format!("{}", settings.max_number_of_problems)),
+            IdeCommand::Complete(offset)
+            | IdeCommand::GotoDef(offset)
+            | IdeCommand::Hover(offset) => Some(format!("{offset}")),
+        };
+        if let Some(argument) = &argument {
+            flags.push(OsStr::new(argument));
+        }
+
+        // record separator character (a character that is unlikely to appear in a path)
+        let record_separator: &OsStr = OsStr::new("\x1e");
+        let include_paths = include_paths(&settings, uri)?;
+        let include_paths: Vec<&OsStr> = include_paths.iter().map(OsStr::new).collect();
+        let include_paths = (capabilities.include_path && !include_paths.is_empty())
+            .then(|| include_paths.join(record_separator));
+        if let Some(include_paths) = &include_paths {
+            flags.push(OsStr::new("--include-path"));
+            flags.push(include_paths);
+        }
+
+        let document = uri.to_file_path().ok().filter(|p| p.parent().is_some());
+        if let Some(key) = worker_key(
+            &capabilities,
+            command,
+            &settings,
+            document.as_deref(),
+            include_paths.as_ref(),
+        ) {
+            return self
+                .pool
+                .ask(key, command, text, uri, &settings, cancel)
+                .await;
+        }
+
+        let _permit = self.pool.limit().acquire().await?;
+        // without `--include-path`, only a file in the document's directory can find its imports,
+        // which matters for problems but is rarely worth littering the project for anything else
+        let shadow = command == IdeCommand::Check && !capabilities.include_path;
+        if let (true, Some(dir)) = (shadow, document.as_deref().and_then(Path::parent)) {
+            if self.shadow_dirs.lock().await.insert(dir.to_path_buf()) {
+                shadow::remove_stale(dir).await;
+            }
+        }
+        let source = SourceFile::new(document.as_deref(), text, shadow).await?;
+        flags.push(source.path().as_os_str());
+
+        let cmdline = format!("nu {flags:?}");
+
+        // see the `in-process` feature for calling nushell Rust code directly,
+        // https://github.com/jokeyrhyme/nuls/issues/7
+        let mut nu = tokio::process::Command::new(settings.nushell_executable_path);
+        nu.args(flags);
+        // same as `nu script.nu` run from the script's directory
+        if let Some(document) = &document {
+            if let Some(dir) = document.parent().filter(|d| d.is_dir()) {
+                nu.current_dir(dir)
+                    .env("FILE_PWD", dir)
+                    .env("CURRENT_FILE", document);
+            }
+        }
+        // dropping the child (on cancellation, or when tower-lsp aborts a request for `$/cancelRequest`) kills it
+        let running = timeout(
+            settings.max_nushell_invocation_time,
+            nu.kill_on_drop(true).output(),
+        );
+        let output = match tokio::select! {
+            biased;
+            () = cancel.cancelled() => return Err(tower_lsp::jsonrpc::Error::request_cancelled()),
+            output = running => output,
+        } {
+            Ok(output) => output,
+            Err(e) => {
+                self.pool.limit().record_crash();
+                return Err(map_err_to_internal_error(
+                    e,
+                    format!(
+                        "`nu {}` timed out after {:?}",
+                        command.flag(),
+                        &settings.max_nushell_invocation_time
+                    ),
+                ));
+            }
+        }
+        .map_err(|e| map_err_to_internal_error(e, format!("`{cmdline}` failed")))?;
+        // a non-zero ExitStatus isn't an error here, we always want stdout regardless,
+        // but a process that was killed (e.g. by a signal) is worth counting
+        let exit_code = output.status.code();
+        if exit_code.is_none() {
+            self.pool.limit().record_crash();
+        }
+
+        let stdout = String::from_utf8(output.stdout).map_err(|e| {
+            map_err_to_parse_error(e, format!("`{cmdline}` did not return valid UTF-8"))
+        })?;
+        // panics and config errors end up here, so keep whatever we can read
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        Ok(CompilerResponse {
+            cmdline,
+            exit_code,
+            source_path: Some(source.path().to_path_buf()),
+            stderr,
+            stdout,
+        })
+    }
+
+    async fn capabilities(&self, settings: &IdeSettings) -> Result<NuCapabilities> {
+        let probed = {
+            let mut cache = self.capabilities.lock().map_err(|e| {
+                map_err_to_internal_error(&e, String::from("cannot lock capabilities cache"))
+            })?;
+            Arc::clone(
+                cache
+                    .entry(settings.nushell_executable_path.clone())
+                    .or_default(),
+            )
+        };
+        probed
+            .get_or_init(|| async {
+                let _permit = self.pool.limit().acquire().await?;
+                probe(
+                    &settings.nushell_executable_path,
+                    settings.max_nushell_invocation_time,
+                )
+                .await
+            })
+            .await
+            .clone()
+    }
+
+    async fn catalog(&self, settings: &IdeSettings) -> Result<Catalog> {
+        let capabilities = self.capabilities(settings).await?;
+        let _permit = self.pool.limit().acquire().await?;
+        catalog::load(
+            &settings.nushell_executable_path,
+            capabilities.version.as_deref(),
+            settings.max_nushell_invocation_time,
+        )
+        .await
+    }
+
+    fn crashes(&self) -> usize {
+        self.pool.limit().crashes()
+    }
+
+    async fn shutdown(&self) {
+        self.pool.shutdown().await;
+    }
+}
+
+/// Which worker can answer `command` about the `document`, if a long-lived `nu --lsp` can.
+///
+/// A worker reads the document from its URI, so needs no temporary copy of it, but can't list the AST.
+fn worker_key(
+    capabilities: &NuCapabilities,
+    command: IdeCommand,
+    settings: &IdeSettings,
+    document: Option<&Path>,
+    include_paths: Option<&OsString>,
+) -> Option<WorkerKey> {
+    if !capabilities.lsp || command == IdeCommand::Ast {
+        return None;
+    }
+    Some(WorkerKey {
+        dir: document?.parent()?.to_path_buf(),
+        executable: settings.nushell_executable_path.clone(),
+        include_paths: include_paths.cloned(),
+    })
+}
+
+/// Where `nu` reads the document from, as close to the real file as we can manage.
+enum SourceFile {
+    /// the saved document is identical to the buffer, so `nu` can read it directly
+    Document(PathBuf),
+    /// a hidden sibling of the document, for unsaved changes checked by a `nu` without `--include-path`
+    Shadow(ShadowFile),
+    /// a temporary file outside the project, for other unsaved changes
+    /// (which find their imports through `--include-path` and the working directory instead),
+    /// and for documents that aren't on disk
+    Scratch(mktemp::Temp),
+}
+
+impl SourceFile {
+    async fn new(document: Option<&Path>, text: &str, shadow: bool) -> Result<Self> {
+        if let Some(document) = document {
+            if fs::read(document)
+                .await
+                .is_ok_and(|saved| saved == text.as_bytes())
+            {
+                return Ok(Self::Document(document.to_path_buf()));
+            }
+            if shadow {
+                if let Ok(shadow) = ShadowFile::create(document, text).await {
+                    return Ok(Self::Shadow(shadow));
+                }
+            }
+        }
+
+        let scratch = mktemp::Temp::new_file().map_err(|e| {
+            map_err_to_internal_error(e, String::from("unable to create temporary file"))
+        })?;
+        fs::write(&scratch, text).await.map_err(|e| {
+            map_err_to_internal_error(e, String::from("unable to write to temporary file"))
+        })?;
+        Ok(Self::Scratch(scratch))
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Self::Document(path) => path,
+            Self::Scratch(scratch) => scratch.as_ref(),
+            Self::Shadow(shadow) => shadow.path(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn capabilities_remembers_a_nu_that_cannot_run() {
+        let subprocess = Subprocess::default();
+        let settings = IdeSettings {
+            nushell_executable_path: PathBuf::from("/nonexistent/nu"),
+            ..IdeSettings::default()
+        };
+
+        assert!(subprocess.capabilities(&settings).await.is_err());
+
+        let probed = subprocess
+            .capabilities
+            .lock()
+            .expect("should lock capabilities cache")
+            .get(&settings.nushell_executable_path)
+            .cloned();
+        assert!(matches!(
+            probed.as_deref().and_then(OnceCell::get),
+            Some(Err(_))
+        ));
+        assert!(subprocess.capabilities(&settings).await.is_err());
+    }
+
+    #[test]
+    fn worker_key_only_for_files_nu_lsp_can_answer() {
+        let lsp = NuCapabilities {
+            lsp: true,
+            ..NuCapabilities::all()
+        };
+        let settings = IdeSettings::default();
+        let document = Path::new("/tmp/script.nu");
+
+        let got = worker_key(&lsp, IdeCommand::Hover(0), &settings, Some(document), None);
+        assert_eq!(got.map(|k| k.dir), Some(PathBuf::from("/tmp")));
+
+        assert!(worker_key(&lsp, IdeCommand::Ast, &settings, Some(document), None).is_none());
+        assert!(worker_key(&lsp, IdeCommand::Check, &settings, None, None).is_none());
+        assert!(worker_key(
+            &NuCapabilities::all(),
+            IdeCommand::Check,
+            &settings,
+            Some(document),
+            None
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,571 @@
+use std::{collections::HashMap, ffi::OsString, io, path::PathBuf, process::Stdio, time::Duration};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    process::{Child, Command},
+    time::timeout,
+};
+use tower_lsp::lsp_types::{
+    CompletionResponse, CompletionTextEdit, DiagnosticSeverity, GotoDefinitionResponse, Hover,
+    HoverContents, InlayHint, InlayHintLabel, Location, MarkedString, PublishDiagnosticsParams,
+    Range, Url,
+};
+
+use super::IdeCommand;
+use crate::offsets::{Encoding, LineIndex};
+
+// how long a worker has to exit once asked to, before it is killed
+const EXIT_TIME: Duration = Duration::from_secs(1);
+
+/// What a worker was started with, so it's only reused for queries that would start it the same way.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct WorkerKey {
+    /// the document's directory, as `nu script.nu` would be run from
+    pub dir: PathBuf,
+    pub executable: PathBuf,
+    /// the value for `--include-path`, if the worker is started with one
+    pub include_paths: Option<OsString>,
+}
+
+/// A long-lived `nu --lsp`, asked the same queries as `nu --ide-*`,
+/// with its answers translated into what `nu --ide-*` would have printed.
+///
+/// An I/O error leaves the conversation in an unknown state, so the worker should be dropped after one.
+pub(crate) struct LspWorker {
+    // killed when the worker is dropped, e.g. when a query is cancelled part way through
+    child: Option<Child>,
+    next_id: i64,
+    reader: BufReader<Box<dyn AsyncRead + Send + Unpin>>,
+    // the version last sent for each document, so `nu` hears about edits rather than a second open
+    versions: HashMap<Url, i32>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl LspWorker {
+    /// Starts `nu --lsp` as `key` describes, ready for its first query.
+    pub async fn spawn(key: &WorkerKey) -> io::Result<Self> {
+        let mut nu = Command::new(&key.executable);
+        nu.arg("--lsp");
+        if let Some(include_paths) = &key.include_paths {
+            nu.arg("--include-path").arg(include_paths);
+        }
+        if key.dir.is_dir() {
+            nu.current_dir(&key.dir).env("FILE_PWD", &key.dir);
+        }
+        let mut child = nu
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "`nu --lsp` has no stdin or stdout",
+            ));
+        };
+        Self::connect(stdout, stdin, Some(child)).await
+    }
+
+    /// Introduces nuls to a language server that reads from `writer` and writes to `reader`.
+    pub async fn connect(
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+        child: Option<Child>,
+    ) -> io::Result<Self> {
+        let mut worker = Self {
+            child,
+            next_id: 0,
+            reader: BufReader::new(Box::new(reader)),
+            versions: HashMap::new(),
+            writer: Box::new(writer),
+        };
+        let (initialized, _) = worker
+            .request(
+                "initialize",
+                json!({ "processId": std::process::id(), "rootUri": null, "capabilities": {} }),
+            )
+            .await?;
+        if initialized.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "`nu --lsp` refused to initialize",
+            ));
+        }
+        worker.notify("initialized", json!({})).await?;
+        Ok(worker)
+    }
+
+    /// Answers `command` about `text`, the contents of `uri`, as `nu --ide-*` would print the answer.
+    pub async fn query(
+        &mut self,
+        command: IdeCommand,
+        text: &str,
+        uri: &Url,
+        max_number_of_problems: u32,
+    ) -> io::Result<String> {
+        self.sync(uri, text).await?;
+        let index = LineIndex::new(text, Encoding::Utf16);
+        let at =
+            |offset| json!({ "textDocument": { "uri": uri }, "position": index.position(offset) });
+        let answer = match command {
+            IdeCommand::Ast => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "`nu --lsp` has no equivalent of `--ide-ast`",
+                ))
+            }
+            IdeCommand::Check => {
+                let end = index.position(u32::try_from(text.len()).unwrap_or(u32::MAX));
+                // `nu` publishes diagnostics as soon as it hears about the edit,
+                // so they arrive before the answer to anything asked after it
+                let (hints, notifications) = self
+                    .request(
+                        "textDocument/inlayHint",
+                        json!({
+                            "textDocument": { "uri": uri },
+                            "range": { "start": { "line": 0, "character": 0 }, "end": end },
+                        }),
+                    )
+                    .await?;
+                check(&index, uri, &notifications, hints, max_number_of_problems)
+            }
+            IdeCommand::Complete(offset) => {
+                let (completions, _) = self.request("textDocument/completion", at(offset)).await?;
+                complete(&index, completions)
+            }
+            IdeCommand::GotoDef(offset) => {
+                let (definition, _) = self.request("textDocument/definition", at(offset)).await?;
+                goto_def(uri, text, definition).await
+            }
+            IdeCommand::Hover(offset) => {
+                let (hover, _) = self.request("textDocument/hover", at(offset)).await?;
+                self::hover(&index, hover)
+            }
+        };
+        Ok(answer)
+    }
+
+    /// Asks `nu` to exit, as a client should, and kills it if it hasn't shortly after.
+    pub async fn shutdown(mut self) {
+        let exiting = async {
+            self.request("shutdown", Value::Null).await?;
+            self.notify("exit", Value::Null).await?;
+            // `nu` closes its stdout as it exits
+            while self.receive().await.is_ok() {}
+            if let Some(child) = &mut self.child {
+                child.wait().await?;
+            }
+            Ok::<_, io::Error>(())
+        };
+        let _ = timeout(EXIT_TIME, exiting).await;
+    }
+
+    /// Tells `nu` what the document contains now.
+    async fn sync(&mut self, uri: &Url, text: &str) -> io::Result<()> {
+        let version = self.versions.get(uri).map(|v| v + 1);
+        self.versions.insert(uri.clone(), version.unwrap_or(0));
+        match version {
+            Some(version) => {
+                self.notify(
+                    "textDocument/didChange",
+                    json!({
+                        "textDocument": { "uri": uri, "version": version },
+                        "contentChanges": [{ "text": text }],
+                    }),
+                )
+                .await
+            }
+            None => {
+                self.notify(
+                    "textDocument/didOpen",
+                    json!({
+                        "textDocument": { "uri": uri, "languageId": "nushell", "version": 0, "text": text },
+                    }),
+                )
+                .await
+            }
+        }
+    }
+
+    /// Sends a request, then waits for its result (`None` if `nu` answered with an error),
+    /// along with any notifications that arrived first.
+    async fn request(
+        &mut self,
+        method: &str,
+        params: Value,
+    ) -> io::Result<(Option<Value>, Vec<Value>)> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .await?;
+        let mut notifications = vec![];
+        loop {
+            let mut message = self.receive().await?;
+            match (message.get("id").cloned(), message.get("method").is_some()) {
+                // a request from `nu` (e.g. to show progress), which nuls has nothing to say to
+                (Some(theirs), true) => {
+                    self.send(&json!({ "jsonrpc": "2.0", "id": theirs, "result": null }))
+                        .await?;
+                }
+                (None, true) => notifications.push(message),
+                (Some(Value::Number(ours)), false) if ours.as_i64() == Some(id) => {
+                    return Ok((message.get_mut("result").map(Value::take), notifications));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+        self.send(&json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+            .await
+    }
+
+    async fn send(&mut self, message: &Value) -> io::Result<()> {
+        write_message(&mut self.writer, message).await
+    }
+
+    async fn receive(&mut self) -> io::Result<Value> {
+        read_message(&mut self.reader).await
+    }
+}
+
+/// Reads one JSON-RPC message, after the headers that frame it.
+pub(super) async fn read_message(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message without a Content-Length",
+        )
+    })?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    serde_json::from_slice(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes one JSON-RPC message, framed by a `Content-Length` header.
+pub(super) async fn write_message(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &Value,
+) -> io::Result<()> {
+    let body = message.to_string();
+    let framed = format!("Content-Length: {}\r\n\r\n{body}", body.len());
+    writer.write_all(framed.as_bytes()).await?;
+    writer.flush().await
+}
+
+/// Diagnostics and hints as `nu --ide-check` prints them, one JSON object per line.
+fn check(
+    index: &LineIndex,
+    uri: &Url,
+    notifications: &[Value],
+    hints: Option<Value>,
+    max_number_of_problems: u32,
+) -> String {
+    // each set of diagnostics replaces the last
+    let diagnostics = notifications
+        .iter()
+        .rev()
+        .filter(|n| n["method"] == "textDocument/publishDiagnostics")
+        .filter_map(|n| {
+            serde_json::from_value::<PublishDiagnosticsParams>(n["params"].clone()).ok()
+        })
+        .find(|p| &p.uri == uri)
+        .map(|p| p.diagnostics)
+        .unwrap_or_default();
+    let hints: Vec<InlayHint> = hints
+        .and_then(|h| serde_json::from_value(h).ok())
+        .unwrap_or_default();
+
+    let diagnostics = diagnostics
+        .into_iter()
+        .take(max_number_of_problems as usize)
+        .map(|d| {
+            let severity = match d.severity {
+                Some(DiagnosticSeverity::WARNING) => "Warning",
+                Some(DiagnosticSeverity::INFORMATION) => "Information",
+                Some(DiagnosticSeverity::HINT) => "Hint",
+                _ => "Error",
+            };
+            json!({
+                "type": "diagnostic",
+                "message": d.message,
+                "severity": severity,
+                "span": span(index, d.range),
+            })
+        });
+    let hints = hints.into_iter().map(|h| {
+        let offset = index.offset(h.position);
+        let label = match h.label {
+            InlayHintLabel::String(label) => label,
+            InlayHintLabel::LabelParts(parts) => parts.into_iter().map(|p| p.value).collect(),
+        };
+        // labelled as they're shown, e.g. `: int`
+        json!({
+            "type": "hint",
+            "typename": label.trim_start_matches(':').trim(),
+            "position": { "start": offset, "end": offset },
+        })
+    });
+    diagnostics
+        .chain(hints)
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Suggestions as `nu --ide-complete` prints them, each with the span it replaces.
+fn complete(index: &LineIndex, completions: Option<Value>) -> String {
+    let items = match completions.and_then(|c| serde_json::from_value(c).ok()) {
+        Some(CompletionResponse::Array(items)) => items,
+        Some(CompletionResponse::List(list)) => list.items,
+        None => vec![],
+    };
+    let completions: Vec<Value> = items
+        .into_iter()
+        .map(|item| match item.text_edit {
+            Some(CompletionTextEdit::Edit(edit)) => {
+                json!({ "value": edit.new_text, "span": span(index, edit.range) })
+            }
+            Some(CompletionTextEdit::InsertAndReplace(edit)) => {
+                json!({ "value": edit.new_text, "span": span(index, edit.replace) })
+            }
+            None => json!(item.insert_text.unwrap_or(item.label)),
+        })
+        .collect();
+    json!({ "completions": completions }).to_string()
+}
+
+/// The definition as `nu --ide-goto-def` prints it, with byte offsets into the file it's in,
+/// or `{}` when there isn't one.
+async fn goto_def(uri: &Url, text: &str, definition: Option<Value>) -> String {
+    let location = match definition.and_then(|d| serde_json::from_value(d).ok()) {
+        Some(GotoDefinitionResponse::Scalar(location)) => Some(location),
+        Some(GotoDefinitionResponse::Array(locations)) => locations.into_iter().next(),
+        Some(GotoDefinitionResponse::Link(links)) => links.into_iter().next().map(|l| Location {
+            uri: l.target_uri,
+            range: l.target_selection_range,
+        }),
+        None => None,
+    };
+    let Some((location, file)) =
+        location.and_then(|l| l.uri.to_file_path().ok().map(|file| (l, file)))
+    else {
+        return json!({}).to_string();
+    };
+    let target = if &location.uri == uri {
+        String::from(text)
+    } else {
+        // nuls reports a file it can't read as missing
+        tokio::fs::read_to_string(&file).await.unwrap_or_default()
+    };
+    let span = span(&LineIndex::new(&target, Encoding::Utf16), location.range);
+    json!({ "file": file, "start": span["start"], "end": span["end"] }).to_string()
+}
+
+/// The hover as `nu --ide-hover` prints it, or nothing when there isn't one.
+fn hover(index: &LineIndex, hover: Option<Value>) -> String {
+    let Some(hover) = hover.and_then(|h| serde_json::from_value::<Hover>(h).ok()) else {
+        return String::new();
+    };
+    let marked = |m: MarkedString| match m {
+        MarkedString::String(s) => s,
+        MarkedString::LanguageString(l) => format!("```{}\n{}\n```", l.language, l.value),
+    };
+    let contents = match hover.contents {
+        HoverContents::Scalar(m) => marked(m),
+        HoverContents::Array(ms) => ms.into_iter().map(marked).collect::<Vec<_>>().join("\n"),
+        HoverContents::Markup(m) => m.value,
+    };
+    json!({ "hover": contents, "span": hover.range.map(|r| span(index, r)) }).to_string()
+}
+
+fn span(index: &LineIndex, range: Range) -> Value {
+    json!({ "start": index.offset(range.start), "end": index.offset(range.end) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::nu::fake::{lsp_reply, lsp_worker};
+
+    const TEXT: &str = "# é\nls | get name\n";
+
+    fn uri() -> Url {
+        Url::parse("file:///tmp/script.nu").expect("should parse URI")
+    }
+
+    /// Answers as `nu --lsp` would for `TEXT`, in UTF-16 positions.
+    fn answer(message: &Value) -> Option<Vec<Value>> {
+        let position = &message["params"]["position"];
+        let mut replies = match message["method"].as_str()? {
+            "textDocument/didOpen" | "textDocument/didChange" => vec![json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/publishDiagnostics",
+                "params": {
+                    "uri": uri(),
+                    "diagnostics": [{
+                        "message": "unused",
+                        "severity": 2,
+                        "range": { "start": { "line": 1, "character": 5 }, "end": { "line": 1, "character": 8 } },
+                    }],
+                },
+            })],
+            _ => vec![],
+        };
+        replies.extend(lsp_reply(
+            message,
+            match message["method"].as_str()? {
+                "textDocument/inlayHint" => json!([{
+                    "position": { "line": 0, "character": 3 },
+                    "label": ": string",
+                }]),
+                "textDocument/hover" => json!({
+                    "contents": { "kind": "markdown", "value": format!("{}:{}", position["line"], position["character"]) },
+                    "range": { "start": { "line": 1, "character": 0 }, "end": { "line": 1, "character": 2 } },
+                }),
+                "textDocument/completion" => json!([{
+                    "label": "get",
+                    "textEdit": {
+                        "newText": "get",
+                        "range": { "start": { "line": 1, "character": 5 }, "end": { "line": 1, "character": 8 } },
+                    },
+                }]),
+                "textDocument/definition" => json!({
+                    "uri": uri(),
+                    "range": { "start": { "line": 0, "character": 2 }, "end": { "line": 0, "character": 3 } },
+                }),
+                "initialize" => json!({ "capabilities": {} }),
+                _ => Value::Null,
+            },
+        ));
+        Some(replies)
+    }
+
+    #[tokio::test]
+    async fn query_answers_as_nu_ide_would() {
+        let mut worker = lsp_worker(answer).await.expect("should initialize");
+
+        let check = worker
+            .query(IdeCommand::Check, TEXT, &uri(), 1000)
+            .await
+            .expect("should check");
+        let check: Vec<Value> = check
+            .lines()
+            .map(|l| serde_json::from_str(l).expect("should be JSON"))
+            .collect();
+        assert_eq!(
+            check,
+            vec![
+                json!({ "type": "diagnostic", "message": "unused", "severity": "Warning", "span": { "start": 10, "end": 13 } }),
+                json!({ "type": "hint", "typename": "string", "position": { "start": 4, "end": 4 } }),
+            ]
+        );
+
+        let hover = worker
+            .query(IdeCommand::Hover(10), TEXT, &uri(), 1000)
+            .await
+            .expect("should hover");
+        assert_eq!(
+            serde_json::from_str::<Value>(&hover).expect("should be JSON"),
+            json!({ "hover": "1:5", "span": { "start": 5, "end": 7 } })
+        );
+
+        let complete = worker
+            .query(IdeCommand::Complete(13), TEXT, &uri(), 1000)
+            .await
+            .expect("should complete");
+        assert_eq!(
+            serde_json::from_str::<Value>(&complete).expect("should be JSON"),
+            json!({ "completions": [{ "value": "get", "span": { "start": 10, "end": 13 } }] })
+        );
+
+        let goto_def = worker
+            .query(IdeCommand::GotoDef(10), TEXT, &uri(), 1000)
+            .await
+            .expect("should go to definition");
+        assert_eq!(
+            serde_json::from_str::<Value>(&goto_def).expect("should be JSON"),
+            json!({ "file": "/tmp/script.nu", "start": 2, "end": 4 })
+        );
+    }
+
+    #[tokio::test]
+    async fn query_opens_a_document_then_edits_it() {
+        let heard = Arc::new(Mutex::new(vec![]));
+        let mut worker = lsp_worker({
+            let heard = Arc::clone(&heard);
+            move |message| {
+                heard
+                    .lock()
+                    .expect("should not be poisoned")
+                    .push(message.clone());
+                answer(message)
+            }
+        })
+        .await
+        .expect("should initialize");
+
+        for _ in 0..2 {
+            worker
+                .query(IdeCommand::Hover(0), TEXT, &uri(), 1000)
+                .await
+                .expect("should hover");
+        }
+
+        let heard = heard.lock().expect("should not be poisoned");
+        let methods: Vec<&str> = heard.iter().filter_map(|m| m["method"].as_str()).collect();
+        assert_eq!(
+            methods,
+            vec![
+                "initialize",
+                "initialized",
+                "textDocument/didOpen",
+                "textDocument/hover",
+                "textDocument/didChange",
+                "textDocument/hover",
+            ]
+        );
+        assert_eq!(heard[4]["params"]["textDocument"]["version"], json!(1));
+    }
+
+    #[tokio::test]
+    async fn query_fails_once_nu_exits() {
+        let mut worker = lsp_worker(|message| match message["method"].as_str() {
+            Some("textDocument/hover") => None,
+            _ => answer(message),
+        })
+        .await
+        .expect("should initialize");
+
+        let got = worker.query(IdeCommand::Hover(0), TEXT, &uri(), 1000).await;
+
+        assert_eq!(
+            got.expect_err("should fail").kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
@@ -0,0 +1,305 @@
+// nushell measures spans in bytes, but LSP positions count code units (of the negotiated encoding) within a line,
+// so every offset crossing between the two goes through here
+
+use tower_lsp::lsp_types::{Position, PositionEncodingKind, Range};
+
+/// What the `character` of an LSP `Position` counts.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Encoding {
+    Utf8,
+    /// the only encoding every client must support
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+impl Encoding {
+    /// Picks the first encoding the client offers, as they're listed in its order of preference,
+    /// or UTF-16 if it offers none.
+    pub fn negotiate(offered: Option<&[PositionEncodingKind]>) -> Self {
+        offered
+            .unwrap_or_default()
+            .iter()
+            .find_map(|kind| {
+                [Self::Utf8, Self::Utf16, Self::Utf32]
+                    .into_iter()
+                    .find(|e| &e.kind() == kind)
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn kind(self) -> PositionEncodingKind {
+        match self {
+            Self::Utf8 => PositionEncodingKind::UTF8,
+            Self::Utf16 => PositionEncodingKind::UTF16,
+            Self::Utf32 => PositionEncodingKind::UTF32,
+        }
+    }
+
+    fn width(self, c: char) -> usize {
+        match self {
+            Self::Utf8 => c.len_utf8(),
+            Self::Utf16 => c.len_utf16(),
+            Self::Utf32 => 1,
+        }
+    }
+}
+
+/// Translates between byte offsets into `text` and line/character positions.
+///
+/// Lines end with `\n` or `\r\n`, and a position never falls between the `\r` and the `\n`.
+pub(crate) struct LineIndex<'a> {
+    encoding: Encoding,
+    text: &'a str,
+    // byte offset of the first character of each line
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(text: &'a str, encoding: Encoding) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            encoding,
+            text,
+            line_starts,
+        }
+    }
+
+    /// The position of the character containing the byte at `offset`,
+    /// or of the end of `text` when `offset` is past it.
+    pub fn position(&self, offset: u32) -> Position {
+        let mut offset =
+            usize::try_from(offset).map_or(self.text.len(), |o| o.min(self.text.len()));
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let mut before = &self.text[self.line_starts[line]..offset];
+        if self.text[offset..].starts_with('\n') {
+            before = before.strip_suffix('\r').unwrap_or(before);
+        }
+        let character = before.chars().map(|c| self.encoding.width(c)).sum();
+        Position {
+            line: saturating_u32(line),
+            character: saturating_u32(character),
+        }
+    }
+
+    pub fn range(&self, start: u32, end: u32) -> Range {
+        Range {
+            start: self.position(start),
+            end: self.position(end),
+        }
+    }
+
+    /// The byte offset of `position`, clamped to the end of its line (or of `text`).
+    ///
+    /// A `character` in the middle of a multi-unit character (e.g. a surrogate pair) refers to the start of that character.
+    pub fn offset(&self, position: Position) -> u32 {
+        let Some(&line_start) = self.line_starts.get(position.line as usize) else {
+            return saturating_u32(self.text.len());
+        };
+        let line = self.text[line_start..]
+            .split_inclusive('\n')
+            .next()
+            .unwrap_or_default();
+        let line = line
+            .strip_suffix("\r\n")
+            .or_else(|| line.strip_suffix('\n'))
+            .unwrap_or(line);
+
+        let mut units = 0;
+        for (i, c) in line.char_indices() {
+            units += self.encoding.width(c);
+            if units > position.character as usize {
+                return saturating_u32(line_start + i);
+            }
+        }
+        saturating_u32(line_start + line.len())
+    }
+}
+
+fn saturating_u32(n: usize) -> u32 {
+    u32::try_from(n).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    // a mix of 1-, 2-, 3- and 4-byte characters, some of which need surrogate pairs in UTF-16
+    const TEXT: &str = r"[ab\r\né日😀\n ]{0,40}";
+
+    #[test]
+    fn position_counts_utf16_code_units() {
+        let index = LineIndex::new("let s = 'é日😀'\nls", Encoding::Utf16);
+
+        // the closing quote, after 2 + 3 + 4 bytes but 1 + 1 + 2 code units
+        assert_eq!(index.position(18), Position::new(0, 13));
+        assert_eq!(index.position(21), Position::new(1, 1));
+        // inside the emoji
+        assert_eq!(index.position(16), Position::new(0, 11));
+        assert_eq!(index.position(99), Position::new(1, 2));
+    }
+
+    #[test]
+    fn offset_counts_bytes() {
+        let index = LineIndex::new("let s = 'é日😀'\nls", Encoding::Utf16);
+
+        assert_eq!(index.offset(Position::new(0, 13)), 18);
+        assert_eq!(index.offset(Position::new(1, 1)), 21);
+        // between the surrogates of the emoji
+        assert_eq!(index.offset(Position::new(0, 12)), 14);
+        // past the end of the first line, and past the last line
+        assert_eq!(index.offset(Position::new(0, 99)), 19);
+        assert_eq!(index.offset(Position::new(9, 0)), 22);
+    }
+
+    #[test]
+    fn position_counts_utf8_and_utf32_code_units() {
+        let text = "let s = 'é日😀'\nls";
+
+        assert_eq!(
+            LineIndex::new(text, Encoding::Utf8).position(18),
+            Position::new(0, 18)
+        );
+        assert_eq!(
+            LineIndex::new(text, Encoding::Utf32).position(18),
+            Position::new(0, 12)
+        );
+        // inside the emoji
+        assert_eq!(
+            LineIndex::new(text, Encoding::Utf8).offset(Position::new(0, 16)),
+            14
+        );
+    }
+
+    #[test]
+    fn crlf_lines_end_before_the_carriage_return() {
+        let index = LineIndex::new("ls\r\npwd\r\n", Encoding::Utf16);
+
+        assert_eq!(index.position(2), Position::new(0, 2));
+        assert_eq!(index.position(3), Position::new(0, 2));
+        assert_eq!(index.position(4), Position::new(1, 0));
+        assert_eq!(index.offset(Position::new(0, 99)), 2);
+        assert_eq!(index.offset(Position::new(1, 3)), 7);
+    }
+
+    #[test]
+    fn negotiate_follows_client_preference() {
+        let offered = [
+            PositionEncodingKind::new("utf-7"),
+            PositionEncodingKind::UTF32,
+            PositionEncodingKind::UTF8,
+        ];
+
+        assert_eq!(Encoding::negotiate(Some(&offered)), Encoding::Utf32);
+        assert_eq!(Encoding::negotiate(Some(&offered[2..])), Encoding::Utf8);
+        assert_eq!(
+            Encoding::negotiate(Some(&[
+                PositionEncodingKind::UTF16,
+                PositionEncodingKind::UTF8
+            ])),
+            Encoding::Utf16
+        );
+        assert_eq!(Encoding::negotiate(Some(&offered[..1])), Encoding::Utf16);
+        assert_eq!(Encoding::negotiate(Some(&[])), Encoding::Utf16);
+        assert_eq!(Encoding::negotiate(None), Encoding::Utf16);
+    }
+
+    fn encoding() -> impl Strategy<Value = Encoding> {
+        prop_oneof![
+            Just(Encoding::Utf8),
+            Just(Encoding::Utf16),
+            Just(Encoding::Utf32)
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn offset_of_position_round_trips(
+            text in TEXT,
+            i in any::<prop::sample::Index>(),
+            encoding in encoding(),
+        ) {
+            // except between `\r` and `\n`, which no position refers to
+            let boundaries: Vec<usize> = text
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(text.len()))
+                .filter(|&i| !(text[..i].ends_with('\r') && text[i..].starts_with('\n')))
+                .collect();
+            let offset = saturating_u32(*i.get(&boundaries));
+            let index = LineIndex::new(&text, encoding);
+
+            prop_assert_eq!(index.offset(index.position(offset)), offset);
+        }
+
+        #[test]
+        fn position_matches_encoded_prefix(
+            text in TEXT,
+            i in any::<prop::sample::Index>(),
+            encoding in encoding(),
+        ) {
+            let offset = i.index(text.len() + 1);
+            let index = LineIndex::new(&text, encoding);
+
+            let got = index.position(saturating_u32(offset));
+
+            let mut boundary = offset;
+            while !text.is_char_boundary(boundary) {
+                boundary -= 1;
+            }
+            let before = &text[..boundary];
+            let line = before.matches('\n').count();
+            let mut line_before = before.rsplit('\n').next().unwrap_or_default();
+            if text[boundary..].starts_with('\n') {
+                line_before = line_before.strip_suffix('\r').unwrap_or(line_before);
+            }
+            let character = match encoding {
+                Encoding::Utf8 => line_before.len(),
+                Encoding::Utf16 => line_before.encode_utf16().count(),
+                Encoding::Utf32 => line_before.chars().count(),
+            };
+            prop_assert_eq!(got, Position::new(saturating_u32(line), saturating_u32(character)));
+        }
+
+        #[test]
+        fn crlf_offset_of_line_end_is_before_the_carriage_return(
+            lines in prop::collection::vec("[ab日😀 ]{0,8}", 1..6),
+            encoding in encoding(),
+        ) {
+            let text = lines.join("\r\n");
+            let index = LineIndex::new(&text, encoding);
+
+            let mut line_start = 0;
+            for (i, line) in lines.iter().enumerate() {
+                let end = index.offset(Position::new(saturating_u32(i), u32::MAX));
+                prop_assert_eq!(end as usize, line_start + line.len());
+                prop_assert_eq!(index.position(end).line, saturating_u32(i));
+                line_start += line.len() + 2;
+            }
+        }
+
+        #[test]
+        fn position_is_monotonic(
+            text in TEXT,
+            a in any::<u32>(),
+            b in any::<u32>(),
+            encoding in encoding(),
+        ) {
+            let index = LineIndex::new(&text, encoding);
+            let (a, b) = (a % 64, b % 64);
+            let (a, b) = (a.min(b), a.max(b));
+
+            prop_assert!(index.position(a) <= index.position(b));
+        }
+    }
+}
@@ -0,0 +1,244 @@
+// what a command accepts, whether declared by a script or described by `nu`
+
+use tower_lsp::lsp_types::{
+    Documentation, MarkupContent, MarkupKind, ParameterInformation, ParameterLabel,
+    SignatureInformation,
+};
+
+/// A command's name, description, and parameters (positionals first, then flags).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Signature {
+    /// e.g. `str join`
+    pub name: String,
+    pub description: String,
+    pub parameters: Vec<Parameter>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Parameter {
+    /// without any `--` or `...`, and empty for a flag that only has a short name
+    pub name: String,
+    pub kind: ParameterKind,
+    /// the type of value, or `None` for an untyped positional or a flag without a value
+    pub shape: Option<String>,
+    pub description: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ParameterKind {
+    Required,
+    Optional,
+    Rest,
+    Flag { short: Option<char> },
+}
+
+impl Parameter {
+    /// e.g. `<path: string>`, `<...rest>` or `--depth(-d) <int>`
+    pub fn label(&self) -> String {
+        let typed = |name: String| match &self.shape {
+            Some(shape) => format!("{name}: {shape}"),
+            None => name,
+        };
+        match self.kind {
+            ParameterKind::Required => format!("<{}>", typed(self.name.clone())),
+            ParameterKind::Optional => format!("<{}>", typed(format!("{}?", self.name))),
+            ParameterKind::Rest => format!("<{}>", typed(format!("...{}", self.name))),
+            ParameterKind::Flag { short } => {
+                let label = match (self.name.is_empty(), short) {
+                    (false, Some(short)) => format!("--{}(-{short})", self.name),
+                    (true, Some(short)) => format!("-{short}"),
+                    _ => format!("--{}", self.name),
+                };
+                match &self.shape {
+                    Some(shape) => format!("{label} <{shape}>"),
+                    None => label,
+                }
+            }
+        }
+    }
+
+    fn is_flag(&self) -> bool {
+        matches!(self.kind, ParameterKind::Flag { .. })
+    }
+
+    /// Whether `arg` (e.g. `--all` or `-a`) names this flag.
+    pub fn is_named_by(&self, arg: &str) -> bool {
+        let ParameterKind::Flag { short } = self.kind else {
+            return false;
+        };
+        if let Some(long) = arg.strip_prefix("--") {
+            return !long.is_empty() && long == self.name;
+        }
+        let mut chars = arg.trim_start_matches('-').chars();
+        chars.next().is_some_and(|c| Some(c) == short) && chars.next().is_none()
+    }
+}
+
+impl Signature {
+    /// Which parameter `args[cursor]` fills, where `args` are the words after the command's name,
+    /// and `cursor` may be just past the end for an argument that hasn't been started yet.
+    pub fn active_parameter(&self, args: &[&str], cursor: usize) -> Option<usize> {
+        let is_flag = |arg: &str| arg.starts_with('-') && arg.parse::<f64>().is_err();
+        let mut positionals = 0;
+        let mut i = 0;
+        let mut awaiting_value = None;
+        while i < cursor.min(args.len()) {
+            awaiting_value = None;
+            if is_flag(args[i]) {
+                let flag = self.parameters.iter().position(|p| p.is_named_by(args[i]));
+                if let Some(flag) = flag.filter(|f| self.parameters[*f].shape.is_some()) {
+                    // the value comes next, unless that's where the cursor is
+                    if i + 1 == cursor {
+                        awaiting_value = Some(flag);
+                    }
+                    i += 1;
+                }
+            } else {
+                positionals += 1;
+            }
+            i += 1;
+        }
+        if let Some(flag) = awaiting_value {
+            return Some(flag);
+        }
+
+        match args.get(cursor) {
+            Some(arg) if is_flag(arg) => {
+                // possibly still being typed, e.g. `--al`
+                let partial = arg.trim_start_matches('-');
+                self.parameters
+                    .iter()
+                    .position(|p| p.is_named_by(arg))
+                    .or_else(|| {
+                        self.parameters.iter().position(|p| {
+                            arg.starts_with("--")
+                                && p.is_flag()
+                                && !partial.is_empty()
+                                && p.name.starts_with(partial)
+                        })
+                    })
+            }
+            _ => {
+                let mut positional = self
+                    .parameters
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, p)| !p.is_flag());
+                positional
+                    .clone()
+                    .nth(positionals)
+                    .map(|(i, _)| i)
+                    .or_else(|| {
+                        positional
+                            .find(|(_, p)| p.kind == ParameterKind::Rest)
+                            .map(|(i, _)| i)
+                    })
+            }
+        }
+    }
+
+    pub fn to_signature_information(
+        &self,
+        active_parameter: Option<usize>,
+    ) -> SignatureInformation {
+        // offsets into the label are in UTF-16 code units, whatever the position encoding
+        let utf16 = |s: &str| u32::try_from(s.encode_utf16().count()).unwrap_or(u32::MAX);
+        let mut label = self.name.clone();
+        let mut parameters = vec![];
+        for parameter in &self.parameters {
+            label.push(' ');
+            let start = utf16(&label);
+            label.push_str(&parameter.label());
+            parameters.push(ParameterInformation {
+                label: ParameterLabel::LabelOffsets([start, utf16(&label)]),
+                documentation: (!parameter.description.is_empty())
+                    .then(|| Documentation::String(parameter.description.clone())),
+            });
+        }
+        SignatureInformation {
+            label,
+            documentation: (!self.description.is_empty()).then(|| {
+                Documentation::MarkupContent(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: self.description.clone(),
+                })
+            }),
+            parameters: Some(parameters),
+            active_parameter: active_parameter.and_then(|p| u32::try_from(p).ok()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameter(name: &str, kind: ParameterKind, shape: Option<&str>) -> Parameter {
+        Parameter {
+            name: String::from(name),
+            kind,
+            shape: shape.map(String::from),
+            description: String::new(),
+        }
+    }
+
+    fn signature() -> Signature {
+        Signature {
+            name: String::from("cp"),
+            description: String::new(),
+            parameters: vec![
+                parameter("source", ParameterKind::Required, Some("path")),
+                parameter("target", ParameterKind::Optional, Some("path")),
+                parameter("rest", ParameterKind::Rest, None),
+                parameter("recursive", ParameterKind::Flag { short: Some('r') }, None),
+                parameter(
+                    "depth",
+                    ParameterKind::Flag { short: Some('d') },
+                    Some("int"),
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn active_parameter_skips_flags_and_their_values() {
+        let signature = signature();
+        let active = |args: &[&str], cursor| signature.active_parameter(args, cursor);
+
+        assert_eq!(active(&[], 0), Some(0));
+        assert_eq!(active(&["a.txt"], 1), Some(1));
+        assert_eq!(active(&["-r", "a.txt"], 2), Some(1));
+        assert_eq!(active(&["--depth", "2", "a.txt"], 3), Some(1));
+        assert_eq!(active(&["--depth"], 1), Some(4));
+        assert_eq!(active(&["a", "b", "c", "d"], 4), Some(2));
+        assert_eq!(active(&["-r"], 0), Some(3));
+        assert_eq!(active(&["--rec"], 0), Some(3));
+        assert_eq!(active(&["--unknown"], 0), None);
+        // negative numbers aren't flags
+        assert_eq!(active(&["-1"], 1), Some(1));
+    }
+
+    #[test]
+    fn to_signature_information_labels_each_parameter() {
+        let got = signature().to_signature_information(Some(1));
+
+        assert_eq!(
+            got.label,
+            "cp <source: path> <target?: path> <...rest> --recursive(-r) --depth(-d) <int>"
+        );
+        let labels: Vec<&str> = got
+            .parameters
+            .iter()
+            .flatten()
+            .map(|p| match p.label {
+                ParameterLabel::LabelOffsets([start, end]) => {
+                    &got.label[start as usize..end as usize]
+                }
+                ParameterLabel::Simple(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(labels[1], "<target?: path>");
+        assert_eq!(labels[4], "--depth(-d) <int>");
+        assert_eq!(got.active_parameter, Some(1));
+    }
+}
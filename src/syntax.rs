@@ -0,0 +1,1211 @@
+// a rough tokenizer for nushell source, for the questions that don't need (or can't wait for) `nu` itself,
+// e.g. which files a script imports, or what it declares
+
+use tower_lsp::lsp_types::{DocumentSymbol, SymbolKind};
+
+use crate::{
+    offsets::LineIndex,
+    signature::{Parameter, ParameterKind, Signature},
+};
+
+/// What sort of text a [`Token`] covers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TokenKind {
+    /// a bare word, e.g. a command name, flag, number or variable
+    Word,
+    /// quoted with `"`, `'` or a backtick
+    String,
+    Comment,
+    Newline,
+    Semicolon,
+    Pipe,
+    /// `(`, `[` or `{`
+    Open,
+    /// `)`, `]` or `}`
+    Close,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Token<'a> {
+    pub kind: TokenKind,
+    /// byte offset into the tokenized text
+    pub start: usize,
+    pub text: &'a str,
+}
+
+impl<'a> Token<'a> {
+    pub fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+
+    /// The text without its surrounding quotes, if it is a string.
+    pub fn unquoted(&self) -> &'a str {
+        if self.kind != TokenKind::String || self.text.len() < 2 {
+            return self.text;
+        }
+        let quote = &self.text[..1];
+        self.text[1..]
+            .strip_suffix(quote)
+            .unwrap_or(&self.text[1..])
+    }
+}
+
+/// Splits `text` into tokens, skipping whitespace other than newlines.
+///
+/// Unterminated strings run to the end of the text, as they would for the parser.
+pub(crate) fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let kind = match c {
+            '\n' => TokenKind::Newline,
+            ';' => TokenKind::Semicolon,
+            '|' => TokenKind::Pipe,
+            '(' | '[' | '{' => TokenKind::Open,
+            ')' | ']' | '}' => TokenKind::Close,
+            '#' => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                TokenKind::Comment
+            }
+            '"' | '\'' | '`' => {
+                skip_string(&mut chars, c);
+                TokenKind::String
+            }
+            c if c.is_whitespace() => continue,
+            _ => {
+                // quotes within a word, e.g. `$"hello ($name)"`, don't end it
+                while let Some((_, c)) = chars.next_if(|&(_, c)| !ends_word(c)) {
+                    if matches!(c, '"' | '\'' | '`') {
+                        skip_string(&mut chars, c);
+                    }
+                }
+                TokenKind::Word
+            }
+        };
+        let end = chars.peek().map_or(text.len(), |&(i, _)| i);
+        tokens.push(Token {
+            kind,
+            start,
+            text: &text[start..end],
+        });
+    }
+    tokens
+}
+
+/// The path arguments of every `use`, `source`, `source-env` and `overlay use` in `text`,
+/// including those exported or inside blocks.
+pub(crate) fn imports(text: &str) -> Vec<Token<'_>> {
+    let tokens = code(text);
+
+    let mut imports = vec![];
+    for i in command_starts(&tokens) {
+        let mut words = words(&tokens[i..]).iter();
+        let mut head = words.next().map(|t| t.text);
+        if head == Some("export") {
+            head = words.next().map(|t| t.text);
+        }
+        let is_import = match head {
+            Some("use" | "source" | "source-env") => true,
+            Some("overlay") => words.next().is_some_and(|t| t.text == "use"),
+            _ => false,
+        };
+        if !is_import {
+            continue;
+        }
+        if let Some(path) = words.find(|t| !(t.kind == TokenKind::Word && t.text.starts_with('-')))
+        {
+            imports.push(*path);
+        }
+    }
+    imports
+}
+
+/// Something a script names at the top level, or within a `module` block.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Declaration<'a> {
+    /// e.g. `export def`
+    pub keyword: String,
+    pub name: Token<'a>,
+    /// byte offsets of the whole statement, from its keyword to the end of any body
+    pub start: usize,
+    pub end: usize,
+    /// declarations within a `module` block
+    pub children: Vec<Declaration<'a>>,
+}
+impl Declaration<'_> {
+    pub fn kind(&self) -> SymbolKind {
+        match self.keyword.rsplit(' ').next() {
+            Some("module") => SymbolKind::MODULE,
+            Some("const") => SymbolKind::CONSTANT,
+            Some("let" | "mut") => SymbolKind::VARIABLE,
+            Some("extern" | "extern-wrapped") => SymbolKind::INTERFACE,
+            _ => SymbolKind::FUNCTION,
+        }
+    }
+
+    pub fn to_document_symbol(&self, index: &LineIndex) -> DocumentSymbol {
+        let offset = |o: usize| u32::try_from(o).unwrap_or(u32::MAX);
+        #[allow(deprecated)]
+        DocumentSymbol {
+            name: String::from(self.name.unquoted()),
+            detail: Some(self.keyword.clone()),
+            kind: self.kind(),
+            tags: None,
+            deprecated: None,
+            range: index.range(offset(self.start), offset(self.end)),
+            selection_range: index.range(offset(self.name.start), offset(self.name.end())),
+            children: Some(
+                self.children
+                    .iter()
+                    .map(|c| c.to_document_symbol(index))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// The commands, aliases, modules, externs and constants `text` declares,
+/// plus its top-level `let` and `mut` variables, with a module's declarations as its children.
+pub(crate) fn declarations(text: &str) -> Vec<Declaration<'_>> {
+    declarations_in(&code(text), true)
+}
+
+fn declarations_in<'a>(tokens: &[Token<'a>], top_level: bool) -> Vec<Declaration<'a>> {
+    let mut declarations = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        let end = statement_end(tokens, i);
+        if let Some(declaration) = declaration(&tokens[i..end], top_level) {
+            declarations.push(declaration);
+        }
+        // past the separator (or stray closing bracket) that ended the statement
+        i = end + 1;
+    }
+    declarations
+}
+
+/// The index of the token that ends the statement starting at `start`,
+/// i.e. the first newline, semicolon or unmatched closing bracket outside any brackets.
+fn statement_end(tokens: &[Token], start: usize) -> usize {
+    let mut depth = 0_usize;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        match token.kind {
+            TokenKind::Open => depth += 1,
+            TokenKind::Close if depth > 0 => depth -= 1,
+            TokenKind::Close | TokenKind::Newline | TokenKind::Semicolon if depth == 0 => return i,
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+/// The index of the bracket that closes the block starting at `start` (just after its opening bracket).
+fn block_end(tokens: &[Token], start: usize) -> usize {
+    let mut depth = 0_usize;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        match token.kind {
+            TokenKind::Open => depth += 1,
+            TokenKind::Close if depth == 0 => return i,
+            TokenKind::Close => depth -= 1,
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+fn declaration<'a>(statement: &[Token<'a>], top_level: bool) -> Option<Declaration<'a>> {
+    let (first, last) = (statement.first()?, statement.last()?);
+    let mut words = statement.iter().enumerate().peekable();
+    let mut keyword = String::new();
+    if first.text == "export" {
+        keyword.push_str("export ");
+        words.next();
+    }
+    let (_, head) = words.next()?;
+    match head.text {
+        "def" | "def-env" | "alias" | "module" | "const" | "extern" | "extern-wrapped" => {}
+        "let" | "mut" if top_level => {}
+        _ => return None,
+    }
+    keyword.push_str(head.text);
+
+    // e.g. `def --env`
+    while words
+        .next_if(|(_, t)| t.kind == TokenKind::Word && t.text.starts_with('-'))
+        .is_some()
+    {}
+    let (name_index, name) = words.next()?;
+    if !matches!(name.kind, TokenKind::Word | TokenKind::String) {
+        return None;
+    }
+    let mut name = *name;
+    // e.g. `const x: int = 1` or `alias ll= ls -l`
+    if name.kind == TokenKind::Word {
+        name.text = name.text.trim_end_matches([':', '=']);
+    }
+
+    let children = if head.text == "module" {
+        let body = &statement[name_index..];
+        body.iter()
+            .position(|t| t.text == "{")
+            .map(|open| {
+                let end = block_end(body, open + 1);
+                declarations_in(&body[open + 1..end], false)
+            })
+            .unwrap_or_default()
+    } else {
+        vec![]
+    };
+
+    Some(Declaration {
+        keyword,
+        name,
+        start: first.start,
+        end: last.end(),
+        children,
+    })
+}
+
+/// What the name under the cursor might refer to.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Symbol {
+    /// a variable, without its `$`
+    Variable(String),
+    /// the words of a command call (or declaration), which may name a subcommand (e.g. `str join`),
+    /// and which of them the cursor is on
+    Command { words: Vec<String>, cursor: usize },
+}
+
+/// A mention of a name, in byte offsets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Reference {
+    pub start: usize,
+    pub end: usize,
+    /// whether this is where the name is defined, rather than used
+    pub declaration: bool,
+    /// whether this calls a command, so a name of several words is written unquoted
+    pub call: bool,
+}
+
+/// The variable or command at byte `offset` in `text`, if any.
+pub(crate) fn symbol_at(text: &str, offset: usize) -> Option<Symbol> {
+    let tokens = code(text);
+    let at = tokens.iter().position(|t| {
+        matches!(t.kind, TokenKind::Word | TokenKind::String)
+            && t.start <= offset
+            && offset <= t.end()
+    })?;
+    let token = tokens[at];
+    if let Some(variable) = token.text.strip_prefix('$') {
+        let name = variable.split(['.', '?']).next().unwrap_or_default();
+        return (!name.is_empty()).then(|| Symbol::Variable(String::from(name)));
+    }
+
+    let start = command_starts(&tokens)
+        .into_iter()
+        .rev()
+        .find(|&i| i <= at && i + words(&tokens[i..]).len() > at)?;
+    let words = words(&tokens[start..]);
+    let cursor = at - start;
+    let keyword = usize::from(words[0].text == "export");
+    match words.get(keyword).map(|t| t.text) {
+        Some("let" | "mut" | "const" | "for") if cursor == keyword + 1 => Some(Symbol::Variable(
+            String::from(token.text.trim_end_matches([':', '='])),
+        )),
+        Some("def" | "def-env" | "alias" | "extern" | "extern-wrapped" | "module") => {
+            let name = keyword
+                + 1
+                + words[keyword + 1..]
+                    .iter()
+                    .take_while(|t| t.kind == TokenKind::Word && t.text.starts_with('-'))
+                    .count();
+            let words: Vec<String> = words[name]
+                .unquoted()
+                .trim_end_matches('=')
+                .split_whitespace()
+                .map(String::from)
+                .collect();
+            // e.g. `def ""`, as auto-closed quotes leave it while the name is typed
+            (cursor == name && !words.is_empty()).then_some(Symbol::Command { words, cursor: 0 })
+        }
+        _ => Some(Symbol::Command {
+            words: words.iter().map(|t| String::from(t.unquoted())).collect(),
+            cursor,
+        }),
+    }
+}
+
+/// Where the variable `name` at byte `offset` is declared (with `let`, `mut`, `const`, `for`,
+/// or as a parameter of a command or closure) and used in `text`.
+///
+/// Only the variable `offset` refers to is included, not others that share its name in other blocks,
+/// or that it shadows or is shadowed by.
+pub(crate) fn variable_references(text: &str, name: &str, offset: usize) -> Vec<Reference> {
+    let tokens = code(text);
+    let declarations = variable_declarations(&tokens, name);
+
+    // uses, including within interpolated strings, e.g. `$"($name)"`
+    let needle = format!("${name}");
+    let mut uses = vec![];
+    for token in tokens.iter().filter(|t| t.kind == TokenKind::Word) {
+        for (at, _) in token.text.match_indices(&needle) {
+            let after = token.text[at + needle.len()..].chars().next();
+            if !after.is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+                let start = token.start + at;
+                uses.push(Reference {
+                    start,
+                    end: start + needle.len(),
+                    declaration: false,
+                    call: false,
+                });
+            }
+        }
+    }
+
+    // the innermost visible declaration, and the latest one if several in the same block
+    let binding = |reference: &Reference| -> Option<usize> {
+        if reference.declaration {
+            return declarations.iter().position(|(d, _)| d == reference);
+        }
+        declarations
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, (start, end)))| *start <= reference.start && reference.start < *end)
+            .min_by_key(|(_, (d, (start, end)))| (end - start, std::cmp::Reverse(d.start)))
+            .map(|(i, _)| i)
+    };
+    let all = declarations
+        .iter()
+        .map(|(d, _)| d)
+        .chain(uses.iter())
+        .copied();
+    let Some(target) = all
+        .clone()
+        .find(|r| r.start <= offset && offset <= r.end)
+        .map(|r| binding(&r))
+    else {
+        return vec![];
+    };
+    // undeclared variables, e.g. `$env`, are all the same one
+    let mut references: Vec<Reference> = all.filter(|r| binding(r) == target).collect();
+    references.sort_by_key(|r| r.start);
+    references
+}
+
+/// Where the variable `name` is declared in `tokens`, with the byte range it can be used within.
+fn variable_declarations(tokens: &[Token], name: &str) -> Vec<(Reference, (usize, usize))> {
+    let text_end = tokens.last().map_or(0, Token::end);
+    let blocks = blocks(tokens);
+    let mut declarations: Vec<(Reference, (usize, usize))> = vec![];
+    let mut declared = |token: &Token, scope: (usize, usize)| {
+        let trimmed = token.text.trim_end_matches([':', '=', '?', ',']);
+        // flags are available as variables, with underscores for dashes
+        let flag = trimmed.trim_start_matches('-').replace('-', "_");
+        if trimmed == name || (trimmed.starts_with("--") && flag == name) {
+            let reference = Reference {
+                start: token.start,
+                end: token.start + trimmed.len(),
+                declaration: true,
+                call: false,
+            };
+            // visible from the declaration onwards
+            declarations.push((reference, (token.start, scope.1)));
+        }
+    };
+    // the end of the `{...}` block starting at `tokens[open]`, if it is one
+    let block = |open: usize| -> Option<usize> {
+        (tokens.get(open)?.text == "{")
+            .then(|| tokens.get(block_end(tokens, open + 1)))
+            .flatten()
+            .map(Token::end)
+    };
+
+    for i in command_starts(tokens) {
+        let words = words(&tokens[i..]);
+        let keyword = usize::from(words[0].text == "export");
+        match words.get(keyword).map(|t| t.text) {
+            Some("let" | "mut" | "const") => {
+                if let Some(token) = words.get(keyword + 1) {
+                    declared(token, enclosing_block(&blocks, token.start));
+                }
+            }
+            Some("for") => {
+                if let Some(token) = words.get(keyword + 1) {
+                    // only within the loop's body, the first block after the list, e.g. `[1 2]`
+                    let end = next_block(tokens, i).and_then(block).unwrap_or(token.end());
+                    declared(token, (token.start, end));
+                }
+            }
+            Some("def" | "def-env" | "extern" | "extern-wrapped") => {
+                let params = i + words.len();
+                if tokens.get(params).is_some_and(|t| t.text == "[") {
+                    let close = block_end(tokens, params + 1);
+                    // within the body, or just the parameters of an `extern`
+                    let end = block(close + 1)
+                        .or_else(|| tokens.get(close).map(Token::end))
+                        .unwrap_or(text_end);
+                    tokens[params + 1..close]
+                        .iter()
+                        .filter(|t| t.kind == TokenKind::Word)
+                        .for_each(|t| declared(t, (t.start, end)));
+                }
+            }
+            _ => {}
+        }
+    }
+    // closure parameters, e.g. `{|x, y| ... }`
+    for (i, pair) in tokens.windows(2).enumerate() {
+        if pair[0].text == "{" && pair[1].kind == TokenKind::Pipe {
+            let end = block(i).unwrap_or(text_end);
+            tokens[i + 2..]
+                .iter()
+                .take_while(|t| t.kind != TokenKind::Pipe)
+                .filter(|t| t.kind == TokenKind::Word)
+                .for_each(|t| declared(t, (t.start, end)));
+        }
+    }
+    declarations
+}
+
+/// The byte ranges of every `{...}` and `(...)` block, where unclosed blocks run to the end.
+fn blocks(tokens: &[Token]) -> Vec<(usize, usize)> {
+    let end = tokens.last().map_or(0, Token::end);
+    let mut blocks = vec![];
+    let mut open = vec![];
+    for token in tokens {
+        match token.kind {
+            TokenKind::Open => open.push(token),
+            TokenKind::Close => {
+                if let Some(o) = open.pop().filter(|o| o.text != "[") {
+                    blocks.push((o.start, token.end()));
+                }
+            }
+            _ => {}
+        }
+    }
+    blocks.extend(
+        open.iter()
+            .filter(|o| o.text != "[")
+            .map(|o| (o.start, end)),
+    );
+    blocks.push((0, end));
+    blocks
+}
+
+/// The index of the first `{` after `tokens[start]`, outside of any other brackets.
+fn next_block(tokens: &[Token], start: usize) -> Option<usize> {
+    let mut depth = 0_usize;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        match token.kind {
+            TokenKind::Open if depth == 0 && token.text == "{" => return Some(i),
+            TokenKind::Open => depth += 1,
+            TokenKind::Close => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The innermost of `blocks` containing `offset`.
+fn enclosing_block(blocks: &[(usize, usize)], offset: usize) -> (usize, usize) {
+    blocks
+        .iter()
+        .filter(|(start, end)| *start <= offset && offset < *end)
+        .min_by_key(|(start, end)| end - start)
+        .copied()
+        .unwrap_or((0, offset))
+}
+
+/// Where the command `name` (e.g. `git push`) is declared and called in `text`,
+/// and where `use` imports it by name.
+///
+/// Calls may be prefixed by one of `modules`, for commands imported with their module's name.
+pub(crate) fn command_references(text: &str, name: &str, modules: &[&str]) -> Vec<Reference> {
+    let name: Vec<&str> = name.split_whitespace().collect();
+    let tokens = code(text);
+    let mut references = vec![];
+    for i in command_starts(&tokens) {
+        let words = words(&tokens[i..]);
+        let keyword = usize::from(words[0].text == "export");
+        match words.get(keyword).map(|t| t.text) {
+            Some("def" | "def-env" | "alias" | "extern" | "extern-wrapped" | "module") => {
+                let declared = words[keyword + 1..]
+                    .iter()
+                    .find(|t| !(t.kind == TokenKind::Word && t.text.starts_with('-')));
+                if let Some(token) = declared {
+                    let unquoted = token.unquoted().trim_end_matches('=');
+                    if unquoted.split_whitespace().eq(name.iter().copied()) {
+                        references.push(Reference {
+                            start: token.start,
+                            end: token.end(),
+                            declaration: true,
+                            call: false,
+                        });
+                    }
+                }
+            }
+            // e.g. `use tools.nu [build run]`, or `overlay use tools.nu [build run]`
+            Some(import @ ("use" | "overlay")) => {
+                // after the keyword(s) and the module path, if there's anything there yet
+                let imports = i + keyword + if import == "overlay" { 3 } else { 2 };
+                let end = statement_end(&tokens, i);
+                references.extend(
+                    tokens
+                        .get(imports..end)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter(|t| {
+                            matches!(t.kind, TokenKind::Word | TokenKind::String)
+                                && t.unquoted().split_whitespace().eq(name.iter().copied())
+                        })
+                        .map(|t| Reference {
+                            start: t.start,
+                            end: t.end(),
+                            declaration: false,
+                            call: false,
+                        }),
+                );
+            }
+            _ => {
+                let prefixed = usize::from(modules.contains(&words[0].text));
+                for skip in 0..=prefixed {
+                    let call = &words[skip..];
+                    if call.len() >= name.len() && call.iter().zip(&name).all(|(t, n)| t.text == *n)
+                    {
+                        references.push(Reference {
+                            start: call[0].start,
+                            end: call[name.len() - 1].end(),
+                            declaration: false,
+                            call: true,
+                        });
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    references
+}
+
+/// The words of the command being called at byte `offset`, e.g. while typing its arguments.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Call {
+    pub words: Vec<String>,
+    /// the word the cursor is on, or `words.len()` if it's after the last one
+    pub cursor: usize,
+    /// byte offset of the first word
+    pub start: usize,
+}
+
+/// The command call that byte `offset` in `text` is within, or just after.
+pub(crate) fn call_at(text: &str, offset: usize) -> Option<Call> {
+    let tokens = code(text);
+    let start = command_starts(&tokens)
+        .into_iter()
+        .rev()
+        .find(|&i| tokens[i].start <= offset)?;
+    let words = words(&tokens[start..]);
+    // the call ended before the cursor, e.g. at a pipe or bracket
+    if tokens
+        .get(start + words.len())
+        .is_some_and(|t| t.start < offset)
+    {
+        return None;
+    }
+    Some(Call {
+        words: words.iter().map(|t| String::from(t.unquoted())).collect(),
+        cursor: words
+            .iter()
+            .position(|t| offset <= t.end())
+            .unwrap_or(words.len()),
+        start: words[0].start,
+    })
+}
+
+/// Where the word or string being typed at byte `offset` starts, or `offset` itself between words.
+pub(crate) fn word_start(text: &str, offset: usize) -> usize {
+    code(text)
+        .iter()
+        .find(|t| {
+            matches!(t.kind, TokenKind::Word | TokenKind::String)
+                && t.start < offset
+                && offset <= t.end()
+        })
+        .map_or(offset, |t| t.start)
+}
+
+/// The signatures of the commands `text` declares with `def` or `extern`, including within modules,
+/// described by the comments just above them and beside each parameter.
+pub(crate) fn signatures(text: &str) -> Vec<Signature> {
+    let tokens = tokenize(text);
+    let mut signatures = vec![];
+    for start in command_starts(&tokens) {
+        let words = words(&tokens[start..]);
+        let keyword = usize::from(words[0].text == "export");
+        if !matches!(
+            words.get(keyword).map(|t| t.text),
+            Some("def" | "def-env" | "extern" | "extern-wrapped")
+        ) {
+            continue;
+        }
+        let Some(name) = words[keyword + 1..]
+            .iter()
+            .find(|t| !(t.kind == TokenKind::Word && t.text.starts_with('-')))
+        else {
+            continue;
+        };
+        let open = start + words.len();
+        if tokens.get(open).map(|t| t.text) != Some("[") {
+            continue;
+        }
+        let end = block_end(&tokens, open + 1);
+        let mut parameters = parameters(&tokens[open + 1..end]);
+        parameters.sort_by_key(|p| matches!(p.kind, ParameterKind::Flag { .. }));
+        signatures.push(Signature {
+            name: String::from(name.unquoted()),
+            description: leading_comment(&tokens, start),
+            parameters,
+        });
+    }
+    signatures
+}
+
+/// The comment lines directly above the statement starting at `tokens[start]`.
+fn leading_comment(tokens: &[Token], start: usize) -> String {
+    let mut lines = vec![];
+    let mut i = start;
+    while i >= 2
+        && tokens[i - 1].kind == TokenKind::Newline
+        && tokens[i - 2].kind == TokenKind::Comment
+    {
+        // not the comment at the end of some other statement
+        if i >= 3 && tokens[i - 3].kind != TokenKind::Newline {
+            break;
+        }
+        lines.push(tokens[i - 2].text.trim_start_matches('#').trim());
+        i -= 2;
+    }
+    lines.reverse();
+    lines.join("\n")
+}
+
+/// What the next word of a signature is.
+#[derive(Clone, Copy, PartialEq)]
+enum Expecting {
+    Parameter,
+    Shape,
+    Default,
+    /// within the brackets of e.g. `--all (-a)`
+    Short,
+}
+
+/// The parameters between the brackets of e.g. `def greet [name: string, --loud (-l)  # shout]`.
+fn parameters(tokens: &[Token]) -> Vec<Parameter> {
+    let mut parameters: Vec<Parameter> = vec![];
+    let mut expecting = Expecting::Parameter;
+    // e.g. within the brackets of a default value like `[]`
+    let mut depth = 0_usize;
+    // whether the last parameter has been followed by a newline, so any comment isn't about it
+    let mut described = false;
+    for token in tokens {
+        match token.kind {
+            TokenKind::Newline => described = true,
+            TokenKind::Comment if !described => {
+                if let Some(last) = parameters.last_mut() {
+                    last.description = String::from(token.text.trim_start_matches('#').trim());
+                }
+                described = true;
+            }
+            TokenKind::Open
+                if token.text == "(" && depth == 0 && expecting == Expecting::Parameter =>
+            {
+                expecting = Expecting::Short;
+            }
+            TokenKind::Open => depth += 1,
+            TokenKind::Close if expecting == Expecting::Short => expecting = Expecting::Parameter,
+            TokenKind::Close => {
+                depth = depth.saturating_sub(1);
+                // e.g. `= (date now)`
+                if depth == 0 && expecting == Expecting::Default {
+                    expecting = Expecting::Parameter;
+                }
+            }
+            TokenKind::Word | TokenKind::String if depth == 0 => {
+                let text = token.text.trim_end_matches(',');
+                match expecting {
+                    Expecting::Short => {
+                        if let Some(ParameterKind::Flag { short }) =
+                            parameters.last_mut().map(|p| &mut p.kind)
+                        {
+                            *short = text.trim_start_matches('-').chars().next();
+                        }
+                    }
+                    Expecting::Shape => {
+                        if let Some(last) = parameters.last_mut() {
+                            let shape = last.shape.get_or_insert_with(String::new);
+                            if !shape.is_empty() {
+                                shape.push(' ');
+                            }
+                            shape.push_str(token.text);
+                            // e.g. `record<name: string>` spans several words
+                            if shape.matches('<').count() <= shape.matches('>').count() {
+                                shape.truncate(shape.trim_end_matches(',').len());
+                                expecting = Expecting::Parameter;
+                            }
+                        }
+                    }
+                    Expecting::Default => expecting = Expecting::Parameter,
+                    Expecting::Parameter => match text {
+                        "" => {}
+                        ":" => expecting = Expecting::Shape,
+                        "=" => {
+                            // positionals with defaults are optional
+                            if let Some(last) = parameters
+                                .last_mut()
+                                .filter(|p| p.kind == ParameterKind::Required)
+                            {
+                                last.kind = ParameterKind::Optional;
+                            }
+                            expecting = Expecting::Default;
+                        }
+                        _ => {
+                            let (name, shape) = text.split_once(':').unwrap_or((text, ""));
+                            parameters.push(parameter(name));
+                            described = false;
+                            if text.ends_with(':') {
+                                expecting = Expecting::Shape;
+                            } else if !shape.is_empty() {
+                                parameters.last_mut().into_iter().for_each(|p| {
+                                    p.shape = Some(String::from(shape));
+                                });
+                            }
+                        }
+                    },
+                }
+            }
+            _ => {}
+        }
+    }
+    parameters
+}
+
+/// A parameter from how its name is written, e.g. `--all`, `-a`, `...rest`, `path?` or `path`.
+fn parameter(name: &str) -> Parameter {
+    let (name, kind) = if let Some(long) = name.strip_prefix("--") {
+        (long, ParameterKind::Flag { short: None })
+    } else if let Some(short) = name.strip_prefix('-') {
+        (
+            "",
+            ParameterKind::Flag {
+                short: short.chars().next(),
+            },
+        )
+    } else if let Some(rest) = name.strip_prefix("...") {
+        (rest, ParameterKind::Rest)
+    } else if let Some(optional) = name.strip_suffix('?') {
+        (optional, ParameterKind::Optional)
+    } else {
+        (name, ParameterKind::Required)
+    };
+    Parameter {
+        name: String::from(name),
+        kind,
+        shape: None,
+        description: String::new(),
+    }
+}
+
+/// The tokens of `text` that aren't comments.
+fn code(text: &str) -> Vec<Token<'_>> {
+    tokenize(text)
+        .into_iter()
+        .filter(|t| t.kind != TokenKind::Comment)
+        .collect()
+}
+
+/// The indexes of tokens that start a command, i.e. the first word after a separator,
+/// an opening bracket (other than for a list), an `=`, or a closure's parameters.
+fn command_starts(tokens: &[Token]) -> Vec<usize> {
+    let mut starts = vec![];
+    let mut command_position = true;
+    let mut parameters = false;
+    for (i, token) in tokens.iter().enumerate() {
+        if parameters {
+            parameters = token.kind != TokenKind::Pipe;
+            command_position = !parameters;
+            continue;
+        }
+        if command_position && token.kind == TokenKind::Word {
+            starts.push(i);
+        }
+        parameters = token.kind == TokenKind::Pipe && i > 0 && tokens[i - 1].text == "{";
+        command_position = match token.kind {
+            TokenKind::Newline | TokenKind::Semicolon => true,
+            TokenKind::Pipe => !parameters,
+            // lists hold values, not commands
+            TokenKind::Open => token.text != "[",
+            TokenKind::Word => token.text == "=",
+            _ => false,
+        };
+    }
+    starts
+}
+
+/// The words (and strings) at the start of `tokens`, e.g. a command and its arguments up to any brackets.
+fn words<'a, 'b>(tokens: &'b [Token<'a>]) -> &'b [Token<'a>] {
+    let end = tokens
+        .iter()
+        .position(|t| !matches!(t.kind, TokenKind::Word | TokenKind::String))
+        .unwrap_or(tokens.len());
+    &tokens[..end]
+}
+
+fn ends_word(c: char) -> bool {
+    c.is_whitespace() || matches!(c, ';' | '|' | '(' | ')' | '[' | ']' | '{' | '}')
+}
+
+fn skip_string(chars: &mut std::iter::Peekable<std::str::CharIndices>, quote: char) {
+    let mut escaped = false;
+    for (_, c) in chars.by_ref() {
+        if c == quote && !escaped {
+            return;
+        }
+        // only double-quoted strings have escapes
+        escaped = quote == '"' && c == '\\' && !escaped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_strings_comments_and_blocks() {
+        let text = "let s = \"a \\\" b\" # note\nls | where {|x| $x.size > 1kb }";
+
+        let got: Vec<(TokenKind, &str)> = tokenize(text).iter().map(|t| (t.kind, t.text)).collect();
+
+        assert_eq!(
+            got,
+            vec![
+                (TokenKind::Word, "let"),
+                (TokenKind::Word, "s"),
+                (TokenKind::Word, "="),
+                (TokenKind::String, "\"a \\\" b\""),
+                (TokenKind::Comment, "# note"),
+                (TokenKind::Newline, "\n"),
+                (TokenKind::Word, "ls"),
+                (TokenKind::Pipe, "|"),
+                (TokenKind::Word, "where"),
+                (TokenKind::Open, "{"),
+                (TokenKind::Pipe, "|"),
+                (TokenKind::Word, "x"),
+                (TokenKind::Pipe, "|"),
+                (TokenKind::Word, "$x.size"),
+                (TokenKind::Word, ">"),
+                (TokenKind::Word, "1kb"),
+                (TokenKind::Close, "}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_keeps_interpolation_in_one_word() {
+        let got = tokenize("print $\"hello (whoami)\"");
+
+        assert_eq!(got[1].text, "$\"hello (whoami)\"");
+        assert_eq!(got[1].start, 6);
+    }
+
+    #[test]
+    fn imports_finds_every_kind_of_import() {
+        let text = "
+use lib/utils.nu *
+export use 'my mod.nu' [foo bar]
+source-env env.nu; source ~/.config/aliases.nu
+overlay use --prefix spam.nu as eggs
+def main [] { use inner.nu }
+# use commented.nu
+print use not-an-import.nu
+let modules = [use list.nu]
+";
+
+        let got: Vec<&str> = imports(text).iter().map(Token::unquoted).collect();
+
+        assert_eq!(
+            got,
+            vec![
+                "lib/utils.nu",
+                "my mod.nu",
+                "env.nu",
+                "~/.config/aliases.nu",
+                "spam.nu",
+                "inner.nu",
+            ]
+        );
+    }
+
+    #[test]
+    fn declarations_nest_modules() {
+        let text = "\
+# helpers
+export def --env greet [name: string] {
+    let greeting = $\"hi ($name)\"
+    print $greeting
+}
+alias ll = ls -l
+const MAX: int = 3
+mut count = 0
+extern \"git push\" [remote?: string]
+module tools {
+    export def 'tool run' [] { }
+    export module nested { export const X = 1 }
+}
+";
+
+        let got = declarations(text);
+
+        let summary: Vec<(&str, &str, Vec<&str>)> = got
+            .iter()
+            .map(|d| {
+                (
+                    d.keyword.as_str(),
+                    d.name.unquoted(),
+                    d.children.iter().map(|c| c.name.unquoted()).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("export def", "greet", vec![]),
+                ("alias", "ll", vec![]),
+                ("const", "MAX", vec![]),
+                ("mut", "count", vec![]),
+                ("extern", "git push", vec![]),
+                ("module", "tools", vec!["tool run", "nested"]),
+            ]
+        );
+        assert_eq!(got[5].children[1].children[0].name.text, "X");
+
+        // from the keyword to the end of the body
+        let greet = &text[got[0].start..got[0].end];
+        assert!(greet.starts_with("export def"));
+        assert!(greet.ends_with("print $greeting\n}"));
+    }
+
+    #[test]
+    fn symbol_at_finds_variables_and_commands() {
+        let text = "let name = 1\nstr join $name.0 # git push\ndef --env \"git push\" [] {}";
+
+        assert_eq!(
+            symbol_at(text, 5),
+            Some(Symbol::Variable(String::from("name")))
+        );
+        assert_eq!(
+            symbol_at(text, 24),
+            Some(Symbol::Variable(String::from("name")))
+        );
+        assert_eq!(
+            symbol_at(text, 17),
+            Some(Symbol::Command {
+                words: vec![
+                    String::from("str"),
+                    String::from("join"),
+                    String::from("$name.0")
+                ],
+                cursor: 1,
+            })
+        );
+        // comments aren't code
+        assert_eq!(symbol_at(text, 36), None);
+        assert_eq!(
+            symbol_at(text, 52),
+            Some(Symbol::Command {
+                words: vec![String::from("git"), String::from("push")],
+                cursor: 0,
+            })
+        );
+        // names still being typed
+        assert_eq!(symbol_at("def \"\" [] {}", 5), None);
+        assert_eq!(symbol_at("alias = ls", 6), None);
+    }
+
+    #[test]
+    fn variable_references_include_parameters_and_interpolation() {
+        let text = "\
+def greet [name: string, --loud-name] {
+    let names = [$name]
+    print $\"hi ($name)\" $loud_name $names
+}
+[1] | each {|name| $name }";
+
+        let got = |offset| -> Vec<(&str, bool)> {
+            variable_references(text, "name", offset)
+                .iter()
+                .map(|r| (&text[r.start..r.end], r.declaration))
+                .collect()
+        };
+
+        let first = vec![("name", true), ("$name", false), ("$name", false)];
+        assert_eq!(got(text.find("$name").expect("should use $name")), first);
+        assert_eq!(got(11), first);
+        assert_eq!(
+            got(text.rfind("$name").expect("should use $name")),
+            vec![("name", true), ("$name", false)]
+        );
+        let flag = variable_references(text, "loud_name", text.find("$loud").unwrap_or_default());
+        assert_eq!(flag.len(), 2);
+        assert_eq!(&text[flag[0].start..flag[0].end], "--loud-name");
+    }
+
+    #[test]
+    fn variable_references_stay_within_their_scope() {
+        let text = "\
+let x = 1
+def a [name] { $name }
+def b [name] { let x = 2; $name + $x }
+for x in [1] { $x }
+print $x $env";
+        let got = |needle: &str, nth: usize| -> Vec<usize> {
+            let offset = text.match_indices(needle).nth(nth).map(|(i, _)| i + 1);
+            let name = needle.trim_start_matches('$');
+            variable_references(text, name, offset.unwrap_or_default())
+                .iter()
+                .map(|r| r.start)
+                .collect()
+        };
+        let at = |needle: &str, nth: usize| {
+            text.match_indices(needle)
+                .nth(nth)
+                .map(|(i, _)| i)
+                .unwrap_or_default()
+        };
+
+        assert_eq!(got("$name", 0), vec![at("name", 0), at("$name", 0)]);
+        assert_eq!(got("$name", 1), vec![at("name", 2), at("$name", 1)]);
+        // shadowed within `b`, and by the loop variable
+        assert_eq!(got("$x", 0), vec![at("x = 2", 0), at("$x", 0)]);
+        assert_eq!(got("$x", 1), vec![at("x in", 0), at("$x", 1)]);
+        assert_eq!(got("$x", 2), vec![at("x = 1", 0), at("$x", 2)]);
+        // not declared at all
+        assert_eq!(got("$env", 0), vec![at("$env", 0)]);
+    }
+
+    #[test]
+    fn command_references_follow_imports_and_module_prefixes() {
+        let text = "\
+use tools.nu [build 'build all']
+export def build [] { ls | build }
+tools build --release
+# build
+print build
+let out = (build)";
+
+        let got: Vec<(usize, &str, bool)> = command_references(text, "build", &["tools"])
+            .iter()
+            .map(|r| (r.start, &text[r.start..r.end], r.declaration))
+            .collect();
+
+        assert_eq!(
+            got,
+            vec![
+                (14, "build", false),
+                (44, "build", true),
+                (60, "build", false),
+                (74, "build", false),
+                (121, "build", false),
+            ]
+        );
+        assert_eq!(command_references(text, "build all", &[]).len(), 1);
+        assert_eq!(
+            command_references("overlay use tools.nu [build]", "tools.nu", &[]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn command_references_ignore_unfinished_imports() {
+        for text in [
+            "use",
+            "use\n",
+            "export use",
+            "ls\nexport use",
+            "overlay use",
+        ] {
+            assert_eq!(command_references(text, "use", &[]), vec![], "{text:?}");
+        }
+        assert_eq!(
+            command_references("overlay use tools.nu [build]", "build", &[]).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn call_at_finds_the_command_being_typed() {
+        let text = "ls | str join --separator ";
+
+        let got = call_at(text, text.len()).expect("should find call");
+
+        assert_eq!(got.words, vec!["str", "join", "--separator"]);
+        assert_eq!(got.cursor, 3);
+        assert_eq!(got.start, 5);
+        assert_eq!(call_at(text, 1).map(|c| c.cursor), Some(0));
+        assert_eq!(call_at("(ls) ", 5), None);
+    }
+
+    #[test]
+    fn word_start_includes_sigils_and_quotes() {
+        let text = "ls --al | get $env.PA | open \"my fi";
+
+        assert_eq!(word_start(text, 7), 3);
+        assert_eq!(word_start(text, 21), 14);
+        assert_eq!(word_start(text, text.len()), 29);
+        assert_eq!(word_start(text, 8), 8);
+        assert_eq!(word_start("(ls)", 1), 1);
+    }
+
+    #[test]
+    fn signatures_read_parameters_and_comments() {
+        let text = "\
+ls # not about greet
+# Say hello
+# to someone
+export def greet [
+    name: string          # who to greet
+    --loud (-l)           # shout
+    --times (-t): int = 1
+    greeting? = \"hi\"
+    ...rest: record<a: int, b: string>
+] {}";
+
+        let got = signatures(text);
+
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].name, "greet");
+        assert_eq!(got[0].description, "Say hello\nto someone");
+        let parameters: Vec<(String, Option<&str>, &str)> = got[0]
+            .parameters
+            .iter()
+            .map(|p| (p.label(), p.shape.as_deref(), p.description.as_str()))
+            .collect();
+        assert_eq!(
+            parameters,
+            vec![
+                (
+                    String::from("<name: string>"),
+                    Some("string"),
+                    "who to greet"
+                ),
+                (String::from("<greeting?>"), None, ""),
+                (
+                    String::from("<...rest: record<a: int, b: string>>"),
+                    Some("record<a: int, b: string>"),
+                    ""
+                ),
+                (String::from("--loud(-l)"), None, "shout"),
+                (String::from("--times(-t) <int>"), Some("int"), ""),
+            ]
+        );
+    }
+}